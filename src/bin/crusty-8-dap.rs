@@ -0,0 +1,101 @@
+// A debug adapter for editors, speaking the Debug Adapter Protocol over stdin and stdout.
+// See the README for the launch arguments.
+
+use std::io::{self, Write};
+use std::sync::mpsc::{self, TryRecvError};
+use std::time::{Duration, Instant};
+use minifb::{Key, Window, WindowOptions};
+use serde_json::Value;
+use crusty_8::chip8::{HEIGHT, WIDTH};
+use crusty_8::dap::{self, Adapter};
+
+const FRAME: Duration = Duration::from_micros(16_667);
+const SCALE: usize = 10;
+
+// The hex keypad on the left of a QWERTY keyboard, in the order of the keys 0 to F
+const KEYS: [Key; 16] = [
+    Key::X, Key::Key1, Key::Key2, Key::Key3,
+    Key::Q, Key::W, Key::E, Key::A,
+    Key::S, Key::D, Key::Z, Key::C,
+    Key::Key4, Key::R, Key::F, Key::V,
+];
+
+fn send(output: &mut dyn Write, messages: Vec<Value>) {
+    for message in messages {
+        // Nobody is left to tell if the editor went away
+        if dap::write_message(output, &message).is_err() {
+            std::process::exit(1);
+        }
+    }
+}
+
+fn main() {
+    // Requests are read on their own thread, so the program keeps running while the editor is quiet.
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let stdin = io::stdin();
+        let mut input = stdin.lock();
+        while let Ok(Some(message)) = dap::read_message(&mut input) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    let mut adapter = Adapter::default();
+    let mut window: Option<Window> = None;
+    let mut closed = false;
+    let mut buffer = vec![0; WIDTH * HEIGHT];
+    let mut next_frame = Instant::now();
+
+    while !adapter.finished() {
+        loop {
+            match receiver.try_recv() {
+                Ok(request) => {
+                    let messages = adapter.handle(&request);
+                    send(&mut output, messages);
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        }
+
+        if window.is_none() && !closed && adapter.display() && adapter.chip8().is_some() {
+            let mut opened = Window::new("Crusty-8", WIDTH * SCALE, HEIGHT * SCALE, WindowOptions::default())
+                .unwrap_or_else(|e| {
+                    panic!("{}", e);
+                });
+            opened.limit_update_rate(None);
+            window = Some(opened);
+        }
+        if window.as_ref().is_some_and(|window| !window.is_open()) {
+            window = None;
+            closed = true;
+            let messages = adapter.terminate();
+            send(&mut output, messages);
+        }
+
+        if let (Some(window), Some(chip8)) = (&window, adapter.chip8()) {
+            for (key, &binding) in chip8.keys.iter_mut().zip(KEYS.iter()) {
+                *key = window.is_key_down(binding);
+            }
+        }
+        let messages = adapter.run_frame();
+        send(&mut output, messages);
+
+        if let Some(window) = &mut window {
+            if let Some(chip8) = adapter.chip8() {
+                for (pixel, &on) in buffer.iter_mut().zip(chip8.framebuffer.iter()) {
+                    *pixel = if on { 0xFF_FF_FF } else { 0x00_00_00 };
+                }
+            }
+            let _ = window.update_with_buffer(&buffer, WIDTH, HEIGHT);
+        }
+
+        next_frame += FRAME;
+        let now = Instant::now();
+        if next_frame > now { std::thread::sleep(next_frame - now) } else { next_frame = now }
+    }
+}
@@ -0,0 +1,122 @@
+use std::path::PathBuf;
+use structopt::StructOpt;
+use crusty_8::chip8::{Chip8, Quirks, Timing};
+use crusty_8::diff::{self, Divergence, Input};
+use crusty_8::machine::Machine;
+use crusty_8::vip::Vip;
+
+#[derive(StructOpt)]
+#[structopt(about = "Runs a Chip-8 rom on two configurations and reports where they diverge.")]
+struct Settings {
+    #[structopt(short, long, parse(from_os_str))]
+    /// Specifies a path to a chip-8 rom
+    path: PathBuf,
+
+    #[structopt(short, default_value = "chip8:vip")]
+    /// The first configuration, chip8:<quirks> like chip8:vip,wrap or vip for the COSMAC VIP backend
+    a: String,
+
+    #[structopt(short, default_value = "chip8:chip48")]
+    /// The second configuration, see -a
+    b: String,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Specifies a dump of the original interpreter for the vip backend
+    interpreter: Option<PathBuf>,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Specifies the keys to press, every line holds a frame number and the keys held from then on, e.g. "120 5A"
+    input: Option<PathBuf>,
+
+    #[structopt(long, default_value = "600")]
+    /// Number of frames to run
+    frames: u64,
+
+    #[structopt(long, default_value = "10")]
+    /// Instructions per frame of the chip8 backend, against the vip backend it uses VIP cycle timing instead
+    instructions: u64,
+
+    #[structopt(long, default_value = "0")]
+    /// Seeds the random numbers of CXNN on both sides
+    seed: u64,
+
+    #[structopt(long, default_value = "16")]
+    /// Number of steps shown before the divergence
+    context: usize,
+}
+
+enum Side {
+    Chip8(Box<Chip8>),
+    Vip(Box<Vip>),
+}
+
+fn boot(spec: &str, rom: &[u8], args: &Settings) -> Result<Side, String> {
+    if spec == "vip" {
+        let path = args.interpreter.as_ref()
+            .ok_or("The vip backend needs a dump of the original interpreter, see --interpreter")?;
+        let interpreter = std::fs::read(path).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        return Ok(Side::Vip(Box::new(Vip::new(&interpreter, rom)?)));
+    }
+
+    let mut chip8 = Chip8::new_with_state();
+    chip8.quirks = spec.strip_prefix("chip8:").and_then(Quirks::from_profile)
+        .ok_or_else(|| format!("Unknown configuration '{}', expected chip8:<quirks> or vip", spec))?;
+    chip8.seed(args.seed);
+    chip8.load_bytes(rom);
+    Ok(Side::Chip8(Box::new(chip8)))
+}
+
+fn run(args: &Settings) -> Result<Option<Divergence>, String> {
+    let rom = std::fs::read(&args.path).map_err(|e| format!("Could not read {}: {}", args.path.display(), e))?;
+    let input = match &args.input {
+        Some(path) => Input::parse(&std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path.display(), e))?)?,
+        None => Input::none(),
+    };
+
+    let divergence = match (boot(&args.a, &rom, args)?, boot(&args.b, &rom, args)?) {
+        // Two interpreters can be compared after every instruction
+        (Side::Chip8(mut a), Side::Chip8(mut b)) => {
+            diff::run_instructions(&mut a, &mut b, &input, args.frames, args.instructions, args.context)
+        }
+        (a, b) => {
+            let machine = |side| -> Box<dyn Machine> {
+                match side {
+                    Side::Chip8(chip8) => chip8,
+                    Side::Vip(vip) => vip,
+                }
+            };
+            let (mut a, mut b) = (machine(a), machine(b));
+            // The vip backend runs as fast as the hardware, so the interpreter has to count cycles as well
+            diff::run_frames(a.as_mut(), b.as_mut(), &input, args.frames, Timing::Vip, args.context)
+        }
+    };
+    Ok(divergence)
+}
+
+fn main() {
+    let args = Settings::from_args();
+
+    match run(&args) {
+        Ok(None) => println!("No divergence within {} frames", args.frames),
+        Ok(Some(divergence)) => {
+            for line in divergence.trace.iter() {
+                println!("{}", line);
+            }
+            match divergence.instruction {
+                Some(instruction) => println!("Diverged in frame {} at instruction {}:", divergence.frame, instruction),
+                None => println!("Diverged in frame {}:", divergence.frame),
+            }
+            for difference in divergence.differences.iter() {
+                println!("  {}", difference);
+            }
+            if divergence.random {
+                println!("CXNN ran before, the backends don't draw the same random numbers");
+            }
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    }
+}
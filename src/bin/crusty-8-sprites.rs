@@ -0,0 +1,128 @@
+use std::path::{Path, PathBuf};
+use minifb::{Key, MouseMode, Window, WindowOptions};
+use structopt::StructOpt;
+use crusty_8::sprites::{self, Sheet, Sprite};
+
+// Colours of lit and unlit sprite pixels and of the space between the sprites
+const ON: u32 = 0xFF_FF_FF;
+const OFF: u32 = 0x00_00_00;
+const SPACE: u32 = 0x40_40_40;
+
+// Sheet pixels per screen pixel in the viewer
+const VIEWER_SCALE: usize = 6;
+
+#[derive(StructOpt)]
+#[structopt(about = "Finds the sprites of a Chip-8 rom, shows them and exports them as a PNG sprite sheet.")]
+struct Settings {
+    #[structopt(short, long, parse(from_os_str))]
+    /// Specifies a path to a chip-8 rom
+    path: PathBuf,
+
+    #[structopt(long)]
+    /// Cuts the whole rom into sprites instead of looking for the ones drawn by DXYN after ANNN
+    all: bool,
+
+    #[structopt(long, default_value = "8")]
+    /// Height of the sprites with --all
+    height: usize,
+
+    #[structopt(long, default_value = "16")]
+    /// Sprites per row of the sheet
+    columns: usize,
+
+    #[structopt(short, long, parse(from_os_str))]
+    /// Writes the sprite sheet to a PNG file
+    output: Option<PathBuf>,
+
+    #[structopt(long, default_value = "1")]
+    /// Image pixels per sprite pixel in the PNG file
+    scale: usize,
+
+    #[structopt(long)]
+    /// Shows the sheet in a window, hovering over a sprite shows its address in the title
+    view: bool,
+}
+
+fn colour(pixel: Option<bool>) -> u32 {
+    match pixel {
+        Some(true) => ON,
+        Some(false) => OFF,
+        None => SPACE,
+    }
+}
+
+fn export(sheet: &Sheet, scale: usize, path: &Path) -> Result<(), String> {
+    let error = |e: &dyn std::fmt::Display| format!("Could not write {}: {}", path.display(), e);
+    let (width, height) = (sheet.width * scale, sheet.height * scale);
+    let mut data = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            let colour = colour(sheet.pixels[(y / scale) * sheet.width + x / scale]);
+            data.extend_from_slice(&colour.to_be_bytes()[1..]);
+        }
+    }
+
+    let file = std::fs::File::create(path).map_err(|e| error(&e))?;
+    let mut encoder = png::Encoder::new(std::io::BufWriter::new(file), width as u32, height as u32);
+    encoder.set_color(png::ColorType::RGB);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|e| error(&e))?;
+    writer.write_image_data(&data).map_err(|e| error(&e))
+}
+
+fn view(sheet: &Sheet, sprites: &[Sprite], name: &str) -> Result<(), String> {
+    let (width, height) = (sheet.width * VIEWER_SCALE, sheet.height * VIEWER_SCALE);
+    let mut window = Window::new(name, width, height, WindowOptions::default()).map_err(|e| e.to_string())?;
+    let buffer: Vec<u32> = (0..width * height)
+        .map(|index| colour(sheet.pixels[(index / width / VIEWER_SCALE) * sheet.width + index % width / VIEWER_SCALE]))
+        .collect();
+
+    let mut title = String::new();
+    while window.is_open() && !window.is_key_down(Key::Escape) {
+        let hovered = window.get_mouse_pos(MouseMode::Discard)
+            .and_then(|(x, y)| sheet.sprite_at(x as usize / VIEWER_SCALE, y as usize / VIEWER_SCALE))
+            .and_then(|index| sprites.get(index));
+        let current = match hovered {
+            Some(sprite) => format!("{} - 0x{:03X}, 8x{}", name, sprite.address, sprite.rows.len()),
+            None => String::from(name),
+        };
+        if current != title {
+            window.set_title(&current);
+            title = current;
+        }
+        window.update_with_buffer(&buffer, width, height).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+fn run(args: &Settings) -> Result<(), String> {
+    let rom = std::fs::read(&args.path).map_err(|e| format!("Could not read {}: {}", args.path.display(), e))?;
+    let sprites = if args.all { sprites::tiles(&rom, args.height) } else { sprites::referenced(&rom) };
+    if sprites.is_empty() {
+        return Err(String::from("No sprites found, try --all"));
+    }
+
+    for sprite in sprites.iter() {
+        let rows: Vec<String> = sprite.rows.iter().map(|row| format!("{:02X}", row)).collect();
+        println!("0x{:03X} 8x{:<2} {}", sprite.address, sprite.rows.len(), rows.join(" "));
+    }
+
+    let sheet = Sheet::new(&sprites, args.columns);
+    if let Some(path) = &args.output {
+        export(&sheet, args.scale.max(1), path)?;
+    }
+    if args.view {
+        let name = args.path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        view(&sheet, &sprites, &name)?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let args = Settings::from_args();
+
+    if let Err(e) = run(&args) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
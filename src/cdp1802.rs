@@ -0,0 +1,454 @@
+// The RCA CDP1802 processor of the COSMAC VIP.
+
+// Size of a serialized processor state, see Cdp1802::save_state.
+pub const STATE_SIZE: usize = 16 * 2 + 8;
+
+// Everything the processor is connected to.
+pub trait Bus {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+    // OUT 1-7
+    fn output(&mut self, port: u8, value: u8);
+    // INP 1-7
+    fn input(&mut self, port: u8) -> u8;
+    // Whether the external flag EF1-EF4 is asserted
+    fn flag(&self, flag: u8) -> bool;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cdp1802 {
+    // scratchpad registers
+    pub r: [u16; 16],
+    // register designator of the program counter
+    pub p: u8,
+    // register designator of the data pointer
+    pub x: u8,
+    // accumulator
+    pub d: u8,
+    // carry flag
+    pub df: bool,
+    // X and P saved by an interrupt
+    pub t: u8,
+    // interrupt enable
+    pub ie: bool,
+    // output flip-flop, which drives the speaker on the VIP
+    pub q: bool,
+    // halted by IDL until the next interrupt or DMA
+    pub idle: bool,
+}
+
+impl Default for Cdp1802 {
+    fn default() -> Cdp1802 {
+        Cdp1802::new()
+    }
+}
+
+impl Cdp1802 {
+    pub fn new() -> Cdp1802 {
+        let mut cpu = Cdp1802 { r: [0; 16], p: 0, x: 0, d: 0, df: false, t: 0, ie: true, q: false, idle: false };
+        cpu.reset();
+        cpu
+    }
+
+    // Like the CLEAR input, the other registers are left alone.
+    pub fn reset(&mut self) {
+        self.r[0] = 0;
+        self.p = 0;
+        self.x = 0;
+        self.ie = true;
+        self.q = false;
+        self.idle = false;
+    }
+
+    // Take an interrupt if they are enabled.
+    pub fn interrupt(&mut self) {
+        if self.ie {
+            self.t = self.x << 4 | self.p;
+            self.p = 1;
+            self.x = 2;
+            self.ie = false;
+            self.idle = false;
+        }
+    }
+
+    // Hand the byte at R0 to a DMA device, usually the display.
+    pub fn dma_out(&mut self, bus: &mut impl Bus) -> u8 {
+        let value = bus.read(self.r[0]);
+        self.r[0] = self.r[0].wrapping_add(1);
+        self.idle = false;
+        value
+    }
+
+    // Execute one instruction and return the number of machine cycles it took.
+    pub fn step(&mut self, bus: &mut impl Bus) -> u32 {
+        if self.idle {
+            return 1;
+        }
+
+        let opcode = self.immediate(bus);
+        let n = (opcode & 0x0F) as usize;
+        let x = self.x as usize;
+
+        match opcode >> 4 {
+            // IDL
+            0x0 if n == 0 => self.idle = true,
+            // LDN
+            0x0 => self.d = bus.read(self.r[n]),
+            // INC
+            0x1 => self.r[n] = self.r[n].wrapping_add(1),
+            // DEC
+            0x2 => self.r[n] = self.r[n].wrapping_sub(1),
+
+            // Short branches within the current page, the upper half of N inverts the condition
+            0x3 => {
+                let condition = match n & 0x7 {
+                    0 => true,
+                    1 => self.q,
+                    2 => self.d == 0,
+                    3 => self.df,
+                    flag => bus.flag(flag as u8 - 3),
+                };
+                // The page is the one of the target byte, even if the branch is taken at the end of a page
+                let page = self.r[self.p as usize] & 0xFF00;
+                let target = self.immediate(bus);
+                if condition != (n & 0x8 != 0) {
+                    self.r[self.p as usize] = page | target as u16;
+                }
+            }
+
+            // LDA
+            0x4 => {
+                self.d = bus.read(self.r[n]);
+                self.r[n] = self.r[n].wrapping_add(1);
+            }
+            // STR
+            0x5 => bus.write(self.r[n], self.d),
+
+            // IRX
+            0x6 if n == 0 => self.r[x] = self.r[x].wrapping_add(1),
+            // OUT
+            0x6 if n < 8 => {
+                let value = bus.read(self.r[x]);
+                self.r[x] = self.r[x].wrapping_add(1);
+                bus.output(n as u8, value);
+            }
+            // 68 is undefined on the 1802
+            0x6 if n == 8 => (),
+            // INP
+            0x6 => {
+                let value = bus.input(n as u8 & 0x7);
+                bus.write(self.r[x], value);
+                self.d = value;
+            }
+
+            0x7 => match n {
+                // RET and DIS
+                0x0 | 0x1 => {
+                    let value = bus.read(self.r[x]);
+                    self.r[x] = self.r[x].wrapping_add(1);
+                    self.x = value >> 4;
+                    self.p = value & 0x0F;
+                    self.ie = n == 0;
+                }
+                // LDXA
+                0x2 => {
+                    self.d = bus.read(self.r[x]);
+                    self.r[x] = self.r[x].wrapping_add(1);
+                }
+                // STXD
+                0x3 => {
+                    bus.write(self.r[x], self.d);
+                    self.r[x] = self.r[x].wrapping_sub(1);
+                }
+                // ADC, SDB, SMB and their immediate versions
+                0x4 | 0x5 | 0x7 | 0xC | 0xD | 0xF => {
+                    let operand = if n < 8 { bus.read(self.r[x]) } else { self.immediate(bus) };
+                    self.arithmetic(n & 0x7, operand, self.df);
+                }
+                // SHRC
+                0x6 => {
+                    let carry = self.d & 0x01 != 0;
+                    self.d = self.d >> 1 | (self.df as u8) << 7;
+                    self.df = carry;
+                }
+                // SHLC
+                0xE => {
+                    let carry = self.d & 0x80 != 0;
+                    self.d = self.d << 1 | self.df as u8;
+                    self.df = carry;
+                }
+                // SAV
+                0x8 => bus.write(self.r[x], self.t),
+                // MARK
+                0x9 => {
+                    self.t = self.x << 4 | self.p;
+                    bus.write(self.r[2], self.t);
+                    self.x = self.p;
+                    self.r[2] = self.r[2].wrapping_sub(1);
+                }
+                // REQ and SEQ
+                _ => self.q = n == 0xB,
+            },
+
+            // GLO, GHI, PLO, PHI
+            0x8 => self.d = self.r[n] as u8,
+            0x9 => self.d = (self.r[n] >> 8) as u8,
+            0xA => self.r[n] = self.r[n] & 0xFF00 | self.d as u16,
+            0xB => self.r[n] = self.r[n] & 0x00FF | (self.d as u16) << 8,
+
+            // Long branches and skips, which take an extra cycle
+            0xC => {
+                let p = self.p as usize;
+                let condition = match n & 0x3 {
+                    0 => true,
+                    1 => self.q,
+                    2 => self.d == 0,
+                    _ => self.df,
+                };
+                match n {
+                    // LBR, LBQ, LBZ, LBDF and their inversions
+                    0x0..=0x3 | 0x8..=0xB => {
+                        if condition != (n & 0x8 != 0) {
+                            let high = bus.read(self.r[p]);
+                            let low = bus.read(self.r[p].wrapping_add(1));
+                            self.r[p] = u16::from_be_bytes([high, low]);
+                        } else {
+                            self.r[p] = self.r[p].wrapping_add(2);
+                        }
+                    }
+                    // NOP
+                    0x4 => (),
+                    // LSIE
+                    0xC => if self.ie { self.r[p] = self.r[p].wrapping_add(2) },
+                    // LSNQ, LSNZ, LSNF, LSQ, LSZ and LSDF
+                    _ => if condition == (n & 0x8 != 0) { self.r[p] = self.r[p].wrapping_add(2) },
+                }
+                return 3;
+            }
+
+            // SEP
+            0xD => self.p = n as u8,
+            // SEX
+            0xE => self.x = n as u8,
+
+            // Logic and arithmetic with M(R(X)), F8 and up use the immediate byte instead
+            _ => {
+                let operand = match n {
+                    0x0..=0x5 | 0x7 => bus.read(self.r[x]),
+                    0x8..=0xD | 0xF => self.immediate(bus),
+                    _ => 0,
+                };
+                match n & 0x7 {
+                    // LDX and LDI
+                    0x0 => self.d = operand,
+                    0x1 => self.d |= operand,
+                    0x2 => self.d &= operand,
+                    0x3 => self.d ^= operand,
+                    // SHR and SHL
+                    0x6 if n == 0x6 => {
+                        self.df = self.d & 0x01 != 0;
+                        self.d >>= 1;
+                    }
+                    0x6 => {
+                        self.df = self.d & 0x80 != 0;
+                        self.d <<= 1;
+                    }
+                    // ADD, SD and SM without carry
+                    operation => self.arithmetic(operation, operand, operation != 0x4),
+                }
+            }
+        }
+        2
+    }
+
+    // Read the byte at the program counter and advance it.
+    fn immediate(&mut self, bus: &mut impl Bus) -> u8 {
+        let p = self.p as usize;
+        let value = bus.read(self.r[p]);
+        self.r[p] = self.r[p].wrapping_add(1);
+        value
+    }
+
+    // 4 adds, 5 subtracts D from the operand and 7 the operand from D.
+    // DF is the carry for additions and set if there was no borrow for subtractions.
+    fn arithmetic(&mut self, operation: usize, operand: u8, carry: bool) {
+        let (result, df) = match operation {
+            0x4 => {
+                let sum = self.d as u16 + operand as u16 + carry as u16;
+                (sum as u8, sum > 0xFF)
+            }
+            0x5 => {
+                let difference = operand as i16 - self.d as i16 - !carry as i16;
+                (difference as u8, difference >= 0)
+            }
+            _ => {
+                let difference = self.d as i16 - operand as i16 - !carry as i16;
+                (difference as u8, difference >= 0)
+            }
+        };
+        self.d = result;
+        self.df = df;
+    }
+
+    // Serialize the registers into STATE_SIZE bytes.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = Vec::with_capacity(STATE_SIZE);
+        for register in self.r.iter() {
+            state.extend_from_slice(&register.to_be_bytes());
+        }
+        state.extend_from_slice(&[
+            self.p, self.x, self.d, self.df as u8, self.t, self.ie as u8, self.q as u8, self.idle as u8,
+        ]);
+        state
+    }
+
+    // Restore a state created by Cdp1802::save_state, the caller checks the length.
+    pub fn load_state(&mut self, state: &[u8]) {
+        for (i, register) in self.r.iter_mut().enumerate() {
+            *register = u16::from_be_bytes([state[i * 2], state[i * 2 + 1]]);
+        }
+        let flags = &state[32..STATE_SIZE];
+        self.p = flags[0] & 0x0F;
+        self.x = flags[1] & 0x0F;
+        self.d = flags[2];
+        self.df = flags[3] != 0;
+        self.t = flags[4];
+        self.ie = flags[5] != 0;
+        self.q = flags[6] != 0;
+        self.idle = flags[7] != 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Ram {
+        memory: [u8; 256],
+        flags: [bool; 4],
+        output: Vec<(u8, u8)>,
+    }
+
+    impl Bus for Ram {
+        fn read(&mut self, address: u16) -> u8 { self.memory[address as usize & 0xFF] }
+        fn write(&mut self, address: u16, value: u8) { self.memory[address as usize & 0xFF] = value }
+        fn output(&mut self, port: u8, value: u8) { self.output.push((port, value)) }
+        fn input(&mut self, port: u8) -> u8 { 0x10 + port }
+        fn flag(&self, flag: u8) -> bool { self.flags[flag as usize - 1] }
+    }
+
+    fn run(program: &[u8], steps: usize) -> (Cdp1802, Ram) {
+        let mut ram = Ram { memory: [0; 256], flags: [false; 4], output: Vec::new() };
+        ram.memory[..program.len()].copy_from_slice(program);
+        let mut cpu = Cdp1802::new();
+        for _ in 0..steps {
+            cpu.step(&mut ram);
+        }
+        (cpu, ram)
+    }
+
+    #[test]
+    fn arithmetic() {
+        // LDI 0xF0, ADI 0x20 -> 0x10 with carry, ADCI 0x00 -> 0x11
+        let (cpu, _) = run(&[0xF8, 0xF0, 0xFC, 0x20, 0x7C, 0x00], 3);
+        assert_eq!((0x11, false), (cpu.d, cpu.df));
+
+        // LDI 0x10, SMI 0x20 -> borrow, SDI 0x05 -> 0x05 - 0xF0
+        let (cpu, _) = run(&[0xF8, 0x10, 0xFF, 0x20], 2);
+        assert_eq!((0xF0, false), (cpu.d, cpu.df));
+        let (cpu, _) = run(&[0xF8, 0x10, 0xFF, 0x20, 0xFD, 0x05], 3);
+        assert_eq!((0x15, false), (cpu.d, cpu.df));
+
+        // LDI 0x81, SHR, SHLC
+        let (cpu, _) = run(&[0xF8, 0x81, 0xF6, 0x7E], 3);
+        assert_eq!((0x81, false), (cpu.d, cpu.df));
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        // LDI 0x80, PLO 5, LDI 0x42, STR 5, INC 5, GLO 5, LDN 5 ... LDA 5
+        let (cpu, ram) = run(&[0xF8, 0x80, 0xA5, 0xF8, 0x42, 0x55, 0x15, 0x85, 0x25, 0x45], 8);
+        assert_eq!(0x42, ram.memory[0x80]);
+        assert_eq!(0x42, cpu.d);
+        assert_eq!(0x81, cpu.r[5]);
+    }
+
+    #[test]
+    fn branches() {
+        // LDI 0, BZ 0x10
+        let (cpu, _) = run(&[0xF8, 0x00, 0x32, 0x10], 2);
+        assert_eq!(0x10, cpu.r[0]);
+        // LDI 1, BZ 0x10 falls through
+        let (cpu, _) = run(&[0xF8, 0x01, 0x32, 0x10], 2);
+        assert_eq!(0x04, cpu.r[0]);
+        // SEQ, LBQ 0x0020
+        let (cpu, _) = run(&[0x7B, 0xC1, 0x00, 0x20], 2);
+        assert_eq!(0x20, cpu.r[0]);
+        // LSNQ skips two bytes when Q is off
+        let (cpu, _) = run(&[0xC5], 1);
+        assert_eq!(0x03, cpu.r[0]);
+
+        // B3 follows EF3
+        let mut ram = Ram { memory: [0; 256], flags: [false, false, true, false], output: Vec::new() };
+        ram.memory[..2].copy_from_slice(&[0x36, 0x40]);
+        let mut cpu = Cdp1802::new();
+        cpu.step(&mut ram);
+        assert_eq!(0x40, cpu.r[0]);
+
+        // BR with its target byte at the end of a page stays in that page
+        let mut ram = Ram { memory: [0; 256], flags: [false; 4], output: Vec::new() };
+        ram.memory[0xFE..].copy_from_slice(&[0x30, 0x10]);
+        let mut cpu = Cdp1802::new();
+        cpu.r[0] = 0x1FE;
+        cpu.step(&mut ram);
+        assert_eq!(0x110, cpu.r[0]);
+    }
+
+    #[test]
+    fn subroutines_and_interrupts() {
+        // LDI 0x10, PLO 3, SEP 3 ... 0x10: SEP 0
+        let mut program = [0; 0x11];
+        program[..4].copy_from_slice(&[0xF8, 0x10, 0xA3, 0xD3]);
+        program[0x10] = 0xD0;
+        let (cpu, _) = run(&program, 4);
+        assert_eq!((0, 0x04, 0x11), (cpu.p, cpu.r[0], cpu.r[3]));
+
+        // An interrupt saves X and P in T and continues with R1
+        let (mut cpu, mut ram) = run(&[0xE5, 0x00], 2);
+        assert!(cpu.idle);
+        cpu.r[2] = 0x80;
+        cpu.interrupt();
+        assert_eq!((1, 2, 0x50, false, false), (cpu.p, cpu.x, cpu.t, cpu.ie, cpu.idle));
+
+        // SAV, then RET restores them from M(R2)
+        ram.memory[0x80] = 0x50;
+        ram.memory[0] = 0x78;
+        ram.memory[1] = 0x70;
+        cpu.r[1] = 0;
+        cpu.step(&mut ram);
+        cpu.step(&mut ram);
+        assert_eq!((0, 5, true), (cpu.p, cpu.x, cpu.ie));
+    }
+
+    #[test]
+    fn input_and_output() {
+        // LDI 0x80, PLO 2, SEX 2, LDI 7, STR 2, OUT 2, DEC 2, INP 1
+        let (cpu, ram) = run(&[0xF8, 0x80, 0xA2, 0xE2, 0xF8, 0x07, 0x52, 0x62, 0x22, 0x69], 8);
+        assert_eq!(vec![(2, 0x07)], ram.output);
+        assert_eq!(0x11, cpu.d);
+        assert_eq!(0x11, ram.memory[0x80]);
+    }
+
+    #[test]
+    fn cycles_and_state() {
+        let mut ram = Ram { memory: [0; 256], flags: [false; 4], output: Vec::new() };
+        ram.memory[..2].copy_from_slice(&[0xC4, 0x7B]);
+        let mut cpu = Cdp1802::new();
+        assert_eq!(3, cpu.step(&mut ram));
+        assert_eq!(2, cpu.step(&mut ram));
+
+        let mut restored = Cdp1802::new();
+        restored.load_state(&cpu.save_state());
+        assert_eq!(cpu, restored);
+    }
+}
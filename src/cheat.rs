@@ -0,0 +1,323 @@
+// Cheats: values frozen at memory addresses, and a search that finds the addresses of lives or score counters
+// by narrowing down the memory between snapshots, e.g. to the bytes that decreased since a life was lost.
+//
+// Cheats are saved as text, one per line as address=value and a name, both in hex. A leading '-' disables one:
+//   2A4=03 Lives
+//   -2F0=99 Score
+
+use std::path::PathBuf;
+use crate::chip8::Chip8;
+
+// The search leaves out the font and interpreter area below the program.
+const SEARCH_START: u16 = 0x200;
+// Candidates listed after every step of a search, if there aren't more
+const LISTED: usize = 10;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cheat {
+    pub address: u16,
+    pub value: u8,
+    pub enabled: bool,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cheats {
+    pub cheats: Vec<Cheat>,
+    // all cheats can be switched off at once without forgetting which ones are enabled
+    pub active: bool,
+}
+
+impl Default for Cheats {
+    fn default() -> Cheats {
+        Cheats { cheats: Vec::new(), active: true }
+    }
+}
+
+impl Cheats {
+    pub fn parse(text: &str) -> Result<Cheats, String> {
+        let mut cheats = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = || format!("Line {}: expected address=value and a name, e.g. 2A4=03 Lives", number + 1);
+            let (enabled, line) = match line.strip_prefix('-') {
+                Some(line) => (false, line),
+                None => (true, line),
+            };
+            let (assignment, name) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let (address, value) = assignment.split_once('=').ok_or_else(error)?;
+            let address = u16::from_str_radix(address, 16).ok().filter(|&address| address < 4096).ok_or_else(error)?;
+            let value = u8::from_str_radix(value, 16).map_err(|_| error())?;
+            cheats.push(Cheat { address, value, enabled, name: name.trim().to_string() });
+        }
+        Ok(Cheats { cheats, active: true })
+    }
+
+    pub fn to_text(&self) -> String {
+        self.cheats.iter()
+            .map(|cheat| {
+                let line = format!("{}{:03X}={:02X} {}", if cheat.enabled { "" } else { "-" }, cheat.address, cheat.value, cheat.name);
+                format!("{}\n", line.trim_end())
+            })
+            .collect()
+    }
+
+    // Write the frozen values, call this before every frame.
+    // Memory that already has the value is left alone, so the history doesn't see a change every frame.
+    pub fn apply(&self, chip8: &mut Chip8) {
+        if !self.active {
+            return;
+        }
+        for cheat in self.cheats.iter().filter(|cheat| cheat.enabled) {
+            let address = cheat.address as usize;
+            if chip8.memory()[address] != cheat.value {
+                chip8.memory_mut()[address] = cheat.value;
+            }
+        }
+    }
+}
+
+// How a byte compares to the last snapshot of a search.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Equal(u8),
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+}
+
+impl Comparison {
+    fn matches(self, before: u8, now: u8) -> bool {
+        match self {
+            Comparison::Equal(value) => now == value,
+            Comparison::Changed => now != before,
+            Comparison::Unchanged => now == before,
+            Comparison::Increased => now > before,
+            Comparison::Decreased => now < before,
+        }
+    }
+}
+
+pub struct Search {
+    snapshot: Vec<u8>,
+    candidates: Vec<u16>,
+}
+
+impl Search {
+    // Every address from the program on is a candidate at first.
+    pub fn new(memory: &[u8]) -> Search {
+        Search { snapshot: memory.to_vec(), candidates: (SEARCH_START..memory.len() as u16).collect() }
+    }
+
+    // Keep the candidates that compare to the last snapshot as asked, then take a new one.
+    pub fn narrow(&mut self, memory: &[u8], comparison: Comparison) {
+        let snapshot = &self.snapshot;
+        self.candidates.retain(|&address| comparison.matches(snapshot[address as usize], memory[address as usize]));
+        self.snapshot = memory.to_vec();
+    }
+
+    pub fn candidates(&self) -> &[u16] {
+        &self.candidates
+    }
+}
+
+// Commands typed while the rom runs, see Console::execute.
+pub struct Console {
+    pub cheats: Cheats,
+    search: Option<Search>,
+    // where save writes the cheats
+    path: Option<PathBuf>,
+}
+
+const HELP: &str = "\
+search                   start a new search over the memory
+= VALUE                  keep the addresses holding a value
+changed, unchanged       keep the addresses that changed or not since the last step
+increased, decreased     keep the addresses that grew or shrank since the last step
+list                     show the candidates of the search
+freeze ADDRESS [VALUE] [NAME]  keep an address at a value, its current one by default
+poke ADDRESS VALUE       write a value once
+cheats                   show the cheats
+on N, off N, remove N    enable, disable or remove a cheat
+save                     write the cheats to disk
+Addresses are hex, values decimal or hex with 0x.";
+
+impl Console {
+    pub fn new(cheats: Cheats, path: Option<PathBuf>) -> Console {
+        Console { cheats, search: None, path }
+    }
+
+    // Run a command against the machine and return what to show the user.
+    pub fn execute(&mut self, line: &str, chip8: &mut Chip8) -> Result<String, String> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(String::new()),
+        };
+        let comparison = match command {
+            "=" => Some(Comparison::Equal(parse_value(words.next())?)),
+            "changed" => Some(Comparison::Changed),
+            "unchanged" => Some(Comparison::Unchanged),
+            "increased" => Some(Comparison::Increased),
+            "decreased" => Some(Comparison::Decreased),
+            _ => None,
+        };
+        if let Some(comparison) = comparison {
+            let search = self.search.as_mut().ok_or("No search was started, see search")?;
+            search.narrow(chip8.memory(), comparison);
+            return Ok(self.list(chip8, LISTED));
+        }
+
+        match command {
+            "search" => {
+                self.search = Some(Search::new(chip8.memory()));
+                Ok(format!("Searching {} addresses", 4096 - SEARCH_START))
+            }
+            "list" => Ok(self.list(chip8, usize::MAX)),
+            "freeze" => {
+                let address = parse_address(words.next())?;
+                let mut rest: Vec<&str> = words.collect();
+                // A value is optional, names rarely start with a digit
+                let value = match rest.first().map(|word| parse_value(Some(word))) {
+                    Some(Ok(value)) => {
+                        rest.remove(0);
+                        value
+                    }
+                    _ => chip8.memory()[address as usize],
+                };
+                self.cheats.cheats.push(Cheat { address, value, enabled: true, name: rest.join(" ") });
+                Ok(format!("Cheat {} keeps {:03X} at {}", self.cheats.cheats.len(), address, value))
+            }
+            "poke" => {
+                let address = parse_address(words.next())?;
+                let value = parse_value(words.next())?;
+                chip8.memory_mut()[address as usize] = value;
+                Ok(format!("{:03X} is {}", address, value))
+            }
+            "cheats" if self.cheats.cheats.is_empty() => Ok(String::from("No cheats")),
+            "cheats" => Ok(self.cheats.cheats.iter().enumerate()
+                .map(|(index, cheat)| format!(
+                    "{:>2} {} {:03X}={:<3} {}",
+                    index + 1, if cheat.enabled { "on " } else { "off" }, cheat.address, cheat.value, cheat.name,
+                ).trim_end().to_string())
+                .collect::<Vec<String>>()
+                .join("\n")),
+            "on" | "off" => {
+                let index = self.cheat(words.next())?;
+                self.cheats.cheats[index].enabled = command == "on";
+                Ok(format!("Cheat {} is {}", index + 1, command))
+            }
+            "remove" => {
+                let index = self.cheat(words.next())?;
+                self.cheats.cheats.remove(index);
+                Ok(format!("Removed cheat {}", index + 1))
+            }
+            "save" => {
+                let path = self.path.as_ref().ok_or("There is nowhere to save the cheats")?;
+                if let Some(directory) = path.parent() {
+                    std::fs::create_dir_all(directory).map_err(|e| format!("Could not create {}: {}", directory.display(), e))?;
+                }
+                std::fs::write(path, self.cheats.to_text()).map_err(|e| format!("Could not write {}: {}", path.display(), e))?;
+                Ok(format!("Saved {} cheats to {}", self.cheats.cheats.len(), path.display()))
+            }
+            "help" => Ok(String::from(HELP)),
+            _ => Err(format!("Unknown command '{}', see help", command)),
+        }
+    }
+
+    // The number of candidates, and them with their current values if there aren't more than a limit.
+    fn list(&self, chip8: &Chip8, limit: usize) -> String {
+        let candidates = match &self.search {
+            Some(search) => search.candidates(),
+            None => return String::from("No search was started, see search"),
+        };
+        let mut lines = vec![format!("{} candidates", candidates.len())];
+        if candidates.len() <= limit {
+            lines.extend(candidates.iter().map(|&address| format!("{:03X}={}", address, chip8.memory()[address as usize])));
+        }
+        lines.join("\n")
+    }
+
+    // Cheats are numbered from 1 like in the list.
+    fn cheat(&self, number: Option<&str>) -> Result<usize, String> {
+        number.and_then(|number| number.parse::<usize>().ok())
+            .filter(|&number| number >= 1 && number <= self.cheats.cheats.len())
+            .map(|number| number - 1)
+            .ok_or_else(|| String::from("Expected the number of a cheat, see cheats"))
+    }
+}
+
+fn parse_address(word: Option<&str>) -> Result<u16, String> {
+    word.and_then(|word| u16::from_str_radix(word.trim_start_matches("0x"), 16).ok())
+        .filter(|&address| address < 4096)
+        .ok_or_else(|| String::from("Expected an address in hex"))
+}
+
+fn parse_value(word: Option<&str>) -> Result<u8, String> {
+    let word = word.ok_or("Expected a value")?;
+    match word.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => word.parse(),
+    }
+    .map_err(|_| format!("'{}' is not a byte", word))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn text_format() {
+        let cheats = Cheats::parse("# Invaders\n2A4=03 Lives\n\n-2f0=99  High score \n").unwrap();
+        assert_eq!(Cheat { address: 0x2A4, value: 3, enabled: true, name: String::from("Lives") }, cheats.cheats[0]);
+        assert_eq!(Cheat { address: 0x2F0, value: 0x99, enabled: false, name: String::from("High score") }, cheats.cheats[1]);
+        assert_eq!("2A4=03 Lives\n-2F0=99 High score\n", cheats.to_text());
+        assert_eq!(cheats, Cheats::parse(&cheats.to_text()).unwrap());
+
+        assert_eq!(Err(String::from("Line 2: expected address=value and a name, e.g. 2A4=03 Lives")), Cheats::parse("\n1000=01"));
+        assert!(Cheats::parse("2A4=100").is_err());
+    }
+
+    #[test]
+    fn apply() {
+        let mut chip8 = Chip8::new_with_state();
+        let mut cheats = Cheats::parse("300=07\n-301=07").unwrap();
+        cheats.apply(&mut chip8);
+        assert_eq!(&[7, 0], &chip8.memory()[0x300..0x302]);
+
+        chip8.memory_mut()[0x300] = 1;
+        cheats.active = false;
+        cheats.apply(&mut chip8);
+        assert_eq!(1, chip8.memory()[0x300]);
+    }
+
+    #[test]
+    fn search() {
+        let mut chip8 = Chip8::new_with_state();
+        let mut console = Console::new(Cheats::default(), None);
+
+        assert_eq!(Ok(String::from("Searching 3584 addresses")), console.execute("search", &mut chip8));
+        chip8.memory_mut()[0x300] = 3;
+        chip8.memory_mut()[0x400] = 3;
+        assert_eq!(Ok(String::from("2 candidates\n300=3\n400=3")), console.execute("= 3", &mut chip8));
+        // A life lost
+        chip8.memory_mut()[0x300] = 2;
+        assert_eq!(Ok(String::from("1 candidates\n300=2")), console.execute("decreased", &mut chip8));
+        assert_eq!(Ok(String::from("1 candidates\n300=2")), console.execute("unchanged", &mut chip8));
+
+        assert_eq!(Ok(String::from("Cheat 1 keeps 300 at 9")), console.execute("freeze 300 0x09 Lives", &mut chip8));
+        assert_eq!(Ok(String::from("Cheat 2 keeps 400 at 3")), console.execute("freeze 400 Something else", &mut chip8));
+        assert_eq!(Ok(String::from("Cheat 2 is off")), console.execute("off 2", &mut chip8));
+        assert_eq!(Ok(String::from(" 1 on  300=9   Lives\n 2 off 400=3   Something else")), console.execute("cheats", &mut chip8));
+        assert_eq!("300=09 Lives\n-400=03 Something else\n", console.cheats.to_text());
+
+        assert!(console.execute("poke 1000 1", &mut chip8).is_err());
+        assert!(console.execute("on 3", &mut chip8).is_err());
+        assert!(console.execute("save", &mut chip8).is_err());
+        assert!(console.execute("jump", &mut chip8).is_err());
+    }
+}
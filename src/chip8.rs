@@ -1,7 +1,189 @@
+use std::any::Any;
+use std::collections::BTreeSet;
 use std::fs::File;
 use std::io::prelude::*;
-use rand::Rng;
-use crate::io::{WIDTH, HEIGHT};
+use rand::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use crate::database::{self, Entry};
+use crate::history::{History, Inputs, Location, Write};
+use crate::machine::{Snapshot, SNAPSHOT_END};
+use crate::profile::Profiler;
+use crate::trace::Tracer;
+
+pub const WIDTH: usize = 64;
+pub const HEIGHT: usize = 32;
+
+// Size of a serialized machine state, see Chip8::save_state.
+pub const STATE_SIZE: usize = 2 + 2 + 16 + 4096 + 16 * 2 + 1 + 1 + 1 + 16 + WIDTH * HEIGHT + 1 + 1 + 1 + 4;
+
+// Instructions at these addresses and above don't fit into memory, the PC has to stay below.
+pub const PC_LIMIT: u16 = 0xFFE;
+
+// Size of a serialized random number generator, see Random::save.
+pub const RNG_STATE_SIZE: usize = 32 + 8;
+
+// Machine cycles of the COSMAC VIP per frame, a 1.7609 MHz clock with 8 clock cycles per machine cycle.
+pub const VIP_CYCLES_PER_FRAME: i32 = 3668;
+// Cycles of every frame taken by the display DMA of the 1861 and the interrupt routine.
+const VIP_DISPLAY_CYCLES: i32 = 1024 + 30;
+// Cycles the interpreter spends fetching and decoding an instruction.
+const VIP_FETCH_CYCLES: i32 = 40;
+
+// How much code runs per 60 Hz frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Timing {
+    // A fixed number of instructions
+    Instructions(u64),
+    // As many instructions as the COSMAC VIP would get through, each charged its cycle cost
+    Vip,
+}
+
+// The source of CXNN. Unlike StdRng it can be saved, so restoring a state repeats the same random numbers.
+#[derive(Clone)]
+pub(crate) struct Random {
+    seed: [u8; 32],
+    rng: ChaCha20Rng,
+    // words drawn since seeding, ChaCha20Rng::get_word_pos underflows before the first one
+    drawn: u64,
+}
+
+impl Random {
+    fn new(seed: [u8; 32]) -> Random {
+        Random { seed, rng: ChaCha20Rng::from_seed(seed), drawn: 0 }
+    }
+
+    fn byte(&mut self) -> u8 {
+        self.drawn += 1;
+        self.rng.next_u32() as u8
+    }
+
+    // The seed and how far the generator got, RNG_STATE_SIZE bytes.
+    fn save(&self) -> Vec<u8> {
+        let mut state = self.seed.to_vec();
+        state.extend_from_slice(&self.drawn.to_be_bytes());
+        state
+    }
+
+    // Restore a generator saved by Random::save, the caller checks the length.
+    fn load(state: &[u8]) -> Random {
+        let mut seed = [0; 32];
+        seed.copy_from_slice(&state[..32]);
+        let mut drawn = [0; 8];
+        drawn.copy_from_slice(&state[32..RNG_STATE_SIZE]);
+        let mut random = Random::new(seed);
+        random.drawn = u64::from_be_bytes(drawn);
+        random.rng.set_word_pos(random.drawn as u128);
+        random
+    }
+}
+
+// The registers of the interpreter, e.g. for tracing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Registers {
+    pub pc: u16,
+    pub i: u16,
+    pub v: [u8; 16],
+    pub sp: u8,
+    pub dt: u8,
+    pub st: u8,
+}
+
+// Progress of an FX0A instruction.
+// Like on the COSMAC VIP, a key counts once it has been pressed and released again.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyWait {
+    // Waiting for any key to go down
+    Press,
+    // Waiting for this key to go up again
+    Release(u8),
+}
+
+// How often every address of the memory was accessed, see Chip8::count_accesses.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccessCounts {
+    // sprites drawn by DXYN and FX65
+    pub reads: Vec<u32>,
+    // FX33 and FX55
+    pub writes: Vec<u32>,
+    // both bytes of every executed instruction
+    pub fetches: Vec<u32>,
+}
+
+impl Default for AccessCounts {
+    fn default() -> AccessCounts {
+        AccessCounts { reads: vec![0; 4096], writes: vec![0; 4096], fetches: vec![0; 4096] }
+    }
+}
+
+impl AccessCounts {
+    fn add(counts: &mut [u32], address: usize, length: usize) {
+        for offset in 0..length {
+            let count = &mut counts[(address + offset) & 0xFFF];
+            *count = count.saturating_add(1);
+        }
+    }
+}
+
+// Behaviour that differs between interpreters.
+// The vip profile corresponds to the original COSMAC VIP interpreter,
+// the default modern one to it without the display wait, like most emulators.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Quirks {
+    // DXYN waits for the next frame like the VIP, which draws at most one sprite per frame
+    pub display_wait: bool,
+    // 8XY6 and 8XYE shift VX in place instead of VY
+    pub shift: bool,
+    // FX55 and FX65 leave I unchanged
+    pub load_store: bool,
+    // BNNN jumps to NNN + VX instead of NNN + V0
+    pub jump: bool,
+    // DXYN wraps sprites around the screen edges instead of clipping them
+    pub wrap: bool,
+}
+
+impl Quirks {
+    pub const PROFILES: [&'static str; 3] = ["modern", "vip", "chip48"];
+    pub const DEFAULT_PROFILE: &'static str = "modern";
+    pub const NAMES: [&'static str; 5] = ["display_wait", "shift", "load_store", "jump", "wrap"];
+
+    // Look up a named set of quirks, see Quirks::PROFILES.
+    // Single quirks can be changed after the profile, e.g. "vip,wrap" or "vip,no-display_wait".
+    pub fn from_profile(spec: &str) -> Option<Quirks> {
+        let mut parts = spec.split(',').map(str::trim);
+        let mut quirks = match parts.next()? {
+            "modern" => Quirks::default(),
+            "vip" => Quirks { display_wait: true, ..Quirks::default() },
+            "chip48" => Quirks { shift: true, load_store: true, jump: true, ..Quirks::default() },
+            _ => return None,
+        };
+
+        for part in parts {
+            let (name, enabled) = match part.strip_prefix("no-") {
+                Some(name) => (name, false),
+                None => (part, true),
+            };
+            let quirk = match name {
+                "display_wait" => &mut quirks.display_wait,
+                "shift" => &mut quirks.shift,
+                "load_store" => &mut quirks.load_store,
+                "jump" => &mut quirks.jump,
+                "wrap" => &mut quirks.wrap,
+                _ => return None,
+            };
+            *quirk = enabled;
+        }
+        Some(quirks)
+    }
+}
+
+// Code the frontend runs at the start and end of every frame and before instructions at the addresses it
+// watches, like a script. It gets the whole machine meanwhile.
+pub trait Hook: Any + Send {
+    fn begin_frame(&mut self, chip8: &mut Chip8);
+    fn end_frame(&mut self, chip8: &mut Chip8);
+    fn watches(&self, pc: u16) -> bool;
+    fn reached(&mut self, chip8: &mut Chip8);
+}
 
 pub struct Chip8 {
     // program counter,
@@ -26,6 +208,28 @@ pub struct Chip8 {
     pub framebuffer: [bool; WIDTH * HEIGHT],
     // draw flag
     pub should_draw: bool,
+    // FX0A progress, None if not waiting for a key
+    key_wait: Option<KeyWait>,
+    // whether a frame started since the last instruction, see Quirks::display_wait
+    vblank: bool,
+    // machine cycles left in the current frame with Timing::Vip, negative if the last instruction ran over
+    cycles: i32,
+    // interpreter specific behaviour
+    pub quirks: Quirks,
+    // source of CXNN
+    rng: Random,
+    // logs every executed instruction
+    pub tracer: Option<Tracer>,
+    // counts executed instructions per address, opcode and call stack
+    pub profiler: Option<Profiler>,
+    // per-address memory accesses, only counted when asked for
+    accesses: Option<AccessCounts>,
+    // run_frame stops before executing an instruction at one of these addresses
+    pub breakpoints: BTreeSet<u16>,
+    // every executed instruction, to go back in time
+    pub history: Option<History>,
+    // called at the start and end of every frame and at the addresses it watches
+    pub hook: Option<Box<dyn Hook>>,
 }
 
 impl Chip8 {
@@ -42,6 +246,17 @@ impl Chip8 {
             keys: [false; 16],
             framebuffer: [false; WIDTH * HEIGHT],
             should_draw: false,
+            key_wait: None,
+            vblank: false,
+            cycles: 0,
+            quirks: Quirks::default(),
+            rng: Random::new(rand::random()),
+            tracer: None,
+            profiler: None,
+            accesses: None,
+            breakpoints: BTreeSet::new(),
+            history: None,
+            hook: None,
         }
     }
 
@@ -61,18 +276,420 @@ impl Chip8 {
         )
     }
 
-    pub fn load_rom(&mut self, f: &File) {
-        for (i, byte) in f.bytes().enumerate() {
-            let index = 0x200 + i;
-            self.memory[index] = byte.unwrap();
+    // Returns the database entry of a known rom, the caller decides whether to use its quirks and timing.
+    pub fn load_rom(&mut self, mut f: &File) -> Option<&'static Entry> {
+        let mut rom = Vec::new();
+        f.read_to_end(&mut rom).unwrap();
+        self.load_bytes(&rom);
+        database::lookup(&rom)
+    }
+
+    pub fn load_bytes(&mut self, rom: &[u8]) {
+        self.changed();
+        self.memory[0x200..(0x200 + rom.len())].copy_from_slice(rom);
+    }
+
+    // Number of times CXNN ran since the last seed.
+    pub fn random_numbers(&self) -> u64 {
+        self.rng.drawn
+    }
+
+    // Make CXNN deterministic, e.g. to compare two machines.
+    pub fn seed(&mut self, seed: u64) {
+        let mut bytes = [0; 32];
+        bytes[..8].copy_from_slice(&seed.to_be_bytes());
+        self.rng = Random::new(bytes);
+        self.changed();
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers { pc: self.pc, i: self.i, v: self.v, sp: self.sp as u8, dt: self.dt, st: self.st }
+    }
+
+    // Change the registers, e.g. from a debugger. The stack pointer can't go past the stack.
+    pub fn set_registers(&mut self, registers: Registers) {
+        self.changed();
+        self.pc = registers.pc & 0xFFF;
+        self.i = registers.i;
+        self.v = registers.v;
+        self.sp = (registers.sp as usize).min(self.stack.len());
+        self.dt = registers.dt;
+        self.st = registers.st;
+    }
+
+    // Return addresses of the subroutines being executed, the innermost last.
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..self.sp]
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        self.changed();
+        &mut self.memory
+    }
+
+    pub fn at_breakpoint(&self) -> bool {
+        self.breakpoints.contains(&self.pc)
+    }
+
+    // The parts of the state that every backend has, see Snapshot.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            pc: self.pc,
+            i: self.i,
+            v: self.v,
+            memory: self.memory[0x200..SNAPSHOT_END].to_vec(),
+            framebuffer: self.framebuffer.to_vec(),
+        }
+    }
+
+    // Changes from outside can't be executed again, see History.
+    fn changed(&mut self) {
+        if let Some(history) = &mut self.history {
+            history.changed();
+        }
+    }
+
+    fn inputs(&self) -> Inputs {
+        let keys = self.keys.iter().rev().fold(0, |keys, &key| keys << 1 | key as u16);
+        Inputs { keys, dt: self.dt, st: self.st, vblank: self.vblank }
+    }
+
+    fn set_inputs(&mut self, inputs: Inputs) {
+        for (n, key) in self.keys.iter_mut().enumerate() {
+            *key = inputs.keys >> n & 1 != 0;
+        }
+        self.dt = inputs.dt;
+        self.st = inputs.st;
+        self.vblank = inputs.vblank;
+    }
+
+    // Go back to the state before an earlier step of the history, executing the instructions since the
+    // checkpoint before it again. The hook, tracer, profiler and access counts don't see them a second time.
+    fn travel(&mut self, step: u64) -> bool {
+        let (state, rng, inputs) = match self.history.as_mut().and_then(|history| history.rewind(step)) {
+            Some(rewound) => rewound,
+            None => return false,
+        };
+        let history = self.history.take();
+        let hook = self.hook.take();
+        let tracer = self.tracer.take();
+        let profiler = self.profiler.take();
+        let accesses = self.accesses.take();
+
+        self.load_state(&state);
+        self.rng = rng;
+        if let Some((last, executed)) = inputs.split_last() {
+            for &inputs in executed {
+                self.set_inputs(inputs);
+                self.tick();
+            }
+            self.set_inputs(*last);
         }
+
+        self.history = history;
+        self.hook = hook;
+        self.tracer = tracer;
+        self.profiler = profiler;
+        self.accesses = accesses;
+        true
+    }
+
+    // Undo the last instruction. Returns false at the start of the history or without one.
+    pub fn step_back(&mut self) -> bool {
+        match self.history.as_ref().and_then(|history| history.position().checked_sub(1)) {
+            Some(step) => self.travel(step),
+            None => false,
+        }
+    }
+
+    // Run backwards to the last breakpoint before the current instruction, or to the start of the history.
+    // Returns false if there was nothing to go back to.
+    pub fn run_back(&mut self) -> bool {
+        let step = match &self.history {
+            Some(history) => history.previous(|pc| self.breakpoints.contains(&pc)).unwrap_or_else(|| history.start()),
+            None => return false,
+        };
+        self.travel(step)
+    }
+
+    // The last instruction in the history that wrote to a register or memory address.
+    pub fn last_write(&self, location: Location) -> Option<Write> {
+        self.history.as_ref()?.last_write(location, self.quirks)
+    }
+
+    // Start or stop counting memory accesses, stopping discards the counts.
+    pub fn count_accesses(&mut self, enabled: bool) {
+        self.accesses = if enabled { Some(self.accesses.take().unwrap_or_default()) } else { None };
+    }
+
+    pub fn access_counts(&self) -> Option<&AccessCounts> {
+        self.accesses.as_ref()
+    }
+
+    // Whether execution is blocked on FX0A, and what it waits for.
+    pub fn key_wait(&self) -> Option<KeyWait> {
+        self.key_wait
+    }
+
+    // Whether the next instruction is a DXYN that has to wait for the next frame.
+    // Further ticks in the current frame won't do anything.
+    pub fn waiting_for_vblank(&self) -> bool {
+        self.quirks.display_wait && !self.vblank && self.memory[self.pc as usize] >> 4 == 0xD
+    }
+
+    // Whether the buzzer should currently sound.
+    pub fn sound_active(&self) -> bool {
+        self.st > 0
+    }
+
+    // Serialize the complete machine state into STATE_SIZE bytes.
+    // Quirks are configuration and therefore not part of the state.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = Vec::with_capacity(STATE_SIZE);
+        state.extend_from_slice(&self.pc.to_be_bytes());
+        state.extend_from_slice(&self.i.to_be_bytes());
+        state.extend_from_slice(&self.v);
+        state.extend_from_slice(&self.memory);
+        for address in self.stack.iter() {
+            state.extend_from_slice(&address.to_be_bytes());
+        }
+        state.push(self.sp as u8);
+        state.push(self.dt);
+        state.push(self.st);
+        state.extend(self.keys.iter().map(|&key| key as u8));
+        state.extend(self.framebuffer.iter().map(|&pixel| pixel as u8));
+        state.push(self.should_draw as u8);
+        state.push(match self.key_wait {
+            None => 0,
+            Some(KeyWait::Press) => 1,
+            Some(KeyWait::Release(key)) => 0x10 | key,
+        });
+        state.push(self.vblank as u8);
+        state.extend_from_slice(&self.cycles.to_be_bytes());
+        state
+    }
+
+    // Restore a state created by Chip8::save_state.
+    // Returns false and leaves the machine untouched if the data is malformed, including addresses the
+    // interpreter couldn't execute or read from.
+    pub fn load_state(&mut self, state: &[u8]) -> bool {
+        if state.len() != STATE_SIZE || state[4 + 16 + 4096 + 32] as usize > self.stack.len() {
+            return false;
+        }
+        let address = |offset: usize| u16::from_be_bytes([state[offset], state[offset + 1]]);
+        let mut stack = (0..state[4 + 16 + 4096 + 32] as usize).map(|n| address(4 + 16 + 4096 + 2 * n));
+        if address(0) >= PC_LIMIT || address(2) >= 4096 || stack.any(|pc| pc >= PC_LIMIT) {
+            return false;
+        }
+
+        self.changed();
+        let mut bytes = state.iter().copied();
+        let mut next = || bytes.next().unwrap();
+
+        self.pc = u16::from_be_bytes([next(), next()]);
+        self.i = u16::from_be_bytes([next(), next()]);
+        self.v.iter_mut().for_each(|r| *r = next());
+        self.memory.iter_mut().for_each(|byte| *byte = next());
+        self.stack.iter_mut().for_each(|address| *address = u16::from_be_bytes([next(), next()]));
+        self.sp = next() as usize;
+        self.dt = next();
+        self.st = next();
+        self.keys.iter_mut().for_each(|key| *key = next() != 0);
+        self.framebuffer.iter_mut().for_each(|pixel| *pixel = next() != 0);
+        self.should_draw = next() != 0;
+        self.key_wait = match next() {
+            0 => None,
+            1 => Some(KeyWait::Press),
+            key => Some(KeyWait::Release(key & 0xF)),
+        };
+        self.vblank = next() != 0;
+        self.cycles = i32::from_be_bytes([next(), next(), next(), next()]);
+        true
+    }
+
+    // The random number generator isn't part of save_state, the history keeps it next to the checkpoints.
+    // Frontends that replay frames after loading a state, like libretro's rewind, save it as well.
+    pub fn save_rng(&self) -> Vec<u8> {
+        self.rng.save()
+    }
+
+    // Restore a generator saved by Chip8::save_rng, returns false if the data is malformed.
+    pub fn load_rng(&mut self, state: &[u8]) -> bool {
+        if state.len() != RNG_STATE_SIZE {
+            return false;
+        }
+        self.changed();
+        self.rng = Random::load(state);
+        true
+    }
+
+    // Reset the CPU but keep the memory, including the loaded rom.
+    pub fn reset(&mut self) {
+        self.changed();
+        self.pc = 0x200;
+        self.i = 0;
+        self.v = [0; 16];
+        self.stack = [0; 16];
+        self.sp = 0;
+        self.dt = 0;
+        self.st = 0;
+        self.framebuffer = [false; WIDTH * HEIGHT];
+        self.should_draw = false;
+        self.key_wait = None;
+        self.vblank = false;
+        self.cycles = 0;
+    }
+
+    // Decrement the delay and sound timers, which should happen at 60 Hz.
+    // This also marks the start of a new frame for the display wait.
+    pub fn tick_timers(&mut self) {
+        if self.dt > 0 { self.dt -= 1 };
+        if self.st > 0 { self.st -= 1 };
+        self.vblank = true;
+    }
+
+    // Emulate one 60 Hz frame, including the timers.
+    // Returns the number of executed instructions and whether any of them drew to the display.
+    // Like the display wait, reaching a breakpoint leaves the rest of the frame idle.
+    pub fn run_frame(&mut self, timing: Timing) -> (u64, bool) {
+        let mut executed = 0;
+        let mut drawn = false;
+        self.run_hook(Hook::begin_frame);
+
+        match timing {
+            Timing::Instructions(count) => {
+                // With the display wait the rest of the frame is idle after a sprite was drawn.
+                while executed < count && !self.waiting_for_vblank() && !self.at_breakpoint() {
+                    self.tick();
+                    drawn |= self.should_draw;
+                    executed += 1;
+                }
+            }
+            Timing::Vip => {
+                self.cycles += VIP_CYCLES_PER_FRAME - VIP_DISPLAY_CYCLES;
+                while self.cycles > 0 && !self.waiting_for_vblank() && !self.at_breakpoint() {
+                    let cycles = self.vip_cycles(self.opcode());
+                    self.cycles -= cycles;
+                    self.tick();
+                    if let Some(tracer) = &mut self.tracer {
+                        tracer.charge(cycles as u64);
+                    }
+                    drawn |= self.should_draw;
+                    executed += 1;
+                }
+                // Cycles spent idling until the interrupt can't be used later on.
+                self.cycles = self.cycles.min(0);
+            }
+        }
+
+        self.tick_timers();
+        self.run_hook(Hook::end_frame);
+        (executed, drawn)
+    }
+
+    // The hook needs the whole machine, so it can't stay part of it meanwhile.
+    fn run_hook(&mut self, run: impl FnOnce(&mut dyn Hook, &mut Chip8)) {
+        if let Some(mut hook) = self.hook.take() {
+            run(hook.as_mut(), self);
+            self.hook = Some(hook);
+        }
+    }
+
+    fn opcode(&self) -> u16 {
+        (self.memory[self.pc as usize] as u16) << 8
+            | (self.memory[(self.pc + 1) as usize] as u16)
     }
 
     pub fn tick(&mut self) {
-        let opcode =
-            (self.memory[self.pc as usize] as u16) << 8
-            | (self.memory[(self.pc + 1) as usize] as u16);
+        if self.hook.as_ref().is_some_and(|hook| hook.watches(self.pc)) {
+            self.run_hook(Hook::reached);
+        }
+        let opcode = self.opcode();
+        let before = self.registers();
+        if let Some(tracer) = &mut self.tracer {
+            tracer.begin(before, opcode);
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(self.pc, opcode, &self.stack[..self.sp], &self.memory);
+        }
+        if let Some(accesses) = &mut self.accesses {
+            AccessCounts::add(&mut accesses.fetches, self.pc as usize, 2);
+        }
+        if self.history.is_some() {
+            self.record(opcode);
+        }
+
         self.execute(opcode);
+
+        if let Some(history) = &mut self.history {
+            history.end(self.pc);
+        }
+
+        let after = self.registers();
+        if let Some(tracer) = &mut self.tracer {
+            tracer.end(after);
+        }
+    }
+
+    // Add the instruction about to execute to the history, after a checkpoint if one is due.
+    fn record(&mut self, opcode: u16) {
+        let checkpoint = match &self.history {
+            Some(history) if history.needs_checkpoint() => Some((self.save_state(), self.rng.clone())),
+            _ => None,
+        };
+        let inputs = self.inputs();
+        if let Some(history) = &mut self.history {
+            if let Some((state, rng)) = checkpoint {
+                history.checkpoint(state, rng);
+            }
+            history.begin(self.pc, opcode, self.i, inputs);
+        }
+    }
+
+    // Approximate machine cycles the VIP interpreter needs for an instruction in the current state.
+    fn vip_cycles(&self, opcode: u16) -> i32 {
+        let x = ((opcode & 0x0F00) >> 8) as usize;
+        let y = ((opcode & 0x00F0) >> 4) as usize;
+        let vx = self.v[x];
+        let vy = self.v[y];
+        let nn = (opcode & 0x00FF) as u8;
+        let n = (opcode & 0x000F) as i32;
+        // skipping an instruction takes a little longer
+        let skip = |condition: bool| if condition { 4 } else { 0 };
+
+        let cycles = match Chip8::decode(opcode) {
+            // clearing loops over all 256 bytes of display memory
+            (0x0, 0x0, 0xE, 0x0) => 1536,
+            (0x0, 0x0, 0xE, 0xE) => 10,
+            (0x0, _, _, _) => 40,
+            (0x1, _, _, _) => 12,
+            (0x2, _, _, _) => 26,
+            (0x3, _, _, _) => 10 + skip(vx == nn),
+            (0x4, _, _, _) => 10 + skip(vx != nn),
+            (0x5, _, _, _) => 14 + skip(vx == vy),
+            (0x6, _, _, _) => 6,
+            (0x7, _, _, _) => 10,
+            (0x8, _, _, _) => 44,
+            (0x9, _, _, _) => 14 + skip(vx != vy),
+            (0xA, _, _, _) => 12,
+            (0xB, _, _, _) => 22,
+            (0xC, _, _, _) => 36,
+            // sprites that aren't aligned to a byte of display memory have to be shifted and span two bytes
+            (0xD, _, _, _) => 26 + n * if vx & 7 == 0 { 34 } else { 68 },
+            (0xE, _, 0x9, 0xE) => 14 + skip(self.keys[vx as usize & 0xF]),
+            (0xE, _, 0xA, 0x1) => 14 + skip(!self.keys[vx as usize & 0xF]),
+            (0xF, _, 0x1, 0xE) => 12,
+            (0xF, _, 0x2, 0x9) => 16,
+            // the digits are found by repeated subtraction
+            (0xF, _, 0x3, 0x3) => 64 + 8 * (vx / 100 + vx / 10 % 10 + vx % 10) as i32,
+            (0xF, _, 0x5, 0x5) | (0xF, _, 0x6, 0x5) => 14 + 14 * (x as i32 + 1),
+            _ => 10,
+        };
+        VIP_FETCH_CYCLES + cycles
     }
 
     fn execute(&mut self, opcode: u16) {
@@ -89,9 +706,7 @@ impl Chip8 {
         self.pc += 2;
 
         self.should_draw = false;
-
-        if self.dt > 0 { self.dt -= 1 };
-        if self.st > 0 { self.st -= 1 };
+        let vblank = std::mem::replace(&mut self.vblank, false);
 
         let nibbles = Chip8::decode(opcode);
 
@@ -119,7 +734,7 @@ impl Chip8 {
 
             // 2NNN Execute subroutine starting at address NNN
             (0x2, _, _, _) => {
-                self.stack[self.sp as usize] = self.pc;
+                self.stack[self.sp] = self.pc;
                 self.sp += 1;
                 self.pc = nnn;
             }
@@ -173,11 +788,12 @@ impl Chip8 {
             },
 
             // 8XY6 Store the value of register VY shifted right one bit in register VX
-            // Set register VF to the least significant bit prior to the shift
+            // Set register VF to the least significant bit of VY prior to the shift
+            // VX is shifted in place with the shift quirk
             (0x8, _, _, 0x6) => {
-                let lsb = vx & 0x1;
-                self.v[x] = vy >> 1;
-                self.v[0xF] = lsb;
+                let source = if self.quirks.shift { vx } else { vy };
+                self.v[x] = source >> 1;
+                self.v[0xF] = source & 0x1;
             }
 
             // 8XY7 Set register VX to the value of VY minus VX
@@ -190,11 +806,12 @@ impl Chip8 {
             }
 
             // 8XYE Store the value of register VY shifted left one bit in register VX
-            // Set register VF to the most significant bit prior to the shift
+            // Set register VF to the most significant bit of VY prior to the shift
+            // VX is shifted in place with the shift quirk
             (0x8, _, _, 0xE) => {
-                let msb = (vx & 0x80) >> 7;
-                self.v[x] = vy << 1;
-                self.v[0xF] = msb;
+                let source = if self.quirks.shift { vx } else { vy };
+                self.v[x] = source << 1;
+                self.v[0xF] = source >> 7;
             }
 
             // 9XY0 Skip the following instruction if the value of register VX is not equal to the value of register VY
@@ -204,29 +821,51 @@ impl Chip8 {
             (0xA, _, _, _) => self.i = nnn,
 
             // BNNN Jump to address NNN + V0
-            (0xB, _, _, _) => self.pc = nnn + self.v[0] as u16,
+            // BXNN Jump to address XNN + VX (jump quirk)
+            (0xB, _, _, _) => {
+                let offset = if self.quirks.jump { vx } else { self.v[0] };
+                self.pc = nnn + offset as u16;
+            }
 
             // CXNN Set VX to a random number with a mask of NN
             (0xC, _, _, _) => {
-                let mut rng = rand::thread_rng();
-                let rn: u8 = rng.gen();
+                let rn = self.rng.byte();
                 self.v[x] = rn & nn;
             }
 
             // DXYN Draw a sprite at position VX, VY with N bytes of sprite data starting at the address stored in I
             // Set VF to 01 if any set pixels are changed to unset, and 00 otherwise
+            // Only the position wraps, the sprite itself is clipped at the edges (unless the wrap quirk is set)
             (0xD, _, _, _) => {
+                // Stall until the next frame starts, like FX0A does for keys
+                if self.quirks.display_wait && !vblank {
+                    self.pc -= 2;
+                    return;
+                }
+
                 self.v[0xF] = 0; // Never forget :(
                
                 let range = (self.i as usize)..(self.i + n as u16) as usize;
+                if let Some(accesses) = &mut self.accesses {
+                    AccessCounts::add(&mut accesses.reads, range.start, range.len());
+                }
                 let sprite_data: &[u8] = &self.memory[range];
 
+                let left = vx as usize % WIDTH;
+                let top = vy as usize % HEIGHT;
+
                 for (i, current_byte) in sprite_data.iter().enumerate() {
+                    if top + i >= HEIGHT && !self.quirks.wrap {
+                        break;
+                    }
                     for j in 0..8 {
+                        if left + j >= WIDTH && !self.quirks.wrap {
+                            break;
+                        }
                         let current_bit = current_byte >> (7 - j) & 0x01;
                         if current_bit != 0 {
-                            let x = (vx as usize + j) % WIDTH;
-                            let y = (vy as usize + i) % HEIGHT;
+                            let x = (left + j) % WIDTH;
+                            let y = (top + i) % HEIGHT;
 
                             let index = y * WIDTH + x;
 
@@ -252,14 +891,22 @@ impl Chip8 {
             (0xF, _, 0x0, 0x7) => self.v[x] = self.dt,
 
             // FX0A Wait for a keypress and store the result in register VX
+            // The key has to be released again before execution continues
             (0xF, _, 0x0, 0xA) => {
                 // https://github.com/ColinEberhardt/wasm-rust-chip8
                 // I love the simplicity of just subtracting from the program counter.
                 self.pc -= 2;
-                for (key_value, key) in self.keys.iter().enumerate() {
-                    if *key {
-                        self.v[x] = key_value as u8;
-                        self.pc += 2;
+                match self.key_wait {
+                    None | Some(KeyWait::Press) => {
+                        let pressed = self.keys.iter().position(|&key| key);
+                        self.key_wait = Some(pressed.map_or(KeyWait::Press, |key| KeyWait::Release(key as u8)));
+                    }
+                    Some(KeyWait::Release(key)) => {
+                        if !self.keys[key as usize] {
+                            self.v[x] = key;
+                            self.key_wait = None;
+                            self.pc += 2;
+                        }
                     }
                 }
             },
@@ -285,24 +932,33 @@ impl Chip8 {
                 self.memory[self.i as usize] = vx / 100;
                 self.memory[self.i as usize + 1] = (vx / 10) % 10;
                 self.memory[self.i as usize + 2] = vx % 10;
+                if let Some(accesses) = &mut self.accesses {
+                    AccessCounts::add(&mut accesses.writes, self.i as usize, 3);
+                }
             }
 
             // FX55 Store the values of registers V0 to VX inclusive in memory starting at address I
-            // I is set to I + X + 1 after operation
+            // I is set to I + X + 1 after operation, unless the load_store quirk is enabled
             (0xF, _, 0x5, 0x5) => {
                 for r in 0..=x {
-                    self.memory[(self.i + r as u16) as usize] = self.v[r] as u8;
+                    self.memory[(self.i + r as u16) as usize] = self.v[r];
                 }
-                self.i += x as u16 + 1;
+                if let Some(accesses) = &mut self.accesses {
+                    AccessCounts::add(&mut accesses.writes, self.i as usize, x + 1);
+                }
+                if !self.quirks.load_store { self.i += x as u16 + 1 };
             }
 
             // FX65 Fill registers V0 to VX inclusive with the values stored in memory starting at address I
-            // I is set to I + X + 1 after operation
+            // I is set to I + X + 1 after operation, unless the load_store quirk is enabled
             (0xF, _, 0x6, 0x5) => {
                 for r in 0..=x {
                     self.v[r] = self.memory[(self.i + r as u16) as usize];
                 }
-                self.i += x as u16 + 1;
+                if let Some(accesses) = &mut self.accesses {
+                    AccessCounts::add(&mut accesses.reads, self.i as usize, x + 1);
+                }
+                if !self.quirks.load_store { self.i += x as u16 + 1 };
             }
 
             // Gotta catch 'em all!
@@ -349,7 +1005,7 @@ mod test {
 
         c.execute(0x00E0);
         for pixel in c.framebuffer.iter() {
-            assert_eq!(false, *pixel);
+            assert!(!*pixel);
         }
     }
 
@@ -505,13 +1161,14 @@ mod test {
         c.execute(0x8016);
         assert_eq!(127, c.v[0]);
         assert_eq!(255, c.v[1]);
-        assert_eq!(0x00, c.v[0xF]);
+        assert_eq!(0x01, c.v[0xF]);
 
+        // The flag is the bit shifted out of VY
         c.v[0] = 0x03;
         c.v[1] = 0x02;
         c.execute(0x8016);
         assert_eq!(0x01, c.v[0]);
-        assert_eq!(0x01, c.v[0xF]);
+        assert_eq!(0x00, c.v[0xF]);
     }
 
     #[test]
@@ -542,6 +1199,13 @@ mod test {
         c.v[1] = 64;
         c.execute(0x801E);
         assert_eq!(128, c.v[0]);
+        assert_eq!(0x00, c.v[0xF]);
+
+        // The flag is the bit shifted out of VY
+        c.v[0] = 0x01;
+        c.v[1] = 0x81;
+        c.execute(0x801E);
+        assert_eq!(0x02, c.v[0]);
         assert_eq!(0x01, c.v[0xF]);
     }
 
@@ -586,7 +1250,7 @@ mod test {
         c.execute(0xc000); // Should always produce zero.
         assert_eq!(0x00, c.v[0]);
         
-        c.execute(0xc0FF);
+        c.execute(0xC0FF);
         let valid_range = 0..255;
         assert!(valid_range.contains(&c.v[0]));
     }
@@ -594,7 +1258,21 @@ mod test {
 // Set VF to 01 if any set pixels are changed to unset, and 00 otherwise
     #[test]
     fn instruction_dxyn() {
-        assert_eq!(1, 2);
+        let mut c = Chip8::new_with_state();
+
+        // Draw the "0" font sprite at (2, 3)
+        c.v[0] = 2;
+        c.v[1] = 3;
+        c.execute(0xD015);
+        assert!(c.framebuffer[3 * WIDTH + 2]);
+        assert!(!c.framebuffer[4 * WIDTH + 3]);
+        assert_eq!(0x00, c.v[0xF]);
+        assert!(c.should_draw);
+
+        // Drawing it again erases it and reports a collision
+        c.execute(0xD015);
+        assert!(c.framebuffer.iter().all(|pixel| !pixel));
+        assert_eq!(0x01, c.v[0xF]);
     }
 
     #[test]
@@ -627,7 +1305,40 @@ mod test {
 
         c.dt = 0xFF;
         c.execute(0xF007);
-        assert_eq!(0xFF - 1, c.v[0]); // Already ticked once
+        assert_eq!(0xFF, c.v[0]);
+        c.tick_timers();
+        c.execute(0xF107);
+        assert_eq!(0xFF - 1, c.v[1]);
+    }
+
+    #[test]
+    fn timers() {
+        let mut c = Chip8::new_with_state();
+
+        c.dt = 1;
+        c.st = 2;
+        c.tick_timers();
+        assert_eq!((0, 1), (c.dt, c.st));
+        assert!(c.sound_active());
+        c.tick_timers();
+        c.tick_timers();
+        assert_eq!((0, 0), (c.dt, c.st));
+        assert!(!c.sound_active());
+    }
+
+    #[test]
+    fn soft_reset() {
+        let mut c = Chip8::new_with_state();
+        c.load_bytes(&[0x60, 0x12, 0x22, 0x08]);
+        c.tick();
+        c.tick();
+        c.memory[0x300] = 0xAB;
+
+        c.reset();
+        assert_eq!(0x200, c.pc);
+        assert_eq!((0, 0), (c.v[0], c.sp));
+        assert_eq!(0x60, c.memory[0x200]);
+        assert_eq!(0xAB, c.memory[0x300]);
     }
 // FX0A 	Wait for a keypress and store the result in register VX
     #[test]
@@ -635,7 +1346,77 @@ mod test {
         let mut c = Chip8::new_with_state();
         c.keys[0xF] = true;
         c.execute(0xF30A);
+        assert_eq!(Some(KeyWait::Release(0xF)), c.key_wait());
+        assert_eq!(0x200, c.pc);
+
+        c.keys[0xF] = false;
+        c.execute(0xF30A);
         assert_eq!(0xF, c.v[0x3]);
+        assert_eq!(None, c.key_wait());
+        assert_eq!(0x202, c.pc);
+    }
+
+    #[test]
+    fn instruction_fx0a_held_key() {
+        let mut c = Chip8::new_with_state();
+        // Two prompts in a row
+        c.load_bytes(&[0xF1, 0x0A, 0xF2, 0x0A]);
+
+        c.tick();
+        assert_eq!(Some(KeyWait::Press), c.key_wait());
+
+        // Holding a key only completes the first prompt once it is released
+        c.keys[0x5] = true;
+        for _ in 0..10 {
+            c.tick();
+        }
+        assert_eq!(0x200, c.pc);
+        assert_eq!(Some(KeyWait::Release(0x5)), c.key_wait());
+
+        c.keys[0x5] = false;
+        c.tick();
+        assert_eq!(0x5, c.v[1]);
+        assert_eq!(0x202, c.pc);
+
+        // The second prompt needs a new key press
+        for _ in 0..10 {
+            c.tick();
+        }
+        assert_eq!(0x202, c.pc);
+        assert_eq!(Some(KeyWait::Press), c.key_wait());
+
+        c.keys[0xA] = true;
+        c.tick();
+        c.keys[0xA] = false;
+        c.tick();
+        assert_eq!(0xA, c.v[2]);
+        assert_eq!(0x204, c.pc);
+    }
+
+    #[test]
+    fn instruction_fx0a_timers() {
+        let mut c = Chip8::new_with_state();
+        c.load_bytes(&[0xF0, 0x0A]);
+        c.dt = 2;
+        c.st = 2;
+
+        c.tick();
+        c.tick_timers();
+        c.tick();
+        c.tick_timers();
+        assert_eq!((0, 0), (c.dt, c.st));
+        assert!(c.key_wait().is_some());
+    }
+
+    #[test]
+    fn key_wait_state() {
+        let mut c = Chip8::new_with_state();
+        c.keys[0x7] = true;
+        c.execute(0xF00A);
+
+        let mut restored = Chip8::new_with_state();
+        assert!(restored.load_state(&c.save_state()));
+        assert_eq!(Some(KeyWait::Release(0x7)), restored.key_wait());
     }
     #[test]
     fn instruction_fx15() {
@@ -706,7 +1487,7 @@ mod test {
             c.v[i] = 0xEE;
         }
 
-        c.execute(0xfF55);
+        c.execute(0xFF55);
 
         for i in 0..=0xF {
             assert_eq!(0xEE, c.memory[(base_address + i) as usize]);
@@ -715,6 +1496,227 @@ mod test {
         assert_eq!(base_address + 0xF + 1, c.i);
     }
 
+    #[test]
+    fn quirk_shift() {
+        let mut c = Chip8::new_with_state();
+        c.quirks.shift = true;
+
+        c.v[0] = 0x03;
+        c.v[1] = 0xF0;
+        c.execute(0x8016);
+        assert_eq!(0x01, c.v[0]);
+        assert_eq!(0x01, c.v[0xF]);
+
+        c.execute(0x801E);
+        assert_eq!(0x02, c.v[0]);
+        assert_eq!(0x00, c.v[0xF]);
+    }
+
+    #[test]
+    fn quirk_jump() {
+        let mut c = Chip8::new_with_state();
+        c.quirks.jump = true;
+
+        c.v[0] = 0x01;
+        c.v[2] = 0x02;
+        c.execute(0xB200);
+        assert_eq!(0x202, c.pc);
+    }
+
+    #[test]
+    fn quirk_load_store() {
+        let mut c = Chip8::new_with_state();
+        c.quirks.load_store = true;
+
+        c.i = 0x300;
+        c.execute(0xF355);
+        assert_eq!(0x300, c.i);
+        c.execute(0xF365);
+        assert_eq!(0x300, c.i);
+    }
+
+    // Sprites drawn across the bottom right corner
+    #[test]
+    fn dxyn_clipping() {
+        let mut c = Chip8::new_with_state();
+        // A filled 8x8 square
+        c.memory[0x300..0x308].copy_from_slice(&[0xFF; 8]);
+        c.i = 0x300;
+        c.v[0] = 60;
+        c.v[1] = 28;
+        c.execute(0xD018);
+        assert_eq!(16, c.framebuffer.iter().filter(|&&pixel| pixel).count());
+        assert!(c.framebuffer[28 * WIDTH + 60]);
+        assert!(c.framebuffer[31 * WIDTH + 63]);
+        assert!(!c.framebuffer[0]);
+        assert_eq!(0x00, c.v[0xF]);
+
+        // Erasing the visible part collides, the pixel where the sprite would wrap to stays
+        c.framebuffer[0] = true;
+        c.execute(0xD018);
+        assert!(c.framebuffer[0]);
+        assert_eq!(1, c.framebuffer.iter().filter(|&&pixel| pixel).count());
+        assert_eq!(0x01, c.v[0xF]);
+
+        // Clipped pixels never collide, only the parts that would wrap around overlap lit pixels
+        c.framebuffer = [false; WIDTH * HEIGHT];
+        c.framebuffer[28 * WIDTH] = true;
+        c.framebuffer[60] = true;
+        c.framebuffer[0] = true;
+        c.execute(0xD018);
+        assert!(c.framebuffer[28 * WIDTH] && c.framebuffer[60] && c.framebuffer[0]);
+        assert_eq!(3 + 16, c.framebuffer.iter().filter(|&&pixel| pixel).count());
+        assert_eq!(0x00, c.v[0xF]);
+
+        // The position itself still wraps
+        c.framebuffer = [false; WIDTH * HEIGHT];
+        c.v[0] = 64 + 2;
+        c.v[1] = 32 + 3;
+        c.execute(0xD011);
+        assert!(c.framebuffer[3 * WIDTH + 2]);
+        assert!(c.framebuffer[3 * WIDTH + 9]);
+        assert_eq!(0x00, c.v[0xF]);
+    }
+
+    #[test]
+    fn quirk_wrap() {
+        let mut c = Chip8::new_with_state();
+        c.quirks.wrap = true;
+        c.memory[0x300..0x308].copy_from_slice(&[0xFF; 8]);
+        c.i = 0x300;
+        c.v[0] = 60;
+        c.v[1] = 28;
+        c.execute(0xD018);
+        assert_eq!(64, c.framebuffer.iter().filter(|&&pixel| pixel).count());
+        assert!(c.framebuffer[0]);
+        assert!(c.framebuffer[3 * WIDTH + 3]);
+        assert!(!c.framebuffer[4 * WIDTH + 4]);
+        assert_eq!(0x00, c.v[0xF]);
+
+        // Wrapped pixels collide like any other
+        c.framebuffer = [false; WIDTH * HEIGHT];
+        c.framebuffer[3 * WIDTH + 3] = true;
+        c.execute(0xD018);
+        assert!(!c.framebuffer[3 * WIDTH + 3]);
+        assert_eq!(0x01, c.v[0xF]);
+
+        // A collision anywhere in the sprite counts, even if other pixels don't collide
+        c.execute(0xD018);
+        assert_eq!(0x01, c.v[0xF]);
+        assert_eq!(1, c.framebuffer.iter().filter(|&&pixel| pixel).count());
+    }
+
+    #[test]
+    fn quirk_profiles() {
+        assert_eq!(Some(Quirks::default()), Quirks::from_profile(Quirks::DEFAULT_PROFILE));
+        assert_eq!(Some(Quirks { display_wait: true, ..Quirks::default() }), Quirks::from_profile("vip"));
+        assert_eq!(Some(Quirks { wrap: true, ..Quirks::default() }), Quirks::from_profile("vip, wrap, no-display_wait"));
+        assert!(Quirks::from_profile("chip48").unwrap().shift);
+        assert_eq!(None, Quirks::from_profile("vip,warp"));
+        assert_eq!(None, Quirks::from_profile("wrap"));
+    }
+
+    #[test]
+    fn instruction_timing() {
+        let mut c = Chip8::new_with_state();
+        c.load_bytes(&[0x70, 0x01, 0x12, 0x00]);
+        assert_eq!((10, false), c.run_frame(Timing::Instructions(10)));
+        assert_eq!(5, c.v[0]);
+    }
+
+    #[test]
+    fn vip_timing() {
+        // 7001 and 1200 cost 50 and 52 cycles, the frame leaves 2614 of them
+        let mut c = Chip8::new_with_state();
+        c.load_bytes(&[0x70, 0x01, 0x12, 0x00]);
+        let (executed, _) = c.run_frame(Timing::Vip);
+        assert_eq!(52, executed);
+        // The last instruction ran over and is paid by the next frame
+        assert_eq!(2614 - 26 * 102, c.cycles);
+        assert_eq!(51, c.run_frame(Timing::Vip).0);
+
+        // Unaligned sprites take longer
+        let count = |vx: u8| {
+            let mut c = Chip8::new_with_state();
+            c.load_bytes(&[0x60, vx, 0xD0, 0x0F, 0x12, 0x02]);
+            c.run_frame(Timing::Vip).0
+        };
+        assert!(count(8) > count(9));
+
+        // The display wait ends the frame, and its remaining cycles are lost
+        let mut c = Chip8::new_with_state();
+        c.quirks.display_wait = true;
+        c.load_bytes(&[0x70, 0x01, 0xD0, 0x01, 0x12, 0x00]);
+        assert_eq!((1, false), c.run_frame(Timing::Vip));
+        assert_eq!(0, c.cycles);
+        let (_, drawn) = c.run_frame(Timing::Vip);
+        assert!(drawn);
+    }
+
+    #[test]
+    fn quirk_display_wait() {
+        let mut c = Chip8::new_with_state();
+        c.quirks.display_wait = true;
+        // Draw the same sprite twice
+        c.load_bytes(&[0xD0, 0x05, 0xD0, 0x05, 0x12, 0x04]);
+
+        assert!(c.waiting_for_vblank());
+        c.tick();
+        c.tick();
+        assert_eq!(0x200, c.pc);
+        assert!(!c.framebuffer[0]);
+
+        // One sprite per frame
+        c.tick_timers();
+        assert!(!c.waiting_for_vblank());
+        c.tick();
+        assert!(c.framebuffer[0]);
+        assert!(c.waiting_for_vblank());
+        c.tick();
+        assert_eq!(0x202, c.pc);
+
+        c.tick_timers();
+        c.tick();
+        assert!(!c.framebuffer[0]);
+        assert_eq!(0x204, c.pc);
+
+        // Other instructions don't wait
+        c.tick();
+        assert_eq!(0x204, c.pc);
+        assert!(!c.waiting_for_vblank());
+    }
+
+    #[test]
+    fn save_and_load_state() {
+        let mut c = Chip8::new_with_state();
+        c.load_bytes(&[0x60, 0x12, 0x22, 0x08]);
+        c.tick();
+        c.tick();
+        c.framebuffer[42] = true;
+
+        let state = c.save_state();
+        assert_eq!(STATE_SIZE, state.len());
+
+        let mut restored = Chip8::new_with_state();
+        assert!(restored.load_state(&state));
+        assert_eq!(0x208, restored.pc);
+        assert_eq!(0x12, restored.v[0]);
+        assert_eq!(1, restored.sp);
+        assert_eq!(0x204, restored.stack[0]);
+        assert!(restored.framebuffer[42]);
+        assert_eq!(state, restored.save_state());
+
+        assert!(!restored.load_state(&state[1..]));
+
+        // Addresses outside of the memory would panic later on
+        for (offset, address) in [(0, 0xFFE), (0, 0xFFFF), (2, 0x1000), (4 + 16 + 4096, 0xFFF)] {
+            let mut malformed = state.clone();
+            malformed[offset..offset + 2].copy_from_slice(&u16::to_be_bytes(address));
+            assert!(!restored.load_state(&malformed), "{:#X} at {}", address, offset);
+        }
+        assert_eq!(state, restored.save_state());
+    }
+
     #[test]
     fn instruction_fx65() {
         let mut c = Chip8::new_with_state();
@@ -727,7 +1729,7 @@ mod test {
             c.memory[(base_address + i) as usize] = 0xFF;
         }
 
-        c.execute(0xfF65);
+        c.execute(0xFF65);
 
         for i in 0..=0xF {
             assert_eq!(0xFF, c.v[i]);
@@ -735,4 +1737,109 @@ mod test {
 
         assert_eq!(base_address + 0xF + 1, c.i);
     }
+
+    #[test]
+    fn access_counts() {
+        let mut c = Chip8::new_with_state();
+        // LD I, 0x300; LD B, V0; DRW V0, V0, 2; LD V1, [I]
+        c.load_bytes(&[0xA3, 0x00, 0xF0, 0x33, 0xD0, 0x02, 0xF1, 0x65]);
+        c.tick();
+        assert_eq!(None, c.access_counts());
+
+        c.count_accesses(true);
+        for _ in 0..3 {
+            c.tick();
+        }
+        let accesses = c.access_counts().unwrap();
+        assert_eq!((0, 1, 1), (accesses.fetches[0x201], accesses.fetches[0x202], accesses.fetches[0x207]));
+        assert_eq!(&[1, 1, 1, 0], &accesses.writes[0x300..0x304]);
+        assert_eq!(&[2, 2, 0], &accesses.reads[0x300..0x303]);
+
+        c.count_accesses(false);
+        assert_eq!(None, c.access_counts());
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut c = Chip8::new_with_state();
+        // ADD V0, 1; JP 0x200
+        c.load_bytes(&[0x70, 0x01, 0x12, 0x00]);
+        c.breakpoints.insert(0x202);
+        assert_eq!(1, c.run_frame(Timing::Instructions(10)).0);
+        assert!(c.at_breakpoint());
+        assert_eq!(0, c.run_frame(Timing::Vip).0);
+
+        c.tick();
+        c.breakpoints.clear();
+        assert_eq!(10, c.run_frame(Timing::Instructions(10)).0);
+    }
+
+    #[test]
+    fn time_travel() {
+        let mut c = Chip8::new_with_state();
+        // LD I, 0x300; RND V0, 0xFF; ADD V1, 1; LD [I], V1; JP 0x200
+        c.load_bytes(&[0xA3, 0x00, 0xC0, 0xFF, 0x71, 0x01, 0xF1, 0x55, 0x12, 0x00]);
+        c.history = Some(History::new(3));
+
+        let mut states = Vec::new();
+        for n in 0..3500 {
+            if n % 10 == 0 {
+                c.tick_timers();
+                c.keys[n / 10 % 16] = !c.keys[n / 10 % 16];
+            }
+            states.push(c.save_state());
+            c.tick();
+        }
+
+        // Random numbers, keys and timers are the same when executing again
+        for expected in states.iter().rev().take(1200) {
+            assert!(c.step_back());
+            assert_eq!(*expected, c.save_state());
+        }
+
+        c.breakpoints.insert(0x204);
+        assert!(c.run_back());
+        assert_eq!(0x204, c.registers().pc);
+        assert_eq!(states[2297], c.save_state());
+
+        assert_eq!(0x204, c.last_write(Location::V(1)).unwrap().pc);
+        assert_eq!(0x206, c.last_write(Location::Memory(0x301)).unwrap().pc);
+        assert_eq!(None, c.last_write(Location::Memory(0x302)));
+
+        // Only three checkpoints are kept, the oldest one at step 1000
+        c.breakpoints.clear();
+        assert!(c.run_back());
+        assert_eq!(states[1000], c.save_state());
+        assert!(!c.step_back());
+    }
+
+    #[test]
+    fn load_rom_from_database() {
+        let mut chip8 = Chip8::new_with_state();
+        let entry = chip8.load_rom(&File::open("roms/games/Blinky [Hans Christian Egeberg, 1991].ch8").unwrap());
+        assert_eq!("Blinky", entry.unwrap().title);
+        assert_eq!(0x12, chip8.memory()[0x200]);
+
+        let mut chip8 = Chip8::new_with_state();
+        assert_eq!(None, chip8.load_rom(&File::open("roms/games/Tank.txt").unwrap()));
+    }
+
+    #[test]
+    fn time_travel_after_changes() {
+        let mut c = Chip8::new_with_state();
+        // ADD V0, 1; JP 0x200
+        c.load_bytes(&[0x70, 0x01, 0x12, 0x00]);
+        c.history = Some(History::new(10));
+        c.tick();
+        c.tick();
+
+        c.memory_mut()[0x200] = 0x71;
+        let changed = c.save_state();
+        c.tick();
+        assert!(c.step_back());
+        assert_eq!(changed, c.save_state());
+        assert!(c.step_back());
+        assert_eq!(0x202, c.registers().pc);
+        assert_eq!(0x70, c.memory()[0x200]);
+    }
 }
@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use minifb::Key;
+use serde::{Deserialize, Serialize};
+use crusty_8::chip8::{Quirks, Timing};
+use crusty_8::database::Entry;
+use crate::session::FRAME;
+use crate::filter;
+use crate::io::Scaling;
+use crate::palette::{self, Palette};
+
+// One set of settings as it appears in the config file.
+// Every value is optional, missing ones fall back to the level below.
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Profile {
+    pub layout: Option<String>,
+    pub keys: Option<Vec<String>>,
+    pub palette: Option<String>,
+    pub foreground: Option<String>,
+    pub background: Option<String>,
+    pub filter: Option<String>,
+    pub decay: Option<f32>,
+    pub update_rate: Option<u64>,
+    pub timing: Option<String>,
+    pub backend: Option<String>,
+    pub interpreter: Option<String>,
+    pub fast_forward: Option<u32>,
+    pub quirks: Option<String>,
+    pub scale: Option<usize>,
+    pub scaling: Option<String>,
+    pub grid: Option<bool>,
+    pub overlay: Option<bool>,
+    pub keypad: Option<bool>,
+}
+
+impl Profile {
+    // The settings the rom database recommends, the lowest layer above the defaults.
+    pub fn from_database(entry: &Entry) -> Profile {
+        Profile {
+            quirks: Some(entry.quirks().to_string()),
+            update_rate: entry.tick_rate.map(|tick_rate| (FRAME.as_micros() as u64 / tick_rate).max(1)),
+            ..Profile::default()
+        }
+    }
+}
+
+// Global settings with per-rom overrides in [rom.<sha1 of the rom>] tables.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct ConfigFile {
+    #[serde(flatten)]
+    global: Profile,
+    rom: HashMap<String, Profile>,
+}
+
+// The effective settings after merging defaults, config file and command line.
+pub struct Config {
+    pub rom_hash: String,
+    pub keys: [Key; 16],
+    // name of the palette preset the colours are based on
+    pub palette: String,
+    pub colours: Palette,
+    pub filter: filter::Mode,
+    // share of a pixel's brightness lost per frame with the phosphor filter
+    pub decay: f32,
+    pub update_rate: u64,
+    // either fixed, which uses the update rate, or vip
+    pub timing: String,
+    // either chip8 or vip, which runs the original interpreter
+    pub backend: String,
+    // path of the interpreter dump for the vip backend
+    pub interpreter: Option<String>,
+    pub fast_forward: u32,
+    pub quirks: String,
+    pub scale: usize,
+    pub scaling: Scaling,
+    // draw lines between the display pixels
+    pub grid: bool,
+    // show the status overlay from the start
+    pub overlay: bool,
+    // show the clickable keypad next to the display
+    pub keypad: bool,
+}
+
+impl Config {
+    // Layers are applied in order, later ones win.
+    pub fn resolve(rom_hash: String, layers: &[Profile]) -> Result<Config, String> {
+        let mut config = Config {
+            rom_hash,
+            keys: QWERTZ,
+            palette: String::from("classic"),
+            colours: palette::find("classic").unwrap(),
+            filter: filter::Mode::None,
+            decay: 0.3,
+            update_rate: 1660,
+            timing: String::from("fixed"),
+            backend: String::from("chip8"),
+            interpreter: None,
+            fast_forward: 4,
+            quirks: String::from(Quirks::DEFAULT_PROFILE),
+            scale: 16,
+            scaling: Scaling::Integer,
+            grid: false,
+            overlay: false,
+            keypad: false,
+        };
+
+        for layer in layers {
+            if let Some(layout) = &layer.layout {
+                config.keys = match layout.as_str() {
+                    "qwertz" => QWERTZ,
+                    "qwerty" => QWERTY,
+                    _ => return Err(format!("Unknown keyboard layout '{}', expected qwertz or qwerty", layout)),
+                };
+            }
+            if let Some(keys) = &layer.keys {
+                if keys.len() != 16 {
+                    return Err(format!("Expected 16 key bindings, got {}", keys.len()));
+                }
+                for (binding, name) in config.keys.iter_mut().zip(keys) {
+                    *binding = parse_key(name).ok_or_else(|| format!("Unknown key '{}'", name))?;
+                }
+            }
+            if let Some(name) = &layer.palette {
+                config.colours = palette::find(name)
+                    .ok_or_else(|| format!("Unknown palette '{}', expected one of {:?}", name, palette::names()))?;
+                config.palette = name.clone();
+            }
+            if let Some(colour) = &layer.background { config.colours[0] = parse_colour(colour)? };
+            if let Some(colour) = &layer.foreground { config.colours[1] = parse_colour(colour)? };
+            if let Some(name) = &layer.filter {
+                config.filter = filter::Mode::from_name(name)
+                    .ok_or_else(|| format!("Unknown filter '{}', expected one of {:?}", name, filter::Mode::NAMES))?;
+            }
+            if let Some(decay) = layer.decay {
+                if !(0.0..=1.0).contains(&decay) {
+                    return Err(format!("Decay {} is out of range, expected a value between 0 and 1", decay));
+                }
+                config.decay = decay;
+            }
+            if let Some(update_rate) = layer.update_rate {
+                if update_rate == 0 {
+                    return Err(String::from("The update rate has to be at least one microsecond"));
+                }
+                config.update_rate = update_rate;
+            }
+            if let Some(timing) = &layer.timing {
+                if timing != "fixed" && timing != "vip" {
+                    return Err(format!("Unknown timing '{}', expected fixed or vip", timing));
+                }
+                config.timing = timing.clone();
+            }
+            if let Some(backend) = &layer.backend {
+                if backend != "chip8" && backend != "vip" {
+                    return Err(format!("Unknown backend '{}', expected chip8 or vip", backend));
+                }
+                config.backend = backend.clone();
+            }
+            if let Some(interpreter) = &layer.interpreter { config.interpreter = Some(interpreter.clone()) };
+            if let Some(fast_forward) = layer.fast_forward { config.fast_forward = fast_forward };
+            if let Some(quirks) = &layer.quirks {
+                if Quirks::from_profile(quirks).is_none() {
+                    return Err(format!(
+                        "Unknown quirks '{}', expected one of {:?}, optionally followed by quirks from {:?} like 'vip,wrap'",
+                        quirks, Quirks::PROFILES, Quirks::NAMES,
+                    ));
+                }
+                config.quirks = quirks.clone();
+            }
+            if let Some(scale) = layer.scale {
+                if !(1..=64).contains(&scale) {
+                    return Err(format!("Unsupported scale {}, expected a value between 1 and 64", scale));
+                }
+                config.scale = scale;
+            }
+            if let Some(name) = &layer.scaling {
+                config.scaling = Scaling::from_name(name)
+                    .ok_or_else(|| format!("Unknown scaling '{}', expected one of {:?}", name, Scaling::NAMES))?;
+            }
+            if let Some(grid) = layer.grid { config.grid = grid };
+            if let Some(overlay) = layer.overlay { config.overlay = overlay };
+            if let Some(keypad) = layer.keypad { config.keypad = keypad };
+        }
+
+        Ok(config)
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        Quirks::from_profile(&self.quirks).unwrap()
+    }
+
+    pub fn timing(&self) -> Timing {
+        match self.timing.as_str() {
+            "vip" => Timing::Vip,
+            _ => Timing::Instructions((FRAME.as_micros() as u64 / self.update_rate).max(1)),
+        }
+    }
+
+    // The effective settings in config file syntax.
+    pub fn to_toml(&self) -> String {
+        let profile = Profile {
+            layout: None,
+            keys: Some(self.keys.iter().map(|key| format!("{:?}", key)).collect()),
+            palette: Some(self.palette.clone()),
+            foreground: Some(format!("#{:06X}", self.colours[1])),
+            background: Some(format!("#{:06X}", self.colours[0])),
+            filter: Some(self.filter.name().to_string()),
+            decay: Some(self.decay),
+            update_rate: Some(self.update_rate),
+            timing: Some(self.timing.clone()),
+            backend: Some(self.backend.clone()),
+            interpreter: self.interpreter.clone(),
+            fast_forward: Some(self.fast_forward),
+            quirks: Some(self.quirks.clone()),
+            scale: Some(self.scale),
+            scaling: Some(self.scaling.name().to_string()),
+            grid: Some(self.grid),
+            overlay: Some(self.overlay),
+            keypad: Some(self.keypad),
+        };
+        format!("# rom sha1: {}\n{}", self.rom_hash, toml::to_string(&profile).unwrap())
+    }
+}
+
+// $XDG_CONFIG_HOME/crusty-8/config.toml, falling back to ~/.config
+pub fn default_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(base.join("crusty-8").join("config.toml"))
+}
+
+// The cheats of a rom are kept in a directory next to the config file, one file per rom hash.
+pub fn cheats_path(config: &Path, rom_hash: &str) -> PathBuf {
+    config.with_file_name("cheats").join(format!("{}.txt", rom_hash))
+}
+
+// Read the global settings and the overrides for the given rom.
+// A missing file is not an error, it just doesn't change anything.
+pub fn load(path: &Path, rom_hash: &str) -> Result<Vec<Profile>, String> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Could not read {}: {}", path.display(), e)),
+    };
+    let mut file: ConfigFile = toml::from_str(&contents)
+        .map_err(|e| format!("Could not parse {}: {}", path.display(), e))?;
+
+    let mut layers = vec![file.global];
+    if let Some(overrides) = file.rom.remove(rom_hash) {
+        layers.push(overrides);
+    }
+    Ok(layers)
+}
+
+// Accepts "#RRGGBB" and "RRGGBB".
+fn parse_colour(colour: &str) -> Result<u32, String> {
+    let hex = colour.trim_start_matches('#');
+    match u32::from_str_radix(hex, 16) {
+        Ok(value) if hex.len() == 6 => Ok(value),
+        _ => Err(format!("Invalid colour '{}', expected #RRGGBB", colour)),
+    }
+}
+
+// Key names are the ones minifb uses, single digits are accepted as well.
+fn parse_key(name: &str) -> Option<Key> {
+    let name = if name.len() == 1 && name.chars().all(|c| c.is_ascii_digit()) {
+        format!("Key{}", name)
+    } else {
+        name.to_string()
+    };
+    KEYS.iter().copied().find(|key| format!("{:?}", key).eq_ignore_ascii_case(&name))
+}
+
+const QWERTZ: [Key; 16] = [
+    Key::Key1, Key::Key2, Key::Key3, Key::Key4,
+    Key::Q, Key::W, Key::E, Key::R,
+    Key::A, Key::S, Key::D, Key::F,
+    Key::Y, Key::X, Key::C, Key::V,
+];
+
+const QWERTY: [Key; 16] = [
+    Key::Key1, Key::Key2, Key::Key3, Key::Key4,
+    Key::Q, Key::W, Key::E, Key::R,
+    Key::A, Key::S, Key::D, Key::F,
+    Key::Z, Key::X, Key::C, Key::V,
+];
+
+// Every key that can be bound.
+const KEYS: [Key; 106] = [
+    Key::Key0, Key::Key1, Key::Key2, Key::Key3, Key::Key4,
+    Key::Key5, Key::Key6, Key::Key7, Key::Key8, Key::Key9,
+    Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I,
+    Key::J, Key::K, Key::L, Key::M, Key::N, Key::O, Key::P, Key::Q, Key::R,
+    Key::S, Key::T, Key::U, Key::V, Key::W, Key::X, Key::Y, Key::Z,
+    Key::F1, Key::F2, Key::F3, Key::F4, Key::F5, Key::F6, Key::F7, Key::F8,
+    Key::F9, Key::F10, Key::F11, Key::F12, Key::F13, Key::F14, Key::F15,
+    Key::Down, Key::Left, Key::Right, Key::Up,
+    Key::Apostrophe, Key::Backquote, Key::Backslash, Key::Comma, Key::Equal,
+    Key::LeftBracket, Key::Minus, Key::Period, Key::RightBracket, Key::Semicolon, Key::Slash,
+    Key::Backspace, Key::Delete, Key::End, Key::Enter, Key::Escape, Key::Home,
+    Key::Insert, Key::Menu, Key::PageDown, Key::PageUp, Key::Pause, Key::Space, Key::Tab,
+    Key::NumLock, Key::CapsLock, Key::ScrollLock,
+    Key::LeftShift, Key::RightShift, Key::LeftCtrl, Key::RightCtrl,
+    Key::NumPad0, Key::NumPad1, Key::NumPad2, Key::NumPad3, Key::NumPad4,
+    Key::NumPad5, Key::NumPad6, Key::NumPad7, Key::NumPad8, Key::NumPad9,
+    Key::NumPadDot, Key::NumPadSlash, Key::NumPadAsterisk, Key::NumPadMinus,
+    Key::NumPadPlus, Key::NumPadEnter,
+    Key::LeftAlt, Key::RightAlt, Key::LeftSuper, Key::RightSuper,
+];
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn later_layers_win() {
+        let global = Profile { update_rate: Some(1000), quirks: Some(String::from("chip48")), ..Profile::default() };
+        let cli = Profile { update_rate: Some(500), ..Profile::default() };
+
+        let config = Config::resolve(String::new(), &[global, cli]).unwrap();
+        assert_eq!(500, config.update_rate);
+        assert_eq!("chip48", config.quirks);
+        assert_eq!(Timing::Instructions(33), config.timing());
+        assert_eq!(16, config.scale);
+    }
+
+    #[test]
+    fn keys_and_colours() {
+        let mut keys: Vec<String> = QWERTY.iter().map(|key| format!("{:?}", key)).collect();
+        keys[0] = String::from("0");
+        let profile = Profile {
+            layout: Some(String::from("qwerty")),
+            keys: Some(keys),
+            foreground: Some(String::from("#33FF66")),
+            ..Profile::default()
+        };
+
+        let config = Config::resolve(String::new(), std::slice::from_ref(&profile)).unwrap();
+        assert_eq!(Key::Key0, config.keys[0]);
+        assert_eq!(Key::Z, config.keys[0xC]);
+        assert_eq!(0x33_FF_66, config.colours[1]);
+        assert_eq!(0x00_00_00, config.colours[0]);
+
+        let amber = Profile { palette: Some(String::from("amber")), ..Profile::default() };
+        let config = Config::resolve(String::new(), &[profile, amber]).unwrap();
+        assert_eq!(palette::find("amber"), Some(config.colours));
+
+        let invalid = Profile { background: Some(String::from("#12")), ..Profile::default() };
+        assert!(Config::resolve(String::new(), &[invalid]).is_err());
+    }
+
+    #[test]
+    fn rom_overrides() {
+        let path = std::env::temp_dir().join("crusty-8-rom-overrides.toml");
+        std::fs::write(&path, "scale = 8\n[rom.abc]\nscale = 4\nlayout = \"qwerty\"\n").unwrap();
+
+        let layers = load(&path, "abc").unwrap();
+        let config = Config::resolve(String::new(), &layers).unwrap();
+        assert_eq!(4, config.scale);
+        assert_eq!(Key::Z, config.keys[0xC]);
+
+        let layers = load(&path, "def").unwrap();
+        let config = Config::resolve(String::new(), &layers).unwrap();
+        assert_eq!(8, config.scale);
+        assert_eq!(Key::Y, config.keys[0xC]);
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(load(&path, "abc").unwrap().is_empty());
+    }
+
+    #[test]
+    fn database_below_config() {
+        let entry = Entry {
+            title: String::from("Blinky"),
+            author: None,
+            year: None,
+            platform: String::from("chip48"),
+            quirks: None,
+            tick_rate: Some(15),
+            keys: None,
+        };
+        let config = Config::resolve(String::from("abc"), &[Profile::from_database(&entry)]).unwrap();
+        assert_eq!("chip48", config.quirks);
+        assert_eq!(Timing::Instructions(15), config.timing());
+
+        let user = Profile { quirks: Some(String::from("vip")), ..Profile::default() };
+        let config = Config::resolve(String::from("abc"), &[Profile::from_database(&entry), user]).unwrap();
+        assert_eq!("vip", config.quirks);
+    }
+
+    #[test]
+    fn print_round_trip() {
+        let config = Config::resolve(String::from("abc"), &[]).unwrap();
+        let profile: Profile = toml::from_str(&config.to_toml()).unwrap();
+
+        let reparsed = Config::resolve(String::new(), &[profile]).unwrap();
+        assert_eq!(config.keys, reparsed.keys);
+        assert_eq!(config.colours[..2], reparsed.colours[..2]);
+    }
+}
@@ -0,0 +1,767 @@
+// A Debug Adapter Protocol server, so editors like VS Code can debug roms, see the crusty-8-dap tool.
+// Requests arrive and responses and events leave as JSON messages with a Content-Length header.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use serde_json::{json, Value};
+use crate::chip8::{Chip8, Quirks, Timing};
+use crate::database::{self, Entry};
+use crate::disassembler;
+use crate::history::{self, History, Location};
+
+// The CHIP-8 has a single thread of execution
+const THREAD: u64 = 1;
+// variablesReference of the scopes
+const REGISTERS: u64 = 1;
+const TIMERS: u64 = 2;
+const STACK: u64 = 3;
+// The whole memory disassembled, the most a disassemble request gets
+const MAX_INSTRUCTIONS: u64 = 4096 / 2;
+
+// Read one message, None once the input ends.
+pub fn read_message(reader: &mut dyn BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+
+    let length = length.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Missing Content-Length"))?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    serde_json::from_slice(&body).map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub fn write_message(writer: &mut dyn Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+// Lines of an assembler source and the address of the code each of them produced, given as
+// {"source": "game.8o", "lines": {"12": 512, "13": 514}}
+pub struct SourceMap {
+    // matched against the end of the paths the editor sends
+    pub source: String,
+    lines: BTreeMap<u64, u16>,
+    addresses: BTreeMap<u16, u64>,
+}
+
+impl SourceMap {
+    pub fn parse(text: &str) -> Result<SourceMap, String> {
+        let error = |e: &dyn std::fmt::Display| format!("Invalid source map: {}", e);
+        let map: Value = serde_json::from_str(text).map_err(|e| error(&e))?;
+        let source = map["source"].as_str().ok_or_else(|| error(&"expected a source"))?.to_string();
+        let mut lines = BTreeMap::new();
+        for (line, address) in map["lines"].as_object().ok_or_else(|| error(&"expected lines"))? {
+            let line = line.parse().map_err(|_| error(&format!("'{}' is not a line", line)))?;
+            let address = address.as_u64().filter(|&address| address < 4096)
+                .ok_or_else(|| error(&format!("line {} has no valid address", line)))?;
+            lines.insert(line, address as u16);
+        }
+        let addresses = lines.iter().map(|(&line, &address)| (address, line)).collect();
+        Ok(SourceMap { source, lines, addresses })
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        Path::new(path).ends_with(&self.source)
+    }
+
+    // Breakpoints on lines without code move down to the next line with code.
+    fn address(&self, line: u64) -> Option<(u64, u16)> {
+        self.lines.range(line..).next().map(|(&line, &address)| (line, address))
+    }
+
+    // The line of the instruction at the address, which may be part of the code of an earlier line.
+    fn line(&self, address: u16) -> Option<u64> {
+        self.addresses.range(..=address).next_back().map(|(_, &line)| line)
+    }
+}
+
+pub struct Adapter {
+    chip8: Option<Chip8>,
+    timing: Timing,
+    source_map: Option<SourceMap>,
+    // every setBreakpoints and setInstructionBreakpoints request replaces its own kind
+    line_breakpoints: BTreeSet<u16>,
+    instruction_breakpoints: BTreeSet<u16>,
+    // where a step over or out ends
+    step_target: Option<u16>,
+    stop_on_entry: bool,
+    // whether the tool should show the display, tests launch without one
+    display: bool,
+    running: bool,
+    finished: bool,
+    seq: u64,
+}
+
+impl Default for Adapter {
+    fn default() -> Adapter {
+        Adapter {
+            chip8: None,
+            timing: Timing::Instructions(10),
+            source_map: None,
+            line_breakpoints: BTreeSet::new(),
+            instruction_breakpoints: BTreeSet::new(),
+            step_target: None,
+            stop_on_entry: false,
+            display: true,
+            running: false,
+            finished: false,
+            seq: 0,
+        }
+    }
+}
+
+impl Adapter {
+    // The interpreter once a rom was launched.
+    pub fn chip8(&mut self) -> Option<&mut Chip8> {
+        self.chip8.as_mut()
+    }
+
+    pub fn display(&self) -> bool {
+        self.display
+    }
+
+    pub fn running(&self) -> bool {
+        self.running
+    }
+
+    // Whether the editor disconnected.
+    pub fn finished(&self) -> bool {
+        self.finished
+    }
+
+    // Answer a request, the response comes first and is followed by any events.
+    pub fn handle(&mut self, request: &Value) -> Vec<Value> {
+        let command = request["command"].as_str().unwrap_or("").to_string();
+        let mut events = Vec::new();
+        let result = self.dispatch(&command, &request["arguments"], &mut events);
+
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": command,
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        let mut messages = vec![response];
+        messages.extend(events);
+        self.number(messages)
+    }
+
+    // Run a frame if the program isn't halted and report where it stopped.
+    pub fn run_frame(&mut self) -> Vec<Value> {
+        let chip8 = match &mut self.chip8 {
+            Some(chip8) if self.running => chip8,
+            _ => return Vec::new(),
+        };
+        chip8.run_frame(self.timing);
+        if !chip8.at_breakpoint() {
+            return Vec::new();
+        }
+
+        let reason = if self.step_target == Some(chip8.registers().pc) { "step" } else { "breakpoint" };
+        self.step_target = None;
+        self.running = false;
+        self.update_breakpoints();
+        let event = stopped(reason);
+        self.number(vec![event])
+    }
+
+    // Tell the editor the program is gone, e.g. because its window was closed.
+    pub fn terminate(&mut self) -> Vec<Value> {
+        self.running = false;
+        self.chip8 = None;
+        self.number(vec![event("terminated", json!({}))])
+    }
+
+    fn number(&mut self, mut messages: Vec<Value>) -> Vec<Value> {
+        for message in messages.iter_mut() {
+            self.seq += 1;
+            message["seq"] = json!(self.seq);
+        }
+        messages
+    }
+
+    fn update_breakpoints(&mut self) {
+        if let Some(chip8) = &mut self.chip8 {
+            chip8.breakpoints = self.line_breakpoints.union(&self.instruction_breakpoints).cloned().collect();
+            chip8.breakpoints.extend(self.step_target);
+        }
+    }
+
+    fn launched(&mut self) -> Result<&mut Chip8, String> {
+        self.chip8.as_mut().ok_or_else(|| String::from("No program was launched"))
+    }
+
+    // Continue until a breakpoint, stepping off the one the program stopped at.
+    fn resume(&mut self, step_target: Option<u16>) -> Result<(), String> {
+        self.step_target = step_target;
+        self.update_breakpoints();
+        let chip8 = self.launched()?;
+        if chip8.at_breakpoint() && step_target != Some(chip8.registers().pc) {
+            chip8.tick();
+        }
+        self.running = true;
+        Ok(())
+    }
+
+    fn dispatch(&mut self, command: &str, arguments: &Value, events: &mut Vec<Value>) -> Result<Value, String> {
+        match command {
+            "initialize" => {
+                events.push(event("initialized", json!({})));
+                Ok(json!({
+                    "supportsConfigurationDoneRequest": true,
+                    "supportsInstructionBreakpoints": true,
+                    "supportsReadMemoryRequest": true,
+                    "supportsWriteMemoryRequest": true,
+                    "supportsDisassembleRequest": true,
+                    "supportsTerminateRequest": true,
+                    "supportsStepBack": true,
+                }))
+            }
+            "launch" => {
+                self.launch(arguments)?;
+                Ok(json!({}))
+            }
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    events.push(stopped("entry"));
+                } else {
+                    self.resume(None)?;
+                }
+                Ok(json!({}))
+            }
+            "setBreakpoints" => self.set_breakpoints(arguments),
+            "setInstructionBreakpoints" => {
+                let mut breakpoints = Vec::new();
+                self.instruction_breakpoints.clear();
+                for breakpoint in arguments["breakpoints"].as_array().into_iter().flatten() {
+                    let address = breakpoint["instructionReference"].as_str().and_then(parse_reference)
+                        .map(|address| address as i64 + breakpoint["offset"].as_i64().unwrap_or(0))
+                        .filter(|address| (0..4096).contains(address));
+                    match address {
+                        Some(address) => {
+                            self.instruction_breakpoints.insert(address as u16);
+                            breakpoints.push(json!({ "verified": true, "instructionReference": reference(address as u16) }));
+                        }
+                        None => breakpoints.push(json!({ "verified": false, "message": "Not an address" })),
+                    }
+                }
+                self.update_breakpoints();
+                Ok(json!({ "breakpoints": breakpoints }))
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD, "name": "CHIP-8" }] })),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS, "expensive": false },
+                { "name": "Timers", "variablesReference": TIMERS, "expensive": false },
+                { "name": "Stack", "variablesReference": STACK, "expensive": false },
+            ]})),
+            "variables" => self.variables(arguments["variablesReference"].as_u64().unwrap_or(0)),
+            "continue" => {
+                self.resume(None)?;
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            // Stepping over a subroutine call runs until the instruction after it.
+            "next" => {
+                let chip8 = self.launched()?;
+                let pc = chip8.registers().pc;
+                if chip8.memory()[pc as usize] >> 4 == 0x2 {
+                    self.resume(Some(pc + 2))?;
+                } else {
+                    chip8.tick();
+                    events.push(stopped("step"));
+                }
+                Ok(json!({}))
+            }
+            "stepIn" => {
+                self.launched()?.tick();
+                events.push(stopped("step"));
+                Ok(json!({}))
+            }
+            "stepOut" => {
+                let chip8 = self.launched()?;
+                match chip8.stack().last().copied() {
+                    Some(address) => self.resume(Some(address))?,
+                    None => return Err(String::from("Not in a subroutine")),
+                }
+                Ok(json!({}))
+            }
+            "stepBack" => {
+                self.launched()?.step_back();
+                events.push(stopped("step"));
+                Ok(json!({}))
+            }
+            // Stops at the start of the history if there is no earlier breakpoint.
+            "reverseContinue" => {
+                self.running = false;
+                self.step_target = None;
+                self.update_breakpoints();
+                let chip8 = self.launched()?;
+                let reason = if chip8.run_back() && chip8.at_breakpoint() { "breakpoint" } else { "step" };
+                events.push(stopped(reason));
+                Ok(json!({}))
+            }
+            "evaluate" => self.evaluate(arguments["expression"].as_str().unwrap_or("")),
+            "pause" => {
+                self.running = false;
+                self.step_target = None;
+                self.update_breakpoints();
+                events.push(stopped("pause"));
+                Ok(json!({}))
+            }
+            "readMemory" => {
+                let address = self.memory_address(arguments)?;
+                let count = arguments["count"].as_u64().unwrap_or(0) as usize;
+                let memory = self.launched()?.memory();
+                let end = address + count.min(memory.len() - address);
+                Ok(json!({
+                    "address": reference(address as u16),
+                    "data": base64(&memory[address..end]),
+                    "unreadableBytes": count - (end - address),
+                }))
+            }
+            "writeMemory" => {
+                let address = self.memory_address(arguments)?;
+                let data = arguments["data"].as_str().and_then(unbase64).ok_or("Invalid data")?;
+                let memory = self.launched()?.memory_mut();
+                if address + data.len() > memory.len() {
+                    return Err(String::from("Writing past the end of the memory"));
+                }
+                memory[address..address + data.len()].copy_from_slice(&data);
+                Ok(json!({ "bytesWritten": data.len() }))
+            }
+            "disassemble" => self.disassemble(arguments),
+            "terminate" => {
+                events.extend(self.terminate());
+                Ok(json!({}))
+            }
+            "disconnect" => {
+                self.finished = true;
+                self.running = false;
+                Ok(json!({}))
+            }
+            _ => Err(format!("Unsupported request '{}'", command)),
+        }
+    }
+
+    // Accepts the rom as program and optionally sourceMap, quirks (like --quirks), timing (fixed or vip),
+    // instructionsPerFrame, stopOnEntry and display.
+    fn launch(&mut self, arguments: &Value) -> Result<(), String> {
+        let program = arguments["program"].as_str().ok_or("Launching needs a program")?;
+        let rom = std::fs::read(program).map_err(|e| format!("Could not read {}: {}", program, e))?;
+        if rom.len() > 4096 - 0x200 {
+            return Err(format!("The rom is {} bytes, at most {} fit into memory", rom.len(), 4096 - 0x200));
+        }
+
+        // Known roms get the quirks and speed from the database unless the configuration gives them
+        let entry = database::lookup(&rom);
+        let mut chip8 = Chip8::new_with_state();
+        let quirks = arguments["quirks"].as_str()
+            .or_else(|| entry.map(Entry::quirks))
+            .unwrap_or(Quirks::DEFAULT_PROFILE);
+        chip8.quirks = Quirks::from_profile(quirks)
+            .ok_or_else(|| format!("Unknown quirk profile '{}', expected one of {:?}", quirks, Quirks::PROFILES))?;
+        chip8.load_bytes(&rom);
+
+        let instructions = arguments["instructionsPerFrame"].as_u64()
+            .or_else(|| entry.and_then(|entry| entry.tick_rate))
+            .unwrap_or(10);
+        self.timing = match arguments["timing"].as_str() {
+            Some("fixed") | None => Timing::Instructions(instructions),
+            Some("vip") => Timing::Vip,
+            Some(other) => return Err(format!("Unknown timing '{}', expected fixed or vip", other)),
+        };
+        self.source_map = match arguments["sourceMap"].as_str() {
+            Some(path) => Some(SourceMap::parse(
+                &std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?,
+            )?),
+            None => None,
+        };
+        self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+        self.display = arguments["display"].as_bool().unwrap_or(true);
+        chip8.history = Some(History::new(history::CHECKPOINTS));
+        self.chip8 = Some(chip8);
+        self.update_breakpoints();
+        Ok(())
+    }
+
+    fn set_breakpoints(&mut self, arguments: &Value) -> Result<Value, String> {
+        let path = arguments["source"]["path"].as_str().unwrap_or("");
+        let map = self.source_map.as_ref().filter(|map| map.matches(path));
+
+        let mut breakpoints = Vec::new();
+        let mut addresses = BTreeSet::new();
+        for breakpoint in arguments["breakpoints"].as_array().into_iter().flatten() {
+            let line = breakpoint["line"].as_u64().unwrap_or(0);
+            match map.and_then(|map| map.address(line)) {
+                Some((line, address)) => {
+                    addresses.insert(address);
+                    breakpoints.push(json!({ "verified": true, "line": line, "instructionReference": reference(address) }));
+                }
+                None => breakpoints.push(json!({ "verified": false, "line": line, "message": "No code at this line" })),
+            }
+        }
+        // The only source with line information is the one of the source map.
+        if map.is_some() {
+            self.line_breakpoints = addresses;
+            self.update_breakpoints();
+        }
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    // The innermost frame is at PC, the others at the subroutine calls on the stack.
+    // Subroutines are named after their address, which is found in the CALL before each return address.
+    fn stack_trace(&mut self) -> Result<Value, String> {
+        let chip8 = self.chip8.as_ref().ok_or("No program was launched")?;
+        let memory = chip8.memory();
+        let callee = |address: u16| {
+            let call = (address.wrapping_sub(2) & 0xFFF) as usize;
+            (memory[call] as u16 & 0x0F) << 8 | memory[(call + 1) & 0xFFF] as u16
+        };
+
+        let stack = chip8.stack();
+        let mut frames = Vec::new();
+        let addresses = std::iter::once(chip8.registers().pc).chain(stack.iter().rev().map(|&address| address.wrapping_sub(2)));
+        for (depth, address) in addresses.enumerate() {
+            let name = match stack.len().checked_sub(depth + 1) {
+                Some(index) => format!("sub_{:03X}", callee(stack[index])),
+                None => String::from("main"),
+            };
+            let mut frame = json!({
+                "id": depth,
+                "name": name,
+                "line": 0,
+                "column": 0,
+                "instructionPointerReference": reference(address),
+            });
+            if let Some(map) = &self.source_map {
+                if let Some(line) = map.line(address) {
+                    frame["line"] = json!(line);
+                    frame["column"] = json!(1);
+                    frame["source"] = json!({ "path": map.source });
+                }
+            }
+            frames.push(frame);
+        }
+        Ok(json!({ "stackFrames": frames, "totalFrames": stack.len() + 1 }))
+    }
+
+    fn variables(&mut self, reference: u64) -> Result<Value, String> {
+        let chip8 = self.launched()?;
+        let registers = chip8.registers();
+        let variable = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+
+        let variables: Vec<Value> = match reference {
+            REGISTERS => {
+                let mut variables: Vec<Value> = (0..16)
+                    .map(|x| variable(format!("V{:X}", x), format!("0x{:02X}", registers.v[x])))
+                    .collect();
+                let mut i = variable(String::from("I"), format!("0x{:03X}", registers.i));
+                i["memoryReference"] = json!(self::reference(registers.i));
+                variables.push(i);
+                variables.push(variable(String::from("PC"), format!("0x{:03X}", registers.pc)));
+                variables.push(variable(String::from("SP"), registers.sp.to_string()));
+                variables
+            }
+            TIMERS => vec![
+                variable(String::from("DT"), registers.dt.to_string()),
+                variable(String::from("ST"), registers.st.to_string()),
+            ],
+            STACK => chip8.stack().iter().enumerate()
+                .map(|(index, &address)| variable(format!("{}", index), format!("0x{:03X}", address)))
+                .collect(),
+            _ => return Err(format!("Unknown variables reference {}", reference)),
+        };
+        Ok(json!({ "variables": variables }))
+    }
+
+    // The debug console answers "last-write v3" with the instruction that last wrote to a register or address.
+    fn evaluate(&mut self, expression: &str) -> Result<Value, String> {
+        let location = expression.trim().strip_prefix("last-write").and_then(Location::parse)
+            .ok_or("Only last-write v0-vf|i|dt|st|address can be evaluated")?;
+        let chip8 = self.launched()?;
+        let result = match (chip8.last_write(location), &chip8.history) {
+            (Some(write), Some(history)) => format!(
+                "{} at {}, {} instructions ago",
+                disassembler::mnemonic(write.opcode), reference(write.pc), history.position() - write.step,
+            ),
+            _ => format!("{} wasn't written as far as the history goes back", location),
+        };
+        Ok(json!({ "result": result, "variablesReference": 0 }))
+    }
+
+    fn memory_address(&self, arguments: &Value) -> Result<usize, String> {
+        let address = arguments["memoryReference"].as_str().and_then(parse_reference)
+            .and_then(|address| (address as i64).checked_add(arguments["offset"].as_i64().unwrap_or(0)))
+            .filter(|address| (0..4096).contains(address))
+            .ok_or("Invalid memory reference")?;
+        Ok(address as usize)
+    }
+
+    // Instructions are always two bytes, offsets that leave the memory are shown as unknown.
+    // No more than the whole memory's worth of instructions is returned.
+    fn disassemble(&mut self, arguments: &Value) -> Result<Value, String> {
+        let start = arguments["memoryReference"].as_str().and_then(parse_reference)
+            .and_then(|address| (address as i64).checked_add(arguments["offset"].as_i64().unwrap_or(0)))
+            .and_then(|address| {
+                let offset = arguments["instructionOffset"].as_i64().unwrap_or(0).checked_mul(2)?;
+                address.checked_add(offset)
+            })
+            .ok_or("Invalid memory reference")?;
+        let count = arguments["instructionCount"].as_u64().unwrap_or(0).min(MAX_INSTRUCTIONS) as i64;
+        let chip8 = self.chip8.as_ref().ok_or("No program was launched")?;
+        let memory = chip8.memory();
+
+        let instructions: Vec<Value> = (0..count).map(|index| start.saturating_add(index * 2)).map(|address| {
+            if address < 0 || address >= memory.len() as i64 - 1 {
+                return json!({ "address": format!("0x{:X}", address.max(0)), "instruction": "??", "presentationHint": "invalid" });
+            }
+            let address = address as usize;
+            let opcode = u16::from_be_bytes([memory[address], memory[address + 1]]);
+            let mut instruction = json!({
+                "address": reference(address as u16),
+                "instructionBytes": format!("{:02X} {:02X}", memory[address], memory[address + 1]),
+                "instruction": disassembler::mnemonic(opcode),
+            });
+            if let Some(line) = self.source_map.as_ref().and_then(|map| map.line(address as u16)) {
+                instruction["line"] = json!(line);
+            }
+            instruction
+        }).collect();
+        Ok(json!({ "instructions": instructions }))
+    }
+}
+
+fn event(name: &str, body: Value) -> Value {
+    json!({ "type": "event", "event": name, "body": body })
+}
+
+fn stopped(reason: &str) -> Value {
+    event("stopped", json!({ "reason": reason, "threadId": THREAD, "allThreadsStopped": true }))
+}
+
+fn reference(address: u16) -> String {
+    format!("0x{:03X}", address)
+}
+
+// Addresses are hex with 0x or decimal.
+fn parse_reference(reference: &str) -> Option<u16> {
+    match reference.strip_prefix("0x").or_else(|| reference.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => reference.parse().ok(),
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64(bytes: &[u8]) -> String {
+    let mut text = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (index, &byte)| bits | (byte as u32) << (16 - index * 8));
+        for index in 0..4 {
+            if index <= chunk.len() {
+                text.push(BASE64[(bits >> (18 - index * 6)) as usize & 0x3F] as char);
+            } else {
+                text.push('=');
+            }
+        }
+    }
+    text
+}
+
+fn unbase64(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len() / 4 * 3);
+    let (mut bits, mut count) = (0u32, 0);
+    for c in text.bytes().filter(|&c| c != b'=') {
+        bits = bits << 6 | BASE64.iter().position(|&digit| digit == c)? as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // CALL 0x206; JP 0x200; 0x204: unused; 0x206: ADD V0, 1; RET
+    const ROM: [u8; 10] = [0x22, 0x06, 0x12, 0x00, 0x00, 0x00, 0x70, 0x01, 0x00, 0xEE];
+
+    fn request(adapter: &mut Adapter, command: &str, arguments: Value) -> Vec<Value> {
+        let messages = adapter.handle(&json!({ "seq": 1, "type": "request", "command": command, "arguments": arguments }));
+        assert_eq!(json!(true), messages[0]["success"], "{} failed: {}", command, messages[0]);
+        messages
+    }
+
+    fn launch(name: &str) -> Adapter {
+        let directory = std::env::temp_dir();
+        let rom = directory.join(format!("crusty-8-dap-{}.ch8", name));
+        let map = directory.join(format!("crusty-8-dap-{}.json", name));
+        std::fs::write(&rom, ROM).unwrap();
+        std::fs::write(&map, r#"{"source": "game.8o", "lines": {"1": 512, "2": 514, "5": 518, "6": 520}}"#).unwrap();
+
+        let mut adapter = Adapter::default();
+        let messages = request(&mut adapter, "initialize", json!({}));
+        assert_eq!(json!("initialized"), messages[1]["event"]);
+        request(&mut adapter, "launch", json!({
+            "program": rom.to_str().unwrap(),
+            "sourceMap": map.to_str().unwrap(),
+            "display": false,
+        }));
+        adapter
+    }
+
+    #[test]
+    fn line_breakpoints() {
+        let mut adapter = launch("lines");
+        let messages = request(&mut adapter, "setBreakpoints", json!({
+            "source": { "path": "/home/me/game.8o" },
+            "breakpoints": [{ "line": 3 }, { "line": 9 }],
+        }));
+        let breakpoints = &messages[0]["body"]["breakpoints"];
+        assert_eq!(json!({ "verified": true, "line": 5, "instructionReference": "0x206" }), breakpoints[0]);
+        assert_eq!(json!(false), breakpoints[1]["verified"]);
+
+        request(&mut adapter, "configurationDone", json!({}));
+        assert!(adapter.running());
+        let messages = adapter.run_frame();
+        assert_eq!(json!("breakpoint"), messages[0]["body"]["reason"]);
+        assert!(!adapter.running());
+
+        let messages = request(&mut adapter, "stackTrace", json!({ "threadId": THREAD }));
+        let frames = &messages[0]["body"]["stackFrames"];
+        assert_eq!(json!("sub_206"), frames[0]["name"]);
+        assert_eq!(json!(5), frames[0]["line"]);
+        assert_eq!(json!("main"), frames[1]["name"]);
+        assert_eq!(json!("0x200"), frames[1]["instructionPointerReference"]);
+
+        // Step out of the subroutine back into the main loop
+        request(&mut adapter, "stepOut", json!({ "threadId": THREAD }));
+        let messages = adapter.run_frame();
+        assert_eq!(json!("step"), messages[0]["body"]["reason"]);
+        assert_eq!(0x202, adapter.chip8().unwrap().registers().pc);
+    }
+
+    #[test]
+    fn inspection() {
+        let mut adapter = launch("inspection");
+        request(&mut adapter, "setInstructionBreakpoints", json!({ "breakpoints": [{ "instructionReference": "0x208" }] }));
+        request(&mut adapter, "configurationDone", json!({}));
+        adapter.run_frame();
+
+        let messages = request(&mut adapter, "variables", json!({ "variablesReference": REGISTERS }));
+        let variables = &messages[0]["body"]["variables"];
+        assert_eq!(json!({ "name": "V0", "value": "0x01", "variablesReference": 0 }), variables[0]);
+        assert_eq!(json!("0x208"), variables[17]["value"]);
+        let messages = request(&mut adapter, "variables", json!({ "variablesReference": STACK }));
+        assert_eq!(json!("0x202"), messages[0]["body"]["variables"][0]["value"]);
+
+        let messages = request(&mut adapter, "readMemory", json!({ "memoryReference": "0x206", "count": 4 }));
+        assert_eq!(json!("cAEA7g=="), messages[0]["body"]["data"]);
+        let messages = request(&mut adapter, "readMemory", json!({ "memoryReference": "0xffe", "count": u64::MAX }));
+        assert_eq!(json!("AAA="), messages[0]["body"]["data"]);
+        assert_eq!(json!(u64::MAX - 2), messages[0]["body"]["unreadableBytes"]);
+        request(&mut adapter, "writeMemory", json!({ "memoryReference": "0x204", "data": "YAc=" }));
+        assert_eq!(&[0x60, 0x07], &adapter.chip8().unwrap().memory()[0x204..0x206]);
+
+        let messages = request(&mut adapter, "disassemble", json!({
+            "memoryReference": "0x200", "instructionOffset": 1, "instructionCount": 2,
+        }));
+        let instructions = &messages[0]["body"]["instructions"];
+        assert_eq!(json!("JP 0x200"), instructions[0]["instruction"]);
+        assert_eq!(json!("LD V0, 0x07"), instructions[1]["instruction"]);
+        let messages = request(&mut adapter, "disassemble", json!({
+            "memoryReference": "0x200", "instructionOffset": -1, "instructionCount": u64::MAX,
+        }));
+        assert_eq!(MAX_INSTRUCTIONS as usize, messages[0]["body"]["instructions"].as_array().unwrap().len());
+        let messages = request(&mut adapter, "disassemble", json!({
+            "memoryReference": "0x200", "offset": i64::MAX - 0x200, "instructionCount": 2,
+        }));
+        assert_eq!(json!("??"), messages[0]["body"]["instructions"][1]["instruction"]);
+        // Offsets past the range of numbers are invalid
+        for (command, arguments) in [
+            ("disassemble", json!({ "memoryReference": "0x200", "instructionOffset": i64::MAX, "instructionCount": 1 })),
+            ("readMemory", json!({ "memoryReference": "0x200", "offset": i64::MAX, "count": 1 })),
+        ] {
+            let messages = adapter.handle(&json!({ "seq": 8, "command": command, "arguments": arguments }));
+            assert_eq!(json!(false), messages[0]["success"], "{}", command);
+        }
+
+        assert_eq!(json!(false), adapter.handle(&json!({ "seq": 9, "command": "evaluate" }))[0]["success"]);
+    }
+
+    #[test]
+    fn known_rom() {
+        let program = "roms/games/Blinky [Hans Christian Egeberg, 1991].ch8";
+        let mut adapter = Adapter::default();
+        request(&mut adapter, "launch", json!({ "program": program, "display": false }));
+        assert_eq!(Quirks::from_profile("chip48"), adapter.chip8().map(|chip8| chip8.quirks));
+        assert_eq!(Timing::Instructions(15), adapter.timing);
+
+        // The launch configuration wins
+        request(&mut adapter, "launch", json!({
+            "program": program, "quirks": "vip", "instructionsPerFrame": 20, "display": false,
+        }));
+        assert_eq!(Quirks::from_profile("vip"), adapter.chip8().map(|chip8| chip8.quirks));
+        assert_eq!(Timing::Instructions(20), adapter.timing);
+    }
+
+    #[test]
+    fn reverse() {
+        let mut adapter = launch("reverse");
+        request(&mut adapter, "setInstructionBreakpoints", json!({ "breakpoints": [{ "instructionReference": "0x208" }] }));
+        request(&mut adapter, "configurationDone", json!({}));
+        adapter.run_frame();
+        request(&mut adapter, "continue", json!({ "threadId": THREAD }));
+        adapter.run_frame();
+        assert_eq!(2, adapter.chip8().unwrap().registers().v[0]);
+
+        let messages = request(&mut adapter, "stepBack", json!({ "threadId": THREAD }));
+        assert_eq!(json!("step"), messages[1]["body"]["reason"]);
+        assert_eq!(0x206, adapter.chip8().unwrap().registers().pc);
+        let messages = request(&mut adapter, "evaluate", json!({ "expression": "last-write v0", "context": "repl" }));
+        assert_eq!(json!("ADD V0, 0x01 at 0x206, 4 instructions ago"), messages[0]["body"]["result"]);
+
+        let messages = request(&mut adapter, "reverseContinue", json!({ "threadId": THREAD }));
+        assert_eq!(json!("breakpoint"), messages[1]["body"]["reason"]);
+        assert_eq!((0x208, 1), (adapter.chip8().unwrap().registers().pc, adapter.chip8().unwrap().registers().v[0]));
+        let messages = request(&mut adapter, "reverseContinue", json!({ "threadId": THREAD }));
+        assert_eq!(json!("step"), messages[1]["body"]["reason"]);
+        assert_eq!(0x200, adapter.chip8().unwrap().registers().pc);
+    }
+
+    #[test]
+    fn encoding() {
+        assert_eq!("", base64(&[]));
+        assert_eq!("TWFu", base64(b"Man"));
+        assert_eq!("TWE=", base64(b"Ma"));
+        assert_eq!(Some(b"Ma".to_vec()), unbase64("TWE="));
+        assert_eq!(None, unbase64("T!=="));
+
+        let mut output = Vec::new();
+        write_message(&mut output, &json!({ "seq": 1 })).unwrap();
+        assert_eq!(b"Content-Length: 9\r\n\r\n{\"seq\":1}".to_vec(), output);
+        let message = read_message(&mut &output[..]).unwrap();
+        assert_eq!(Some(json!({ "seq": 1 })), message);
+        assert_eq!(None, read_message(&mut &b""[..]).unwrap());
+    }
+}
@@ -0,0 +1,99 @@
+// Settings for known roms, looked up by the SHA-1 hash of the file. Many roms were written for later
+// interpreters like CHIP-48 and only run right with their quirks, or need a certain speed to be playable.
+// The entries live in database.toml, which is compiled into the binary.
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use serde::Deserialize;
+use crate::chip8::Timing;
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Entry {
+    pub title: String,
+    pub author: Option<String>,
+    pub year: Option<u16>,
+    // chip8 for the COSMAC VIP, chip48 for the HP48 calculators or hires for the 64x64 mode of the VIP
+    pub platform: String,
+    // overrides the quirks of the platform, like --quirks
+    pub quirks: Option<String>,
+    // instructions per frame
+    pub tick_rate: Option<u64>,
+    // what the keys do
+    pub keys: Option<String>,
+}
+
+impl Entry {
+    // The quirks to run the rom with, given ones or the ones of the platform.
+    pub fn quirks(&self) -> &str {
+        match &self.quirks {
+            Some(quirks) => quirks,
+            None if self.platform == "chip48" => "chip48",
+            None => "vip",
+        }
+    }
+
+    pub fn timing(&self) -> Option<Timing> {
+        self.tick_rate.map(Timing::Instructions)
+    }
+
+    // Whether the interpreter can run roms for the platform at all, the hires mode isn't emulated.
+    pub fn supported(&self) -> bool {
+        self.platform == "chip8" || self.platform == "chip48"
+    }
+
+    // Like "Blitz by David Winter (1990)".
+    pub fn name(&self) -> String {
+        let mut name = self.title.clone();
+        if let Some(author) = &self.author {
+            name.push_str(&format!(" by {}", author));
+        }
+        if let Some(year) = self.year {
+            name.push_str(&format!(" ({})", year));
+        }
+        name
+    }
+}
+
+pub fn rom_hash(rom: &[u8]) -> String {
+    sha1::Sha1::from(rom).digest().to_string()
+}
+
+fn entries() -> &'static HashMap<String, Entry> {
+    static ENTRIES: OnceLock<HashMap<String, Entry>> = OnceLock::new();
+    ENTRIES.get_or_init(|| toml::from_str(include_str!("database.toml")).unwrap())
+}
+
+pub fn lookup(rom: &[u8]) -> Option<&'static Entry> {
+    entries().get(&rom_hash(rom))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chip8::Quirks;
+
+    #[test]
+    fn entries_are_valid() {
+        for (hash, entry) in entries() {
+            assert_eq!(40, hash.len(), "{}", hash);
+            assert!(["chip8", "chip48", "hires"].contains(&entry.platform.as_str()), "{}", hash);
+            assert!(Quirks::from_profile(entry.quirks()).is_some(), "{}", hash);
+            assert!(entry.tick_rate != Some(0), "{}", hash);
+        }
+    }
+
+    #[test]
+    fn known_rom() {
+        let rom = std::fs::read("roms/games/Blinky [Hans Christian Egeberg, 1991].ch8").unwrap();
+        let entry = lookup(&rom).unwrap();
+        assert_eq!("Blinky by Hans Christian Egeberg (1991)", entry.name());
+        assert_eq!("chip48", entry.quirks());
+        assert_eq!(Some(Timing::Instructions(15)), entry.timing());
+        assert!(entry.supported());
+
+        let rom = std::fs::read("roms/games/Space Invaders [David Winter].ch8").unwrap();
+        assert_eq!("vip,shift", lookup(&rom).unwrap().quirks());
+
+        assert_eq!(None, lookup(&[0x12, 0x00]));
+    }
+}
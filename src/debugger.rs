@@ -0,0 +1,330 @@
+use minifb::{Key, KeyRepeat, MouseButton, MouseMode, Window, WindowOptions};
+use crusty_8::chip8::{Chip8, Registers};
+use crusty_8::disassembler;
+use crusty_8::history::Location;
+use crate::overlay::{self, GLYPH_HEIGHT, GLYPH_WIDTH};
+
+const TITLE: &str = "Crusty-8 debugger";
+
+// Everything is text in the overlay font, laid out on a grid of characters.
+const SCALE: usize = 2;
+const CHAR_WIDTH: usize = (GLYPH_WIDTH + 1) * SCALE;
+const LINE_HEIGHT: usize = (GLYPH_HEIGHT + 2) * SCALE;
+const COLUMNS: usize = 72;
+const ROWS: usize = 32;
+
+// The disassembly fills the left side, registers, stack and keypad the right one, the memory dump the bottom.
+const DISASSEMBLY_ROWS: usize = 24;
+const PANEL_COLUMN: usize = 32;
+const DUMP_ROW: usize = 25;
+const DUMP_ROWS: usize = 5;
+const LAST_WRITE_ROW: usize = 18;
+
+const BACKGROUND: u32 = 0x10_10_18;
+const TEXT: u32 = 0xC0_C0_C0;
+const HEADING: u32 = 0x60_A0_FF;
+const CURRENT: u32 = 0xFF_E0_40;
+const BREAKPOINT: u32 = 0xFF_50_50;
+const PRESSED: u32 = 0x40_FF_60;
+
+// A second window showing the state of the interpreter, which can halt it, step through it and set breakpoints.
+// Space steps a single instruction, R runs or halts, a click on a line of the disassembly toggles its breakpoint
+// and a right click runs up to it. The mouse wheel scrolls the memory dump, Home makes it follow I again.
+// With the history Backspace steps back and B runs back to the previous breakpoint, and a click on a register
+// or a byte of the memory dump shows the instruction that last wrote to it.
+pub struct Debugger {
+    // None once the user closed it, which also lets the program run freely
+    window: Option<Window>,
+    buffer: Vec<u32>,
+    halted: bool,
+    // where running to the cursor stops, and whether there was a breakpoint already
+    run_to: Option<(u16, bool)>,
+    // first address of the memory dump, None to follow I
+    dump_start: Option<u16>,
+    // addresses of the disassembly lines as drawn last, to find the clicked one
+    lines: Vec<u16>,
+    // mouse buttons held in the last update, only new clicks count
+    buttons: (bool, bool),
+    // the register or address whose last write is shown
+    query: Option<Location>,
+}
+
+impl Debugger {
+    // The program starts halted, so breakpoints can be set before anything ran.
+    pub fn new() -> Debugger {
+        let mut window = Window::new(TITLE, COLUMNS * CHAR_WIDTH, ROWS * LINE_HEIGHT, WindowOptions::default())
+            .unwrap_or_else(|e| {
+                panic!("{}", e);
+            });
+        window.limit_update_rate(None);
+        Debugger {
+            window: Some(window),
+            buffer: vec![BACKGROUND; COLUMNS * CHAR_WIDTH * ROWS * LINE_HEIGHT],
+            halted: true,
+            run_to: None,
+            dump_start: None,
+            lines: Vec::new(),
+            buttons: (false, false),
+            query: None,
+        }
+    }
+
+    pub fn halted(&self) -> bool {
+        self.window.is_some() && self.halted
+    }
+
+    // Handle the keys and clicks of the debugger window.
+    pub fn update(&mut self, chip8: &mut Chip8) {
+        let window = match &self.window {
+            Some(window) if window.is_open() => window,
+            _ => {
+                self.window = None;
+                return;
+            }
+        };
+
+        let step = window.is_key_pressed(Key::Space, KeyRepeat::Yes);
+        let back = window.is_key_pressed(Key::Backspace, KeyRepeat::Yes);
+        let toggle = window.is_key_pressed(Key::R, KeyRepeat::No);
+        let run_back = window.is_key_pressed(Key::B, KeyRepeat::No);
+        if window.is_key_pressed(Key::Home, KeyRepeat::No) {
+            self.dump_start = None;
+        }
+        if let Some((_, scroll)) = window.get_scroll_wheel() {
+            let start = self.dump_start.unwrap_or(chip8.registers().i & 0xFFF0) as i32;
+            self.dump_start = Some((start - scroll.signum() as i32 * 16).clamp(0, 0x1000 - 16) as u16);
+        }
+
+        let buttons = (window.get_mouse_down(MouseButton::Left), window.get_mouse_down(MouseButton::Right));
+        let position = window.get_mouse_pos(MouseMode::Discard)
+            .map(|(x, y)| (x as usize / CHAR_WIDTH, y as usize / LINE_HEIGHT));
+        let clicked = position
+            .and_then(|(column, row)| if column < PANEL_COLUMN { self.lines.get(row).copied() } else { None });
+        let (left, right) = (buttons.0 && !self.buttons.0, buttons.1 && !self.buttons.1);
+        self.buttons = buttons;
+
+        if step {
+            self.halt(chip8);
+            chip8.tick();
+        }
+        if back {
+            self.halt(chip8);
+            chip8.step_back();
+        }
+        if run_back {
+            self.halt(chip8);
+            chip8.run_back();
+        }
+        if left {
+            let start = self.dump_start.unwrap_or(chip8.registers().i & 0xFFF0);
+            if let Some(location) = position.and_then(|(column, row)| location_at(column, row, start)) {
+                self.query = Some(location);
+            }
+        }
+        if toggle {
+            if self.halted { self.resume(chip8) } else { self.halt(chip8) }
+        }
+        match clicked {
+            Some(address) if left && !chip8.breakpoints.remove(&address) => {
+                chip8.breakpoints.insert(address);
+            }
+            Some(address) if right => {
+                let existed = !chip8.breakpoints.insert(address);
+                self.run_to = Some((address, existed));
+                self.resume(chip8);
+            }
+            _ => (),
+        }
+    }
+
+    // Halt at breakpoints, call this after every frame.
+    pub fn after_frame(&mut self, chip8: &mut Chip8) {
+        if self.window.is_some() && chip8.at_breakpoint() {
+            self.halt(chip8);
+        }
+    }
+
+    fn halt(&mut self, chip8: &mut Chip8) {
+        self.halted = true;
+        if let Some((address, false)) = self.run_to.take() {
+            chip8.breakpoints.remove(&address);
+        }
+    }
+
+    // Step off the breakpoint the program stopped at.
+    fn resume(&mut self, chip8: &mut Chip8) {
+        if chip8.at_breakpoint() && self.run_to.map(|(address, _)| address) != Some(chip8.registers().pc) {
+            chip8.tick();
+        }
+        self.halted = false;
+    }
+
+    pub fn draw(&mut self, chip8: &Chip8) {
+        if self.window.is_none() {
+            return;
+        }
+        self.buffer.iter_mut().for_each(|pixel| *pixel = BACKGROUND);
+        let registers = chip8.registers();
+
+        let lines = disassembly(chip8.memory(), registers.pc, DISASSEMBLY_ROWS);
+        for (row, (address, text)) in lines.iter().enumerate() {
+            let colour = if *address == registers.pc {
+                CURRENT
+            } else if chip8.breakpoints.contains(address) {
+                BREAKPOINT
+            } else {
+                TEXT
+            };
+            let marker = if chip8.breakpoints.contains(address) { "*" } else { " " };
+            self.text(&format!("{}{}", marker, text), 0, row, colour);
+        }
+        self.lines = lines.iter().map(|&(address, _)| address).collect();
+
+        let state = if self.halted { "HALTED" } else { "RUNNING" };
+        self.text(&format!("REGISTERS   {}", state), PANEL_COLUMN, 0, HEADING);
+        for (row, line) in register_lines(&registers).iter().enumerate() {
+            self.text(line, PANEL_COLUMN, 1 + row, TEXT);
+        }
+
+        self.text("STACK", PANEL_COLUMN, 8, HEADING);
+        for (row, address) in chip8.stack().iter().rev().enumerate() {
+            let (column, row) = (PANEL_COLUMN + (row / 8) * 6, 9 + row % 8);
+            self.text(&format!("{:03X}", address), column, row, TEXT);
+        }
+
+        if let Some(location) = self.query {
+            self.text("LAST WRITE", PANEL_COLUMN, LAST_WRITE_ROW, HEADING);
+            let lines = match (chip8.last_write(location), &chip8.history) {
+                (Some(write), Some(history)) => vec![
+                    format!("{} AT {:03X} {}", location, write.pc, disassembler::mnemonic(write.opcode)),
+                    format!("{} STEPS AGO", history.position() - write.step),
+                ],
+                (None, Some(_)) => vec![format!("{} NOT WRITTEN IN THE HISTORY", location)],
+                (_, None) => vec![String::from("NO HISTORY")],
+            };
+            for (row, line) in lines.iter().enumerate() {
+                self.text(line, PANEL_COLUMN, LAST_WRITE_ROW + 1 + row, TEXT);
+            }
+        }
+
+        self.text("KEYPAD", PANEL_COLUMN + 24, 8, HEADING);
+        for (position, &key) in [0x1, 0x2, 0x3, 0xC, 0x4, 0x5, 0x6, 0xD, 0x7, 0x8, 0x9, 0xE, 0xA, 0x0, 0xB, 0xF].iter().enumerate() {
+            let colour = if chip8.keys[key] { PRESSED } else { TEXT };
+            self.text(&format!("{:X}", key), PANEL_COLUMN + 24 + (position % 4) * 2, 9 + position / 4, colour);
+        }
+
+        let start = self.dump_start.unwrap_or(registers.i & 0xFFF0);
+        let heading = if self.dump_start.is_some() { "MEMORY" } else { "MEMORY AT I" };
+        self.text(heading, 0, DUMP_ROW - 1, HEADING);
+        for (row, line) in dump(chip8.memory(), start, DUMP_ROWS).iter().enumerate() {
+            self.text(line, 0, DUMP_ROW + row, TEXT);
+        }
+        self.text("SPACE STEP  BACKSPACE STEP BACK  R RUN  B RUN BACK", 0, ROWS - 2, HEADING);
+        self.text("CLICK BREAKPOINT OR LAST WRITE  RIGHT CLICK RUN TO", 0, ROWS - 1, HEADING);
+
+        if let Some(window) = &mut self.window {
+            let _ = window.update_with_buffer(&self.buffer, COLUMNS * CHAR_WIDTH, ROWS * LINE_HEIGHT);
+        }
+    }
+
+    fn text(&mut self, text: &str, column: usize, row: usize, colour: u32) {
+        let size = (COLUMNS * CHAR_WIDTH, ROWS * LINE_HEIGHT);
+        overlay::draw_text(&mut self.buffer, size, text, column * CHAR_WIDTH, row * LINE_HEIGHT, SCALE, colour);
+    }
+}
+
+// Instructions around PC, which is shown on the third of the lines.
+fn disassembly(memory: &[u8], pc: u16, rows: usize) -> Vec<(u16, String)> {
+    let first = (pc as i32 - (rows / 3) as i32 * 2).max(0) as usize;
+    (first..memory.len() - 1).step_by(2).take(rows)
+        .map(|address| {
+            let opcode = u16::from_be_bytes([memory[address], memory[address + 1]]);
+            let marker = if address == pc as usize { '>' } else { ' ' };
+            (address as u16, format!("{}{:03X} {:04X} {}", marker, address, opcode, disassembler::mnemonic(opcode)))
+        })
+        .collect()
+}
+
+fn register_lines(registers: &Registers) -> Vec<String> {
+    let mut lines: Vec<String> = registers.v.chunks(4).enumerate()
+        .map(|(row, values)| {
+            let values: Vec<String> = values.iter().enumerate()
+                .map(|(column, value)| format!("V{:X} {:02X}", row * 4 + column, value))
+                .collect();
+            values.join("  ")
+        })
+        .collect();
+    lines.push(format!("I {:03X}  PC {:03X}  SP {}", registers.i, registers.pc, registers.sp));
+    lines.push(format!("DT {:02X}  ST {:02X}", registers.dt, registers.st));
+    lines
+}
+
+// The register or byte of the memory dump at a position of the window, the opposite of what draw does.
+fn location_at(column: usize, row: usize, dump_start: u16) -> Option<Location> {
+    if (DUMP_ROW..DUMP_ROW + DUMP_ROWS).contains(&row) {
+        // "200: 60 12 ..."
+        let byte = column.checked_sub(5).filter(|offset| offset % 3 < 2)? / 3;
+        let address = dump_start as usize + (row - DUMP_ROW) * 16 + byte;
+        return if byte < 16 && address < 4096 { Some(Location::Memory(address as u16)) } else { None };
+    }
+
+    // "V0 12  V1 00 ...", "I 000  PC 202  SP 0" and "DT 00  ST 00"
+    let column = column.checked_sub(PANEL_COLUMN).filter(|column| column % 7 < 5)?;
+    match (row, column / 7) {
+        (1..=4, entry @ 0..=3) => Some(Location::V(((row - 1) * 4 + entry) as u8)),
+        (5, 0) => Some(Location::I),
+        (6, 0) => Some(Location::Dt),
+        (6, 1) => Some(Location::St),
+        _ => None,
+    }
+}
+
+// 16 bytes per line, starting at an address.
+fn dump(memory: &[u8], start: u16, rows: usize) -> Vec<String> {
+    memory[start as usize..].chunks(16).take(rows).enumerate()
+        .map(|(row, bytes)| {
+            let bytes: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            format!("{:03X}: {}", start as usize + row * 16, bytes.join(" "))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lines() {
+        let mut chip8 = Chip8::new_with_state();
+        chip8.load_bytes(&[0x60, 0x12, 0xA2, 0xA0, 0x12, 0x00]);
+        chip8.tick();
+
+        let lines = disassembly(chip8.memory(), 0x202, 4);
+        assert_eq!((0x200, String::from(" 200 6012 LD V0, 0x12")), lines[0]);
+        assert_eq!((0x202, String::from(">202 A2A0 LD I, 0x2A0")), lines[1]);
+        assert_eq!(4, lines.len());
+        // The end of the memory
+        assert_eq!(2, disassembly(chip8.memory(), 0xFFE, 3).len());
+
+        let registers = register_lines(&chip8.registers());
+        assert_eq!("V0 12  V1 00  V2 00  V3 00", registers[0]);
+        assert_eq!("I 000  PC 202  SP 0", registers[4]);
+
+        let dump = dump(chip8.memory(), 0x200, 2);
+        assert_eq!("200: 60 12 A2 A0 12 00 00 00 00 00 00 00 00 00 00 00", dump[0]);
+        assert_eq!(1, super::dump(chip8.memory(), 0xFF0, 2).len());
+    }
+
+    #[test]
+    fn locations() {
+        assert_eq!(Some(Location::V(0x5)), location_at(PANEL_COLUMN + 10, 2, 0x200));
+        assert_eq!(None, location_at(PANEL_COLUMN + 5, 2, 0x200));
+        assert_eq!(Some(Location::I), location_at(PANEL_COLUMN + 2, 5, 0x200));
+        assert_eq!(Some(Location::St), location_at(PANEL_COLUMN + 8, 6, 0x200));
+        assert_eq!(None, location_at(PANEL_COLUMN + 8, 5, 0x200));
+        assert_eq!(Some(Location::Memory(0x200)), location_at(5, DUMP_ROW, 0x200));
+        assert_eq!(Some(Location::Memory(0x21F)), location_at(50, DUMP_ROW + 1, 0x200));
+        assert_eq!(None, location_at(7, DUMP_ROW, 0x200));
+        assert_eq!(None, location_at(5, DUMP_ROW + 1, 0xFF0));
+    }
+}
@@ -0,0 +1,234 @@
+// Differential testing: run the same rom on two machines with the same input
+// and find the first point where their states differ.
+
+use std::collections::VecDeque;
+use crate::chip8::{Chip8, Timing, WIDTH};
+use crate::machine::{Machine, Snapshot};
+
+// Keys held from a given frame on.
+pub struct Input {
+    changes: Vec<(u64, [bool; 16])>,
+}
+
+impl Input {
+    pub fn none() -> Input {
+        Input { changes: Vec::new() }
+    }
+
+    // Every line holds a frame number and the keys held from then on as hex digits, e.g. "120 5A".
+    // A frame number on its own releases all keys, # starts a comment.
+    pub fn parse(text: &str) -> Result<Input, String> {
+        let mut changes = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            let frame = parts.next().unwrap().parse()
+                .map_err(|_| format!("Line {}: expected a frame number", number + 1))?;
+            let mut keys = [false; 16];
+            for digit in parts.flat_map(str::chars) {
+                let key = digit.to_digit(16)
+                    .ok_or_else(|| format!("Line {}: '{}' is not a key", number + 1, digit))?;
+                keys[key as usize] = true;
+            }
+            changes.push((frame, keys));
+        }
+        changes.sort_by_key(|&(frame, _)| frame);
+        Ok(Input { changes })
+    }
+
+    pub fn keys(&self, frame: u64) -> [bool; 16] {
+        self.changes.iter()
+            .take_while(|&&(from, _)| from <= frame)
+            .last()
+            .map_or([false; 16], |&(_, keys)| keys)
+    }
+}
+
+// Where two machines went separate ways.
+#[derive(Debug)]
+pub struct Divergence {
+    pub frame: u64,
+    // instruction within the frame, None when comparing whole frames
+    pub instruction: Option<u64>,
+    pub differences: Vec<String>,
+    // what happened right before, oldest first
+    pub trace: Vec<String>,
+    // CXNN ran on machines that can't draw the same random numbers, which might be all there is to it
+    pub random: bool,
+}
+
+// Describe how two snapshots differ, empty if they don't.
+pub fn differences(a: &Snapshot, b: &Snapshot) -> Vec<String> {
+    let mut differences = Vec::new();
+    if a.pc != b.pc {
+        differences.push(format!("PC 0x{:03X} != 0x{:03X}", a.pc, b.pc));
+    }
+    if a.i != b.i {
+        differences.push(format!("I 0x{:03X} != 0x{:03X}", a.i, b.i));
+    }
+    for (register, (x, y)) in a.v.iter().zip(b.v.iter()).enumerate() {
+        if x != y {
+            differences.push(format!("V{:X} 0x{:02X} != 0x{:02X}", register, x, y));
+        }
+    }
+    for (offset, (x, y)) in a.memory.iter().zip(b.memory.iter()).enumerate() {
+        if x != y {
+            differences.push(format!("memory at 0x{:03X} 0x{:02X} != 0x{:02X}", 0x200 + offset, x, y));
+        }
+    }
+    let pixels: Vec<usize> = (0..a.framebuffer.len()).filter(|&i| a.framebuffer[i] != b.framebuffer[i]).collect();
+    if let Some(&first) = pixels.first() {
+        differences.push(format!("{} pixels, the first at ({}, {})", pixels.len(), first % WIDTH, first / WIDTH));
+    }
+    differences
+}
+
+fn opcode(snapshot: &Snapshot) -> Option<u16> {
+    let offset = (snapshot.pc as usize).checked_sub(0x200)?;
+    let bytes = snapshot.memory.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+// One line of the trace, both sides are shown if they were at different instructions.
+fn trace_line(frame: u64, a: &Snapshot, b: &Snapshot) -> String {
+    let describe = |snapshot: &Snapshot| match opcode(snapshot) {
+        Some(opcode) => format!("{:03X}: {:04X}", snapshot.pc, opcode),
+        None => format!("{:03X}: ????", snapshot.pc),
+    };
+    if a.pc == b.pc {
+        format!("{:>6} {}", frame, describe(a))
+    } else {
+        format!("{:>6} {} | {}", frame, describe(a), describe(b))
+    }
+}
+
+fn remember(trace: &mut VecDeque<String>, line: String, context: usize) {
+    if trace.len() == context {
+        trace.pop_front();
+    }
+    if context > 0 {
+        trace.push_back(line);
+    }
+}
+
+// Whether the high-level interpreter executed CXNN, None for other backends.
+fn random_numbers(machine: &mut dyn Machine) -> Option<bool> {
+    machine.chip8().map(|chip8| chip8.random_numbers() > 0)
+}
+
+// Run two interpreters in lockstep and compare them after every instruction.
+pub fn run_instructions(
+    a: &mut Chip8, b: &mut Chip8, input: &Input, frames: u64, per_frame: u64, context: usize,
+) -> Option<Divergence> {
+    let mut trace = VecDeque::new();
+
+    for frame in 0..frames {
+        a.keys = input.keys(frame);
+        b.keys = input.keys(frame);
+
+        for instruction in 0..per_frame {
+            remember(&mut trace, trace_line(frame, &a.snapshot(), &b.snapshot()), context);
+            for chip8 in [&mut *a, &mut *b].iter_mut() {
+                if !chip8.waiting_for_vblank() {
+                    chip8.tick();
+                }
+            }
+
+            let differences = differences(&a.snapshot(), &b.snapshot());
+            if !differences.is_empty() {
+                let trace = trace.into();
+                return Some(Divergence { frame, instruction: Some(instruction), differences, trace, random: false });
+            }
+        }
+        a.tick_timers();
+        b.tick_timers();
+    }
+    None
+}
+
+// Compare any two machines at the end of every frame, or at the end of the instruction they are in the middle of.
+// Frames that end while one of them waits within an instruction aren't compared.
+pub fn run_frames(
+    a: &mut dyn Machine, b: &mut dyn Machine, input: &Input, frames: u64, timing: Timing, context: usize,
+) -> Option<Divergence> {
+    let mut trace = VecDeque::new();
+
+    for frame in 0..frames {
+        *a.keys() = input.keys(frame);
+        *b.keys() = input.keys(frame);
+        a.run_frame(timing);
+        b.run_frame(timing);
+        if !(a.finish_instruction() & b.finish_instruction()) {
+            continue;
+        }
+
+        let (left, right) = (a.snapshot(), b.snapshot());
+        let differences = differences(&left, &right);
+        if !differences.is_empty() {
+            // Seeding makes two interpreters agree, but not an interpreter and the COSMAC VIP
+            let random = match (random_numbers(a), random_numbers(b)) {
+                (Some(drawn), None) | (None, Some(drawn)) => drawn,
+                _ => false,
+            };
+            return Some(Divergence { frame, instruction: None, differences, trace: trace.into(), random });
+        }
+        remember(&mut trace, trace_line(frame, &left, &right), context);
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chip8::Quirks;
+
+    // Shifts V1 into V0 every frame and waits for a key
+    const ROM: [u8; 8] = [0x61, 0x03, 0x80, 0x16, 0xF2, 0x0A, 0x12, 0x00];
+
+    fn machines(a: &str, b: &str) -> (Chip8, Chip8) {
+        let boot = |quirks: &str| {
+            let mut chip8 = Chip8::new_with_state();
+            chip8.quirks = Quirks::from_profile(quirks).unwrap();
+            chip8.seed(1);
+            chip8.load_bytes(&ROM);
+            chip8
+        };
+        (boot(a), boot(b))
+    }
+
+    #[test]
+    fn input() {
+        let input = Input::parse("# start\n10 5A\n\n20\n15 f # fire").unwrap();
+        assert_eq!([false; 16], input.keys(9));
+        assert!(input.keys(10)[0x5] && input.keys(10)[0xA]);
+        assert!(input.keys(17)[0xF] && !input.keys(17)[0x5]);
+        assert_eq!([false; 16], input.keys(20));
+        assert!(Input::parse("10 G").is_err());
+        assert!(Input::parse("ten").is_err());
+    }
+
+    #[test]
+    fn same_configuration() {
+        let (mut a, mut b) = machines("vip", "vip");
+        assert!(run_instructions(&mut a, &mut b, &Input::none(), 10, 10, 4).is_none());
+    }
+
+    #[test]
+    fn shift_quirk() {
+        let (mut a, mut b) = machines("vip", "chip48");
+        let divergence = run_instructions(&mut a, &mut b, &Input::none(), 10, 10, 4).unwrap();
+        assert_eq!((0, Some(1)), (divergence.frame, divergence.instruction));
+        assert_eq!(vec!["V0 0x01 != 0x00", "VF 0x01 != 0x00"], divergence.differences);
+        assert_eq!(vec!["     0 200: 6103", "     0 202: 8016"], divergence.trace);
+
+        // Whole frames with the same input catch it too
+        let (mut a, mut b) = machines("vip", "chip48");
+        let input = Input::parse("0 7").unwrap();
+        let divergence = run_frames(&mut a, &mut b, &input, 10, Timing::Instructions(10), 4).unwrap();
+        assert_eq!(0, divergence.frame);
+        assert!(divergence.trace.is_empty());
+    }
+}
@@ -0,0 +1,114 @@
+// Mnemonics after Cowgod's Chip-8 technical reference.
+
+pub fn mnemonic(opcode: u16) -> String {
+    let x = (opcode & 0x0F00) >> 8;
+    let y = (opcode & 0x00F0) >> 4;
+    let nnn = opcode & 0x0FFF;
+    let nn = opcode & 0x00FF;
+    let n = opcode & 0x000F;
+
+    match (opcode >> 12, x, y, n) {
+        (0x0, 0x0, 0xE, 0x0) => String::from("CLS"),
+        (0x0, 0x0, 0xE, 0xE) => String::from("RET"),
+        (0x0, _, _, _) => format!("SYS 0x{:03X}", nnn),
+        (0x1, _, _, _) => format!("JP 0x{:03X}", nnn),
+        (0x2, _, _, _) => format!("CALL 0x{:03X}", nnn),
+        (0x3, _, _, _) => format!("SE V{:X}, 0x{:02X}", x, nn),
+        (0x4, _, _, _) => format!("SNE V{:X}, 0x{:02X}", x, nn),
+        (0x5, _, _, 0x0) => format!("SE V{:X}, V{:X}", x, y),
+        (0x6, _, _, _) => format!("LD V{:X}, 0x{:02X}", x, nn),
+        (0x7, _, _, _) => format!("ADD V{:X}, 0x{:02X}", x, nn),
+        (0x8, _, _, 0x0) => format!("LD V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x1) => format!("OR V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x2) => format!("AND V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x3) => format!("XOR V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x4) => format!("ADD V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x5) => format!("SUB V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x6) => format!("SHR V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x7) => format!("SUBN V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0xE) => format!("SHL V{:X}, V{:X}", x, y),
+        (0x9, _, _, 0x0) => format!("SNE V{:X}, V{:X}", x, y),
+        (0xA, _, _, _) => format!("LD I, 0x{:03X}", nnn),
+        (0xB, _, _, _) => format!("JP V0, 0x{:03X}", nnn),
+        (0xC, _, _, _) => format!("RND V{:X}, 0x{:02X}", x, nn),
+        (0xD, _, _, _) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        (0xE, _, 0x9, 0xE) => format!("SKP V{:X}", x),
+        (0xE, _, 0xA, 0x1) => format!("SKNP V{:X}", x),
+        (0xF, _, 0x0, 0x7) => format!("LD V{:X}, DT", x),
+        (0xF, _, 0x0, 0xA) => format!("LD V{:X}, K", x),
+        (0xF, _, 0x1, 0x5) => format!("LD DT, V{:X}", x),
+        (0xF, _, 0x1, 0x8) => format!("LD ST, V{:X}", x),
+        (0xF, _, 0x1, 0xE) => format!("ADD I, V{:X}", x),
+        (0xF, _, 0x2, 0x9) => format!("LD F, V{:X}", x),
+        (0xF, _, 0x3, 0x3) => format!("LD B, V{:X}", x),
+        (0xF, _, 0x5, 0x5) => format!("LD [I], V{:X}", x),
+        (0xF, _, 0x6, 0x5) => format!("LD V{:X}, [I]", x),
+        _ => format!("DW 0x{:04X}", opcode),
+    }
+}
+
+// The opcode pattern an instruction belongs to, like DXYN.
+pub fn pattern(opcode: u16) -> &'static str {
+    match (opcode >> 12, (opcode & 0x0F00) >> 8, (opcode & 0x00F0) >> 4, opcode & 0x000F) {
+        (0x0, 0x0, 0xE, 0x0) => "00E0",
+        (0x0, 0x0, 0xE, 0xE) => "00EE",
+        (0x0, _, _, _) => "0NNN",
+        (0x1, _, _, _) => "1NNN",
+        (0x2, _, _, _) => "2NNN",
+        (0x3, _, _, _) => "3XNN",
+        (0x4, _, _, _) => "4XNN",
+        (0x5, _, _, 0x0) => "5XY0",
+        (0x6, _, _, _) => "6XNN",
+        (0x7, _, _, _) => "7XNN",
+        (0x8, _, _, 0x0) => "8XY0",
+        (0x8, _, _, 0x1) => "8XY1",
+        (0x8, _, _, 0x2) => "8XY2",
+        (0x8, _, _, 0x3) => "8XY3",
+        (0x8, _, _, 0x4) => "8XY4",
+        (0x8, _, _, 0x5) => "8XY5",
+        (0x8, _, _, 0x6) => "8XY6",
+        (0x8, _, _, 0x7) => "8XY7",
+        (0x8, _, _, 0xE) => "8XYE",
+        (0x9, _, _, 0x0) => "9XY0",
+        (0xA, _, _, _) => "ANNN",
+        (0xB, _, _, _) => "BNNN",
+        (0xC, _, _, _) => "CXNN",
+        (0xD, _, _, _) => "DXYN",
+        (0xE, _, 0x9, 0xE) => "EX9E",
+        (0xE, _, 0xA, 0x1) => "EXA1",
+        (0xF, _, 0x0, 0x7) => "FX07",
+        (0xF, _, 0x0, 0xA) => "FX0A",
+        (0xF, _, 0x1, 0x5) => "FX15",
+        (0xF, _, 0x1, 0x8) => "FX18",
+        (0xF, _, 0x1, 0xE) => "FX1E",
+        (0xF, _, 0x2, 0x9) => "FX29",
+        (0xF, _, 0x3, 0x3) => "FX33",
+        (0xF, _, 0x5, 0x5) => "FX55",
+        (0xF, _, 0x6, 0x5) => "FX65",
+        _ => "invalid",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mnemonics() {
+        assert_eq!("CLS", mnemonic(0x00E0));
+        assert_eq!("CALL 0x2A4", mnemonic(0x22A4));
+        assert_eq!("LD V3, 0x12", mnemonic(0x6312));
+        assert_eq!("SHR V0, V1", mnemonic(0x8016));
+        assert_eq!("DRW V0, V1, 5", mnemonic(0xD015));
+        assert_eq!("LD [I], VF", mnemonic(0xFF55));
+        assert_eq!("DW 0x5121", mnemonic(0x5121));
+        assert_eq!("DW 0xF0FF", mnemonic(0xF0FF));
+    }
+
+    #[test]
+    fn patterns() {
+        assert_eq!("DXYN", pattern(0xD015));
+        assert_eq!("8XYE", pattern(0x812E));
+        assert_eq!("invalid", pattern(0xE0FF));
+    }
+}
@@ -0,0 +1,138 @@
+use std::time::Instant;
+use crusty_8::chip8::{WIDTH, HEIGHT};
+
+// Post-processing of the framebuffer before it is drawn.
+// Sprites are erased and redrawn with XOR, which flickers without some persistence.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    // Pixels are either on or off
+    None,
+    // Unlit pixels fade out like the phosphor of a CRT
+    Phosphor,
+    // Every pixel is the average of the current and the previous frame
+    Blend,
+}
+
+impl Mode {
+    pub const NAMES: [&'static str; 3] = ["none", "phosphor", "blend"];
+
+    pub fn from_name(name: &str) -> Option<Mode> {
+        match name {
+            "none" => Some(Mode::None),
+            "phosphor" => Some(Mode::Phosphor),
+            "blend" => Some(Mode::Blend),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::None => "none",
+            Mode::Phosphor => "phosphor",
+            Mode::Blend => "blend",
+        }
+    }
+}
+
+pub struct Filter {
+    mode: Mode,
+    // share of the intensity lost per 60 Hz frame
+    decay: f32,
+    // brightness of every pixel between 0.0 and 1.0
+    intensity: [f32; WIDTH * HEIGHT],
+    // the framebuffer at the end of the previous 60 Hz frame
+    previous: [bool; WIDTH * HEIGHT],
+    // progress into the current 60 Hz frame
+    frame: f32,
+    last_update: Instant,
+}
+
+impl Filter {
+    pub fn new(mode: Mode, decay: f32) -> Filter {
+        Filter {
+            mode,
+            decay,
+            intensity: [0.0; WIDTH * HEIGHT],
+            previous: [false; WIDTH * HEIGHT],
+            frame: 0.0,
+            last_update: Instant::now(),
+        }
+    }
+
+    // Fading is based on the time since the last call, so it looks the same at every update rate.
+    pub fn apply(&mut self, framebuffer: &[bool]) -> &[f32] {
+        let now = Instant::now();
+        let frames = now.duration_since(self.last_update).as_secs_f32() * 60.0;
+        self.last_update = now;
+        self.step(framebuffer, frames)
+    }
+
+    // Advance the filter by the given number of 60 Hz frames.
+    fn step(&mut self, framebuffer: &[bool], frames: f32) -> &[f32] {
+        match self.mode {
+            Mode::None => {
+                for (pixel, &on) in self.intensity.iter_mut().zip(framebuffer) {
+                    *pixel = if on { 1.0 } else { 0.0 };
+                }
+            }
+
+            Mode::Phosphor => {
+                let remaining = (1.0 - self.decay).powf(frames);
+                for (pixel, &on) in self.intensity.iter_mut().zip(framebuffer) {
+                    *pixel = if on { 1.0 } else { *pixel * remaining };
+                }
+            }
+
+            Mode::Blend => {
+                for (i, pixel) in self.intensity.iter_mut().enumerate() {
+                    *pixel = (framebuffer[i] as u8 + self.previous[i] as u8) as f32 / 2.0;
+                }
+                self.frame += frames;
+                if self.frame >= 1.0 {
+                    self.previous.copy_from_slice(framebuffer);
+                    self.frame %= 1.0;
+                }
+            }
+        }
+
+        &self.intensity
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn phosphor_decay() {
+        let mut filter = Filter::new(Mode::Phosphor, 0.5);
+        let mut framebuffer = [false; WIDTH * HEIGHT];
+
+        framebuffer[0] = true;
+        assert_eq!(1.0, filter.step(&framebuffer, 1.0)[0]);
+
+        framebuffer[0] = false;
+        assert_eq!(0.5, filter.step(&framebuffer, 1.0)[0]);
+        assert_eq!(0.125, filter.step(&framebuffer, 2.0)[0]);
+
+        // Relighting a pixel restores the full intensity
+        framebuffer[0] = true;
+        assert_eq!(1.0, filter.step(&framebuffer, 0.1)[0]);
+    }
+
+    #[test]
+    fn frame_blending() {
+        let mut filter = Filter::new(Mode::Blend, 0.0);
+        let mut framebuffer = [false; WIDTH * HEIGHT];
+
+        framebuffer[0] = true;
+        assert_eq!(0.5, filter.step(&framebuffer, 0.5)[0]);
+        assert_eq!(0.5, filter.step(&framebuffer, 0.5)[0]);
+        // A full frame has passed, so the previous frame has the pixel lit as well
+        assert_eq!(1.0, filter.step(&framebuffer, 0.5)[0]);
+
+        framebuffer[0] = false;
+        assert_eq!(0.5, filter.step(&framebuffer, 1.0)[0]);
+        assert_eq!(0.0, filter.step(&framebuffer, 1.0)[0]);
+    }
+}
@@ -0,0 +1,498 @@
+// A server for the GDB remote serial protocol, so debugger frontends can attach to a running Chip8.
+// It never blocks: the frontend polls it once per frame and doesn't run frames while it is halted.
+//
+// gdb sees the registers V0-VF, I, PC, SP, DT and ST in this order, with I and PC as 16 bit values
+// and all of them big endian like the CHIP-8 itself. The memory is the 4K address space.
+// With a History attached to the Chip8, reverse-stepi and reverse-continue go back in time, and
+// "monitor last-write v3" tells which instruction last wrote to a register or memory address.
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use crate::chip8::{Chip8, Registers, PC_LIMIT};
+use crate::disassembler;
+use crate::history::Location;
+
+// Size in bytes of every register as gdb sees it, see the top of the file
+const REGISTER_SIZES: [usize; 21] = [1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 1, 1, 1];
+const REGISTER_BYTES: usize = 16 + 2 + 2 + 1 + 1 + 1;
+
+// Stop reasons as signal numbers
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+pub struct GdbServer {
+    listener: TcpListener,
+    // only one frontend at a time
+    client: Option<TcpStream>,
+    // received bytes that don't make a complete packet yet
+    input: Vec<u8>,
+    // the last packet sent, repeated when the frontend asks for it with '-'
+    last: Vec<u8>,
+    // whether the program was continued, it is halted as soon as a frontend attaches
+    running: bool,
+}
+
+impl GdbServer {
+    // Listen on localhost only, the protocol has no authentication.
+    pub fn bind(port: u16) -> io::Result<GdbServer> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        listener.set_nonblocking(true)?;
+        Ok(GdbServer { listener, client: None, input: Vec::new(), last: Vec::new(), running: false })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // Whether the frontend has to hold the machine, i.e. not run any frames.
+    pub fn halted(&self) -> bool {
+        self.client.is_some() && !self.running
+    }
+
+    // Accept a frontend and answer everything it sent since the last call.
+    pub fn poll(&mut self, chip8: &mut Chip8) {
+        if self.client.is_none() {
+            match self.listener.accept() {
+                Ok((stream, _)) if stream.set_nonblocking(true).is_ok() => {
+                    let _ = stream.set_nodelay(true);
+                    self.client = Some(stream);
+                    self.input.clear();
+                    self.running = false;
+                }
+                _ => return,
+            }
+        }
+
+        let mut buffer = [0; 4096];
+        loop {
+            let result = match &mut self.client {
+                Some(client) => client.read(&mut buffer),
+                None => return,
+            };
+            match result {
+                Ok(0) => return self.disconnect(),
+                Ok(length) => self.input.extend_from_slice(&buffer[..length]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => return self.disconnect(),
+            }
+        }
+        self.process(chip8);
+    }
+
+    // Report a breakpoint that was reached while running, call this after every frame.
+    pub fn after_frame(&mut self, chip8: &Chip8) {
+        if self.running && chip8.at_breakpoint() {
+            self.running = false;
+            self.send(&format!("S{:02x}", SIGTRAP));
+        }
+    }
+
+    // The program keeps running without a frontend.
+    fn disconnect(&mut self) {
+        self.client = None;
+        self.input.clear();
+        self.running = false;
+    }
+
+    fn send(&mut self, data: &str) {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        self.last = format!("${}#{:02x}", data, checksum).into_bytes();
+        self.write(&self.last.clone());
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        let failed = match &mut self.client {
+            Some(client) => client.write_all(bytes).is_err(),
+            None => false,
+        };
+        if failed {
+            self.disconnect();
+        }
+    }
+
+    // Packets look like $data#checksum, acknowledgements and the interrupt byte 0x03 come on their own.
+    fn process(&mut self, chip8: &mut Chip8) {
+        while let Some(&first) = self.input.first() {
+            match first {
+                b'$' => {
+                    let end = match self.input.iter().position(|&byte| byte == b'#') {
+                        Some(end) if self.input.len() >= end + 3 => end,
+                        // wait for the rest
+                        _ => return,
+                    };
+                    let packet: Vec<u8> = self.input.drain(..end + 3).collect();
+                    let data = &packet[1..end];
+                    let checksum = std::str::from_utf8(&packet[end + 1..]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                    if checksum != Some(data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))) {
+                        self.write(b"-");
+                        continue;
+                    }
+                    self.write(b"+");
+                    let data = String::from_utf8_lossy(data).into_owned();
+                    self.handle(&data, chip8);
+                    if self.client.is_none() {
+                        return;
+                    }
+                }
+                b'-' => {
+                    self.input.remove(0);
+                    self.write(&self.last.clone());
+                }
+                0x03 => {
+                    self.input.remove(0);
+                    if self.running {
+                        self.running = false;
+                        self.send(&format!("S{:02x}", SIGINT));
+                    }
+                }
+                _ => { self.input.remove(0); }
+            }
+        }
+    }
+
+    fn handle(&mut self, packet: &str, chip8: &mut Chip8) {
+        let (command, arguments) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => hex(&encode(&chip8.registers())),
+            "G" => match unhex(arguments).and_then(|bytes| decode(&bytes)) {
+                Some(registers) => {
+                    chip8.set_registers(registers);
+                    String::from("OK")
+                }
+                None => String::from("E01"),
+            },
+            "p" => match usize::from_str_radix(arguments, 16).ok().filter(|&n| n < REGISTER_SIZES.len()) {
+                Some(n) => {
+                    let offset: usize = REGISTER_SIZES[..n].iter().sum();
+                    hex(&encode(&chip8.registers())[offset..offset + REGISTER_SIZES[n]])
+                }
+                None => String::from("E01"),
+            },
+            "P" => match write_register(chip8, arguments) {
+                Some(()) => String::from("OK"),
+                None => String::from("E01"),
+            },
+            "m" => match parse_range(arguments).filter(|&(address, _)| address < 4096) {
+                Some((address, length)) => hex(&chip8.memory()[address..address + length.min(4096 - address)]),
+                None => String::from("E01"),
+            },
+            "M" => match write_memory(chip8, arguments) {
+                Some(()) => String::from("OK"),
+                None => String::from("E01"),
+            },
+            "s" => {
+                chip8.tick();
+                format!("S{:02x}", SIGTRAP)
+            }
+            // The breakpoint at the current address has to be stepped over first.
+            // The reply is sent once the program stops again, see after_frame.
+            "c" => {
+                if chip8.at_breakpoint() {
+                    chip8.tick();
+                }
+                if chip8.at_breakpoint() {
+                    format!("S{:02x}", SIGTRAP)
+                } else {
+                    self.running = true;
+                    return;
+                }
+            }
+            // bs and bc, a stop at the start of the history tells gdb there is nothing left to go back to
+            "b" => {
+                if chip8.history.is_none() {
+                    String::from("E01")
+                } else if (arguments == "s" && chip8.step_back()) || (arguments == "c" && chip8.run_back() && chip8.at_breakpoint()) {
+                    format!("S{:02x}", SIGTRAP)
+                } else {
+                    format!("T{:02x}replaylog:begin;", SIGTRAP)
+                }
+            }
+            "Z" | "z" => match parse_breakpoint(arguments) {
+                Some(address) => {
+                    if command == "Z" {
+                        chip8.breakpoints.insert(address);
+                    } else {
+                        chip8.breakpoints.remove(&address);
+                    }
+                    String::from("OK")
+                }
+                None => String::new(),
+            },
+            "D" => {
+                self.send("OK");
+                return self.disconnect();
+            }
+            "k" => return self.disconnect(),
+            "H" => String::from("OK"),
+            "q" => match arguments.strip_prefix("Rcmd,") {
+                Some(command) => monitor(chip8, command),
+                None => query(arguments),
+            },
+            // Everything else is unsupported, which the empty reply tells gdb
+            _ => String::new(),
+        };
+        self.send(&reply);
+    }
+}
+
+fn query(query: &str) -> String {
+    if query.starts_with("Supported") {
+        return String::from("PacketSize=1000;qXfer:features:read+;ReverseStep+;ReverseContinue+");
+    }
+    if let Some(range) = query.strip_prefix("Xfer:features:read:target.xml:") {
+        let xml = target_xml();
+        return match parse_range(range) {
+            Some((offset, length)) if offset <= xml.len() => {
+                let end = offset + length.min(xml.len() - offset);
+                format!("{}{}", if end == xml.len() { 'l' } else { 'm' }, &xml[offset..end])
+            }
+            _ => String::from("E01"),
+        };
+    }
+    match query {
+        "Attached" => String::from("1"),
+        "C" => String::from("QC1"),
+        "fThreadInfo" => String::from("m1"),
+        "sThreadInfo" => String::from("l"),
+        _ => String::new(),
+    }
+}
+
+// Commands given with "monitor", hex encoded like the output gdb prints.
+fn monitor(chip8: &Chip8, command: &str) -> String {
+    let command = match unhex(command).and_then(|bytes| String::from_utf8(bytes).ok()) {
+        Some(command) => command,
+        None => return String::from("E01"),
+    };
+    let output = match command.trim().strip_prefix("last-write").map(Location::parse) {
+        Some(Some(location)) => match (chip8.last_write(location), &chip8.history) {
+            (Some(write), Some(history)) => format!(
+                "{} was last written by {} at 0x{:03x}, {} instructions ago\n",
+                location, disassembler::mnemonic(write.opcode), write.pc, history.position() - write.step,
+            ),
+            (None, Some(_)) => format!("{} wasn't written as far as the history goes back\n", location),
+            (_, None) => String::from("There is no history to search\n"),
+        },
+        _ => String::from("Usage: monitor last-write v0-vf|i|dt|st|address\n"),
+    };
+    hex(output.as_bytes())
+}
+
+// Describes the registers to gdb, see the top of the file.
+fn target_xml() -> String {
+    let mut registers: Vec<String> = (0..16).map(|x| format!("<reg name=\"v{:x}\" bitsize=\"8\" type=\"uint8\"/>", x)).collect();
+    registers.push(String::from("<reg name=\"i\" bitsize=\"16\" type=\"data_ptr\"/>"));
+    registers.push(String::from("<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>"));
+    for name in ["sp", "dt", "st"].iter() {
+        registers.push(format!("<reg name=\"{}\" bitsize=\"8\" type=\"uint8\"/>", name));
+    }
+    format!(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\"><feature name=\"org.crusty8.chip8\">{}</feature></target>",
+        registers.concat(),
+    )
+}
+
+fn encode(registers: &Registers) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(REGISTER_BYTES);
+    bytes.extend_from_slice(&registers.v);
+    bytes.extend_from_slice(&registers.i.to_be_bytes());
+    bytes.extend_from_slice(&registers.pc.to_be_bytes());
+    bytes.extend_from_slice(&[registers.sp, registers.dt, registers.st]);
+    bytes
+}
+
+// Registers written by gdb, a PC that leaves no room for an instruction is rejected.
+fn decode(bytes: &[u8]) -> Option<Registers> {
+    if bytes.len() != REGISTER_BYTES || u16::from_be_bytes([bytes[18], bytes[19]]) >= PC_LIMIT {
+        return None;
+    }
+    let mut v = [0; 16];
+    v.copy_from_slice(&bytes[..16]);
+    Some(Registers {
+        v,
+        i: u16::from_be_bytes([bytes[16], bytes[17]]),
+        pc: u16::from_be_bytes([bytes[18], bytes[19]]),
+        sp: bytes[20],
+        dt: bytes[21],
+        st: bytes[22],
+    })
+}
+
+// Pn=value
+fn write_register(chip8: &mut Chip8, arguments: &str) -> Option<()> {
+    let mut parts = arguments.splitn(2, '=');
+    let n = usize::from_str_radix(parts.next()?, 16).ok().filter(|&n| n < REGISTER_SIZES.len())?;
+    let value = unhex(parts.next()?).filter(|value| value.len() == REGISTER_SIZES[n])?;
+    let offset: usize = REGISTER_SIZES[..n].iter().sum();
+    let mut bytes = encode(&chip8.registers());
+    bytes[offset..offset + value.len()].copy_from_slice(&value);
+    chip8.set_registers(decode(&bytes)?);
+    Some(())
+}
+
+// Maddress,length:data
+fn write_memory(chip8: &mut Chip8, arguments: &str) -> Option<()> {
+    let mut parts = arguments.splitn(2, ':');
+    let (address, length) = parse_range(parts.next()?)?;
+    let data = unhex(parts.next()?).filter(|data| data.len() == length && address.checked_add(length).is_some_and(|end| end <= 4096))?;
+    chip8.memory_mut()[address..address + length].copy_from_slice(&data);
+    Some(())
+}
+
+// Software (0) and hardware (1) breakpoints are the same here: type,address,kind
+fn parse_breakpoint(arguments: &str) -> Option<u16> {
+    let mut parts = arguments.split(',');
+    match parts.next()? {
+        "0" | "1" => u16::from_str_radix(parts.next()?, 16).ok(),
+        _ => None,
+    }
+}
+
+// address,length in hex
+fn parse_range(range: &str) -> Option<(usize, usize)> {
+    let mut parts = range.splitn(2, ',');
+    let address = usize::from_str_radix(parts.next()?, 16).ok()?;
+    let length = usize::from_str_radix(parts.next()?, 16).ok()?;
+    Some((address, length))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if text.len() & 1 != 0 {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+    use crate::chip8::Timing;
+    use crate::history::History;
+
+    // ADD V0, 1; ADD V1, 1; JP 0x200
+    const ROM: [u8; 6] = [0x70, 0x01, 0x71, 0x01, 0x12, 0x00];
+
+    // Send a packet and poll the server until its reply arrived.
+    fn exchange(server: &mut GdbServer, chip8: &mut Chip8, client: &mut TcpStream, packet: &str) -> String {
+        let checksum = packet.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        client.write_all(format!("${}#{:02x}", packet, checksum).as_bytes()).unwrap();
+        receive(server, chip8, client)
+    }
+
+    fn receive(server: &mut GdbServer, chip8: &mut Chip8, client: &mut TcpStream) -> String {
+        let mut received = Vec::new();
+        for _ in 0..1000 {
+            server.poll(chip8);
+            let mut buffer = [0; 1024];
+            if let Ok(length) = client.read(&mut buffer) {
+                received.extend_from_slice(&buffer[..length]);
+            }
+            let text = String::from_utf8_lossy(&received).into_owned();
+            if let (Some(start), Some(end)) = (text.find('$'), text.find('#')) {
+                if text.len() >= end + 3 {
+                    return text[start + 1..end].to_string();
+                }
+            }
+        }
+        panic!("no reply, received {:?}", String::from_utf8_lossy(&received));
+    }
+
+    #[test]
+    fn session() {
+        let mut chip8 = Chip8::new_with_state();
+        chip8.load_bytes(&ROM);
+        let mut server = GdbServer::bind(0).unwrap();
+        let mut client = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(1))).unwrap();
+
+        assert_eq!("S05", exchange(&mut server, &mut chip8, &mut client, "?"));
+        assert!(server.halted());
+        assert_eq!(format!("{}0000{}", "00".repeat(16), "0200000000"), exchange(&mut server, &mut chip8, &mut client, "g"));
+
+        // I is register 0x10
+        assert_eq!("OK", exchange(&mut server, &mut chip8, &mut client, "P10=0300"));
+        assert_eq!("0300", exchange(&mut server, &mut chip8, &mut client, "p10"));
+        assert_eq!("70017101", exchange(&mut server, &mut chip8, &mut client, "m200,4"));
+        assert_eq!("OK", exchange(&mut server, &mut chip8, &mut client, "M300,2:abcd"));
+        assert_eq!(&[0xAB, 0xCD], &chip8.memory()[0x300..0x302]);
+        assert_eq!("E01", exchange(&mut server, &mut chip8, &mut client, "m1000,2"));
+        assert_eq!(2 * 0x100, exchange(&mut server, &mut chip8, &mut client, "mf00,ffffffffffffffff").len());
+        assert_eq!("E01", exchange(&mut server, &mut chip8, &mut client, "Mffffffffffffffff,1:00"));
+        // PC is register 0x11, there has to be room for an instruction
+        assert_eq!("E01", exchange(&mut server, &mut chip8, &mut client, "P11=0fff"));
+        assert_eq!(0x200, chip8.registers().pc);
+
+        assert_eq!("S05", exchange(&mut server, &mut chip8, &mut client, "s"));
+        assert_eq!((0x202, 1), (chip8.registers().pc, chip8.registers().v[0]));
+
+        // Continue until the jump
+        assert_eq!("OK", exchange(&mut server, &mut chip8, &mut client, "Z0,204,2"));
+        client.write_all(b"$c#63").unwrap();
+        for _ in 0..100 {
+            server.poll(&mut chip8);
+            if !server.halted() { break }
+        }
+        assert!(!server.halted());
+        chip8.run_frame(Timing::Instructions(10));
+        server.after_frame(&chip8);
+        assert_eq!("S05", receive(&mut server, &mut chip8, &mut client));
+        assert!(server.halted());
+        assert_eq!(0x204, chip8.registers().pc);
+        assert_eq!("OK", exchange(&mut server, &mut chip8, &mut client, "z0,204,2"));
+        assert!(chip8.breakpoints.is_empty());
+
+        let xml = exchange(&mut server, &mut chip8, &mut client, "qXfer:features:read:target.xml:0,10");
+        assert_eq!("m<?xml version=\"1", xml);
+        assert_eq!("", exchange(&mut server, &mut chip8, &mut client, "vMustReplyEmpty"));
+
+        assert_eq!("OK", exchange(&mut server, &mut chip8, &mut client, "D"));
+        assert!(!server.halted());
+    }
+
+    #[test]
+    fn reverse() {
+        let mut chip8 = Chip8::new_with_state();
+        chip8.load_bytes(&ROM);
+        chip8.history = Some(History::new(10));
+        let mut server = GdbServer::bind(0).unwrap();
+        let mut client = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(1))).unwrap();
+
+        let supported = exchange(&mut server, &mut chip8, &mut client, "qSupported");
+        assert!(supported.contains("ReverseStep+"));
+        chip8.run_frame(Timing::Instructions(10));
+        assert_eq!("S05", exchange(&mut server, &mut chip8, &mut client, "bs"));
+        assert_eq!((0x200, 3), (chip8.registers().pc, chip8.registers().v[1]));
+
+        chip8.breakpoints.insert(0x202);
+        assert_eq!("S05", exchange(&mut server, &mut chip8, &mut client, "bc"));
+        assert_eq!((0x202, 3), (chip8.registers().pc, chip8.registers().v[0]));
+
+        let output = exchange(&mut server, &mut chip8, &mut client, &format!("qRcmd,{}", hex(b"last-write v1")));
+        let output = String::from_utf8(unhex(&output).unwrap()).unwrap();
+        assert_eq!("V1 was last written by ADD V1, 0x01 at 0x202, 3 instructions ago\n", output);
+
+        chip8.breakpoints.clear();
+        assert_eq!("T05replaylog:begin;", exchange(&mut server, &mut chip8, &mut client, "bc"));
+        assert_eq!(0x200, chip8.registers().pc);
+        assert_eq!("T05replaylog:begin;", exchange(&mut server, &mut chip8, &mut client, "bs"));
+    }
+
+    #[test]
+    fn registers() {
+        let registers = Registers { pc: 0x2A4, i: 0xFFF, v: [7; 16], sp: 2, dt: 60, st: 0 };
+        let bytes = encode(&registers);
+        assert_eq!(REGISTER_BYTES, bytes.len());
+        assert_eq!(REGISTER_BYTES, REGISTER_SIZES.iter().sum::<usize>());
+        assert_eq!(Some(registers), decode(&bytes));
+        assert_eq!(Some(vec![0x12, 0xAB]), unhex("12ab"));
+        assert_eq!(None, unhex("12a"));
+    }
+}
@@ -0,0 +1,98 @@
+use minifb::{Window, WindowOptions};
+use crusty_8::chip8::AccessCounts;
+
+const TITLE: &str = "Crusty-8 memory";
+
+// Every address is a cell, 64 of them per row, so the 4K fit into a square.
+const COLUMNS: usize = 64;
+const ROWS: usize = 4096 / COLUMNS;
+const CELL: usize = 8;
+
+// How much heat is left after a frame, recent accesses stand out while old ones fade within a second.
+const DECAY: f32 = 0.9;
+const BACKGROUND: u32 = 0x18_18_18;
+
+// A live view of the memory. Every cell turns red when written, green when executed
+// and blue when read, the more accesses the brighter.
+pub struct Heatmap {
+    // None once the user closed it
+    window: Option<Window>,
+    // counts as of the last frame, only new accesses heat up a cell
+    last: AccessCounts,
+    // writes, fetches and reads per address
+    heat: Vec<[f32; 3]>,
+    buffer: Vec<u32>,
+}
+
+impl Heatmap {
+    pub fn new() -> Heatmap {
+        let mut window = Window::new(TITLE, COLUMNS * CELL, ROWS * CELL, WindowOptions::default())
+            .unwrap_or_else(|e| {
+                panic!("{}", e);
+            });
+        window.limit_update_rate(None);
+        Heatmap {
+            window: Some(window),
+            last: AccessCounts::default(),
+            heat: vec![[0.0; 3]; 4096],
+            buffer: vec![0; COLUMNS * CELL * ROWS * CELL],
+        }
+    }
+
+    // Add the accesses since the last frame and let the older ones cool down.
+    // The counts start over with a hard reset, which must not count as accesses.
+    pub fn update(&mut self, counts: &AccessCounts) {
+        for (address, heat) in self.heat.iter_mut().enumerate() {
+            let channels = [(&counts.writes, &self.last.writes), (&counts.fetches, &self.last.fetches), (&counts.reads, &self.last.reads)];
+            for (value, (now, before)) in heat.iter_mut().zip(channels.iter()) {
+                *value = *value * DECAY + now[address].saturating_sub(before[address]) as f32;
+            }
+        }
+        self.last.clone_from(counts);
+    }
+
+    pub fn draw(&mut self) {
+        if !self.window.as_ref().is_some_and(Window::is_open) {
+            self.window = None;
+            return;
+        }
+
+        let width = COLUMNS * CELL;
+        for (address, &heat) in self.heat.iter().enumerate() {
+            let (left, top) = ((address % COLUMNS) * CELL, (address / COLUMNS) * CELL);
+            let colour = colour(heat);
+            // The last row and column of every cell stay dark to separate them
+            for y in top..top + CELL {
+                for x in left..left + CELL {
+                    self.buffer[y * width + x] = if x == left + CELL - 1 || y == top + CELL - 1 { 0 } else { colour };
+                }
+            }
+        }
+
+        if let Some(window) = &mut self.window {
+            let _ = window.update_with_buffer(&self.buffer, width, ROWS * CELL);
+        }
+    }
+}
+
+// A single access is clearly visible, a busy loop is at full brightness.
+fn colour(heat: [f32; 3]) -> u32 {
+    heat.iter().zip([16, 8, 0].iter()).fold(0, |colour, (&value, &shift)| {
+        let base = (BACKGROUND >> shift) & 0xFF;
+        let brightness = base as f32 + (255 - base) as f32 * (1.0 - (-value / 2.0).exp());
+        colour | (brightness.round() as u32) << shift
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn colours() {
+        assert_eq!(BACKGROUND, colour([0.0; 3]));
+        assert_eq!(0xFF_18_18, colour([100.0, 0.0, 0.0]));
+        let single = colour([0.0, 1.0, 0.0]) >> 8 & 0xFF;
+        assert!(single > 0x60 && single < 0xFF);
+    }
+}
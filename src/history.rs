@@ -0,0 +1,264 @@
+// Time travel for the debuggers. While a History is attached, Chip8 records every executed instruction
+// together with the keys and timers it saw, and takes a checkpoint of the whole machine every now and then.
+// Going back loads the last checkpoint before the target and executes the recorded steps again, which
+// ends up in exactly the same state since the checkpoints include the random number generator.
+//
+// Changes from outside, like a debugger writing to memory, can't be executed again. They force a
+// checkpoint before the next instruction, so re-execution never has to cross them.
+
+use std::collections::VecDeque;
+use std::fmt;
+use crate::chip8::{Quirks, Random};
+
+// Instructions between two checkpoints
+const INTERVAL: u64 = 1000;
+// Checkpoints kept by default, older steps are forgotten
+pub const CHECKPOINTS: usize = 300;
+
+// What an instruction saw of the world outside the interpreter, restored before executing it again.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Inputs {
+    // one bit per key
+    pub keys: u16,
+    pub dt: u8,
+    pub st: u8,
+    pub vblank: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Step {
+    pc: u16,
+    opcode: u16,
+    i: u16,
+    // the address of the next instruction, which tells whether FX0A and DXYN had to wait
+    next_pc: u16,
+    inputs: Inputs,
+}
+
+struct Checkpoint {
+    // number of the step executed next
+    step: u64,
+    state: Vec<u8>,
+    rng: Random,
+}
+
+// Something an instruction can write to, see History::last_write.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Location {
+    V(u8),
+    I,
+    Dt,
+    St,
+    Memory(u16),
+}
+
+impl Location {
+    // Parse a location like "v3", "i", "dt", "st" or a memory address like "2a4".
+    pub fn parse(text: &str) -> Option<Location> {
+        let text = text.trim().to_ascii_lowercase();
+        match text.as_str() {
+            "i" => return Some(Location::I),
+            "dt" => return Some(Location::Dt),
+            "st" => return Some(Location::St),
+            _ => (),
+        }
+        if let Some(register) = text.strip_prefix('v').filter(|register| register.len() == 1) {
+            return u8::from_str_radix(register, 16).ok().map(Location::V);
+        }
+        let address = text.strip_prefix("0x").unwrap_or(&text);
+        u16::from_str_radix(address, 16).ok().filter(|&address| address < 4096).map(Location::Memory)
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Location::V(register) => write!(f, "V{:X}", register),
+            Location::I => write!(f, "I"),
+            Location::Dt => write!(f, "DT"),
+            Location::St => write!(f, "ST"),
+            Location::Memory(address) => write!(f, "{:03X}", address),
+        }
+    }
+}
+
+// The instruction that last wrote to a location.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Write {
+    // number of the step, see History::position
+    pub step: u64,
+    pub pc: u16,
+    pub opcode: u16,
+}
+
+pub struct History {
+    checkpoints: VecDeque<Checkpoint>,
+    steps: VecDeque<Step>,
+    // number of the first step in steps, counting from when recording started
+    first: u64,
+    capacity: usize,
+    // whether the machine was changed from outside since the last step
+    changed: bool,
+}
+
+impl History {
+    // Remember up to a number of checkpoints, each of them covering a thousand instructions.
+    pub fn new(checkpoints: usize) -> History {
+        History {
+            checkpoints: VecDeque::new(),
+            steps: VecDeque::new(),
+            first: 0,
+            capacity: checkpoints.max(1),
+            changed: false,
+        }
+    }
+
+    // Number of the step executed next.
+    pub fn position(&self) -> u64 {
+        self.first + self.steps.len() as u64
+    }
+
+    // The earliest step that can be gone back to.
+    pub fn start(&self) -> u64 {
+        self.checkpoints.front().map_or(self.position(), |checkpoint| checkpoint.step)
+    }
+
+    // The machine was changed from outside, see the top of the file.
+    pub fn changed(&mut self) {
+        self.changed = true;
+    }
+
+    // Whether an instruction is about to run without a checkpoint to go back to.
+    pub(crate) fn needs_checkpoint(&self) -> bool {
+        self.changed || self.checkpoints.back().is_none_or(|checkpoint| self.position() - checkpoint.step >= INTERVAL)
+    }
+
+    pub(crate) fn checkpoint(&mut self, state: Vec<u8>, rng: Random) {
+        self.checkpoints.push_back(Checkpoint { step: self.position(), state, rng });
+        self.changed = false;
+        if self.checkpoints.len() > self.capacity {
+            self.checkpoints.pop_front();
+            let start = self.start();
+            while self.first < start {
+                self.steps.pop_front();
+                self.first += 1;
+            }
+        }
+    }
+
+    // Record an instruction before it executes, History::end completes it.
+    pub(crate) fn begin(&mut self, pc: u16, opcode: u16, i: u16, inputs: Inputs) {
+        self.steps.push_back(Step { pc, opcode, i, next_pc: pc, inputs });
+    }
+
+    pub(crate) fn end(&mut self, next_pc: u16) {
+        if let Some(step) = self.steps.back_mut() {
+            step.next_pc = next_pc;
+        }
+    }
+
+    // Where to start executing again to get to a step: the state and random number generator of the last
+    // checkpoint before it, and the inputs of the steps in between and of the step itself.
+    // Forgets everything after the step, which is executed again from there on.
+    pub(crate) fn rewind(&mut self, step: u64) -> Option<(Vec<u8>, Random, Vec<Inputs>)> {
+        if step < self.start() || step >= self.position() {
+            return None;
+        }
+        self.checkpoints.retain(|checkpoint| checkpoint.step <= step);
+        let checkpoint = self.checkpoints.back()?;
+        let inputs = self.steps.range((checkpoint.step - self.first) as usize..=(step - self.first) as usize)
+            .map(|step| step.inputs)
+            .collect();
+        let rewound = (checkpoint.state.clone(), checkpoint.rng.clone(), inputs);
+        // The step itself is recorded again when it executes
+        self.steps.truncate((step - self.first) as usize);
+        // and changes from outside since the last step are undone as well
+        self.changed = false;
+        Some(rewound)
+    }
+
+    // The last step before the current one that executed at one of the addresses.
+    pub fn previous(&self, addresses: impl Fn(u16) -> bool) -> Option<u64> {
+        self.steps.iter().rposition(|step| addresses(step.pc)).map(|index| self.first + index as u64)
+    }
+
+    // The last recorded instruction that wrote to a location, e.g. to find out where a broken I came from.
+    pub fn last_write(&self, location: Location, quirks: Quirks) -> Option<Write> {
+        self.steps.iter().enumerate().rev()
+            .find(|(_, step)| writes(step, location, quirks))
+            .map(|(index, step)| Write { step: self.first + index as u64, pc: step.pc, opcode: step.opcode })
+    }
+}
+
+// Whether an instruction wrote to a location, even if the value stayed the same.
+fn writes(step: &Step, location: Location, quirks: Quirks) -> bool {
+    let opcode = step.opcode;
+    let x = ((opcode & 0x0F00) >> 8) as u8;
+    let nibbles = (opcode >> 12, opcode & 0x000F, opcode & 0x00FF);
+    // FX0A and DXYN with the display wait execute again and again until they are done
+    let done = step.next_pc != step.pc;
+
+    match location {
+        Location::V(register) => match nibbles {
+            (0x6, _, _) | (0x7, _, _) | (0xC, _, _) => register == x,
+            (0x8, 0x0..=0x3, _) => register == x,
+            (0x8, 0x4..=0x7, _) | (0x8, 0xE, _) => register == x || register == 0xF,
+            (0xD, _, _) => register == 0xF && done,
+            (0xF, _, 0x07) => register == x,
+            (0xF, _, 0x0A) => register == x && done,
+            (0xF, _, 0x1E) => register == 0xF,
+            (0xF, _, 0x65) => register <= x,
+            _ => false,
+        },
+        Location::I => match nibbles {
+            (0xA, _, _) | (0xF, _, 0x1E) | (0xF, _, 0x29) => true,
+            (0xF, _, 0x55) | (0xF, _, 0x65) => !quirks.load_store,
+            _ => false,
+        },
+        Location::Dt => nibbles.0 == 0xF && nibbles.2 == 0x15,
+        Location::St => nibbles.0 == 0xF && nibbles.2 == 0x18,
+        Location::Memory(address) => {
+            let length = match nibbles {
+                (0xF, _, 0x33) => 3,
+                (0xF, _, 0x55) => x as u16 + 1,
+                _ => return false,
+            };
+            (step.i..step.i + length).contains(&address)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn locations() {
+        assert_eq!(Some(Location::V(0xA)), Location::parse("VA"));
+        assert_eq!(Some(Location::I), Location::parse("i"));
+        assert_eq!(Some(Location::St), Location::parse(" st "));
+        assert_eq!(Some(Location::Memory(0x2A4)), Location::parse("0x2a4"));
+        assert_eq!(Some(Location::Memory(0x10)), Location::parse("10"));
+        assert_eq!(None, Location::parse("v10"));
+        assert_eq!(None, Location::parse("1000"));
+    }
+
+    #[test]
+    fn writes_by_instruction() {
+        let inputs = Inputs { keys: 0, dt: 0, st: 0, vblank: false };
+        let step = |opcode| Step { pc: 0x200, opcode, i: 0x300, next_pc: 0x202, inputs };
+        let quirks = Quirks::default();
+
+        assert!(writes(&step(0x8124), Location::V(0xF), quirks));
+        assert!(!writes(&step(0x8121), Location::V(0xF), quirks));
+        assert!(writes(&step(0xF265), Location::V(2), quirks));
+        assert!(!writes(&step(0xF265), Location::V(3), quirks));
+        assert!(writes(&step(0xF255), Location::I, quirks));
+        assert!(!writes(&step(0xF255), Location::I, Quirks { load_store: true, ..quirks }));
+        assert!(writes(&step(0xF233), Location::Memory(0x302), quirks));
+        assert!(!writes(&step(0xF233), Location::Memory(0x303), quirks));
+        assert!(writes(&step(0xF315), Location::Dt, quirks));
+        // Still waiting for a key
+        assert!(!writes(&Step { next_pc: 0x200, ..step(0xF00A) }, Location::V(0), quirks));
+    }
+}
@@ -1,73 +1,364 @@
-use minifb::{Key, Scale, Window, WindowOptions};
+use minifb::{Key, KeyRepeat, MouseButton, MouseMode, Scale, ScaleMode, Window, WindowOptions};
+use crusty_8::script::Shape;
+use crate::config::Config;
+use crate::overlay::{self, GLYPH_HEIGHT, GLYPH_WIDTH};
+use crate::palette::{self, Palette};
+use crate::session::Command;
+
+const TITLE: &str = "Crusty-8 (Press ESC to exit)";
+
+const HOTKEYS: [(Key, Command); 6] = [
+    (Key::F3, Command::TogglePause),
+    (Key::F4, Command::Advance),
+    (Key::F5, Command::SoftReset),
+    (Key::F6, Command::HardReset),
+    (Key::F7, Command::ToggleSlowMotion),
+    (Key::F8, Command::ToggleFastForward),
+];
+
+// The keys of the virtual keypad in the order of the original COSMAC VIP keypad.
+const KEYPAD_LAYOUT: [usize; 16] = [
+    0x1, 0x2, 0x3, 0xC,
+    0x4, 0x5, 0x6, 0xD,
+    0x7, 0x8, 0x9, 0xE,
+    0xA, 0x0, 0xB, 0xF,
+];
+
+// How the display is fitted into the window.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scaling {
+    // The largest whole multiple of the display size that fits
+    Integer,
+    // As large as possible while keeping the aspect ratio
+    Aspect,
+}
+
+impl Scaling {
+    pub const NAMES: [&'static str; 2] = ["integer", "aspect"];
+
+    pub fn from_name(name: &str) -> Option<Scaling> {
+        match name {
+            "integer" => Some(Scaling::Integer),
+            "aspect" => Some(Scaling::Aspect),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Scaling::Integer => "integer",
+            Scaling::Aspect => "aspect",
+        }
+    }
+}
 
 pub struct Io {
     pub window: Window,
-    pub framebuffer: [u32; WIDTH * HEIGHT],
-    pub keys: [bool; 16],
+    // the window contents, always as large as the window
+    framebuffer: Vec<u32>,
+    // size of the framebuffer
+    window_size: (usize, usize),
+    key_map: [Key; 16],
+    palette: String,
+    colours: Palette,
+    scale: usize,
+    scaling: Scaling,
+    grid: bool,
+    fullscreen: bool,
+    // show the virtual keypad next to the display
+    keypad: bool,
+    // the keys pressed as of the last call to set_keys
+    pressed: [bool; 16],
 }
 
 impl Io {
-    pub fn new() -> Io {
+    pub fn new(config: &Config, width: usize, height: usize) -> Io {
         Io {
-            keys: [false; 16],
-            framebuffer: [0x00_00_00_00; WIDTH * HEIGHT],
-            window: Window::new(
-                "Crusty-8 (Press ESC to exit)",
-                WIDTH,
-                HEIGHT,
-                WindowOptions {
-                    resize: false,
-                    scale: Scale::X16,
-                    ..WindowOptions::default()
-                },
-            )
-            .unwrap_or_else(|e| {
-                panic!("{}", e);
-            }),
+            framebuffer: Vec::new(),
+            window_size: (0, 0),
+            key_map: config.keys,
+            palette: config.palette.clone(),
+            colours: config.colours,
+            scale: config.scale,
+            scaling: config.scaling,
+            grid: config.grid,
+            fullscreen: false,
+            keypad: config.keypad,
+            pressed: [false; 16],
+            window: open_window(Io::window_width(width, height, config.scale, config.keypad), height * config.scale, false),
         }
     }
 
-    pub fn setup(&mut self, update_rate: u64) {
-        self.window.limit_update_rate(Some(std::time::Duration::from_micros(update_rate)));
+    // The keypad is a square as high as the display.
+    fn window_width(width: usize, height: usize, scale: usize, keypad: bool) -> usize {
+        if keypad { (width + height) * scale } else { width * scale }
     }
 
-    pub fn draw(&mut self, display: &[bool]) {
-        for (i, pixel) in self.framebuffer.iter_mut().enumerate() {
-            *pixel = if display[i] { 0xFF_FF_FF_FF } else { 0x_00_00_00_00 };
+    // The area of the window taken by the keypad as (left, top, size).
+    fn keypad_area(&self) -> Option<(usize, usize, usize)> {
+        let (window_width, window_height) = self.window_size;
+        if !self.keypad {
+            return None;
         }
+        let size = window_height.min(window_width / 3);
+        Some((window_width - size, (window_height - size) / 2, size))
+    }
 
-        self.window.update_with_buffer(&self.framebuffer, WIDTH, HEIGHT).unwrap();
+    // minifb can't switch to real fullscreen, so this replaces the window
+    // with a borderless one that is as large as the screen allows.
+    pub fn toggle_fullscreen(&mut self, width: usize, height: usize) {
+        self.fullscreen = !self.fullscreen;
+        self.window = if self.fullscreen {
+            open_window(Io::window_width(width, height, 1, self.keypad), height, true)
+        } else {
+            open_window(Io::window_width(width, height, self.scale, self.keypad), height * self.scale, false)
+        };
+        if self.fullscreen { self.window.set_position(0, 0) };
     }
 
-    pub fn set_keys(&mut self, keys: &mut [bool]) {
-        for (i, key) in KEY_MAP.iter().enumerate() {
-            if self.window.is_key_down(*key) {
-                keys[i] = true;
+    // Expects the brightness of every pixel between 0.0 and 1.0, see Filter.
+    // The overlay lines are drawn on top, without touching the display.
+    // Shapes drawn by a script go on top of the display, see draw_shapes.
+    pub fn draw(&mut self, display: &[f32], width: usize, height: usize, overlay: &[String], shapes: &[Shape]) {
+        let (window_width, window_height) = self.window.get_size();
+        self.window_size = (window_width, window_height);
+        self.framebuffer.clear();
+        self.framebuffer.resize(window_width * window_height, self.colours[0]);
+
+        let display_area = match self.keypad_area() {
+            Some((keypad_left, _, _)) => (keypad_left, window_height),
+            None => (window_width, window_height),
+        };
+        let (left, top, draw_width, draw_height) = viewport(self.scaling, display_area, (width, height));
+
+        // Grid lines replace the last row and column of every display pixel,
+        // which only looks right if the pixels are large enough.
+        let grid = self.grid && draw_width / width >= 4 && draw_height / height >= 4;
+
+        for y in 0..draw_height {
+            let source_y = y * height / draw_height;
+            let row = (top + y) * window_width + left;
+            let grid_row = grid && (y + 1) * height / draw_height != source_y;
+
+            // Rows showing the same display row are identical, unless there is a grid line.
+            if y > 0 && !grid_row && source_y == (y - 1) * height / draw_height {
+                self.framebuffer.copy_within((row - window_width)..(row - window_width + draw_width), row);
+                continue;
+            }
+
+            for x in 0..draw_width {
+                let source_x = x * width / draw_width;
+                let brightness = display[source_y * width + source_x];
+                let grid_line = grid_row || (grid && (x + 1) * width / draw_width != source_x);
+                self.framebuffer[row + x] = if grid_line {
+                    mix(self.colours[0], self.colours[1], 0.1 + brightness * 0.5)
+                } else {
+                    mix(self.colours[0], self.colours[1], brightness)
+                };
+            }
+        }
+
+        self.draw_shapes(shapes, (left, top, draw_width, draw_height), (width, height));
+        self.draw_keypad();
+        self.draw_overlay(overlay);
+
+        self.window.update_with_buffer(&self.framebuffer, window_width, window_height).unwrap();
+    }
+
+    // Pressed keys are highlighted, every key shows its hex value and the keyboard key it is bound to.
+    fn draw_keypad(&mut self) {
+        let (left, top, size) = match self.keypad_area() {
+            Some(area) => area,
+            None => return,
+        };
+        let cell = size / 4;
+        // Too small for the labels to fit into a key
+        if cell < GLYPH_HEIGHT + 2 {
+            return;
+        }
+        let margin = (cell / 12).max(1);
+        let (background, foreground) = (self.colours[0], self.colours[1]);
+
+        for (position, &key) in KEYPAD_LAYOUT.iter().enumerate() {
+            let x = left + (position % 4) * cell;
+            let y = top + (position / 4) * cell;
+            let (fill, text) = if self.pressed[key] {
+                (foreground, background)
             } else {
-                keys[i] = false;
+                (mix(background, foreground, 0.2), foreground)
+            };
+            self.fill(x + margin, y + margin, cell - 2 * margin, cell - 2 * margin, fill);
+
+            let digit = (cell / 12).max(1);
+            self.draw_string(&format!("{:X}", key), x + (cell - GLYPH_WIDTH * digit) / 2, y + cell / 5, digit, text);
+
+            let binding = format!("{:?}", self.key_map[key]);
+            let binding = binding.trim_start_matches("Key");
+            let label = (cell / 36).max(1);
+            let label_width = binding.len() * (GLYPH_WIDTH + 1) * label;
+            let label_x = x + cell.saturating_sub(label_width) / 2;
+            let label_y = (y + cell - cell / 5).saturating_sub(GLYPH_HEIGHT * label);
+            self.draw_string(binding, label_x, label_y, label, text);
+        }
+    }
+
+    // Script shapes are positioned in display pixels, so they stay on the sprites they mark whatever the window size.
+    // Text uses the size of the overlay.
+    fn draw_shapes(&mut self, shapes: &[Shape], viewport: (usize, usize, usize, usize), display: (usize, usize)) {
+        let (left, top, draw_width, draw_height) = viewport;
+        let x = |x: i64| (left as i64 + x * draw_width as i64 / display.0 as i64).max(0) as usize;
+        let y = |y: i64| (top as i64 + y * draw_height as i64 / display.1 as i64).max(0) as usize;
+        let size = (self.window_size.1 / 160).max(1);
+
+        for shape in shapes {
+            match shape {
+                Shape::Text { x: left, y: top, text, colour } => self.draw_string(text, x(*left), y(*top), size, *colour),
+                Shape::Rect { x: left, y: top, width, height, colour } => {
+                    let (right, bottom) = (x(left + width), y(top + height));
+                    let (left, top) = (x(*left), y(*top));
+                    self.fill(left, top, right.saturating_sub(left), bottom.saturating_sub(top), *colour);
+                }
             }
         }
     }
+
+    // White text with a black shadow in the top left corner, readable with every palette.
+    fn draw_overlay(&mut self, lines: &[String]) {
+        let size = (self.window_size.1 / 160).max(1);
+        let line_height = (GLYPH_HEIGHT + 2) * size;
+
+        for (line, text) in lines.iter().enumerate() {
+            let top = size * 2 + line * line_height;
+            self.draw_string(text, size * 3, top + size, size, 0x00_00_00);
+            self.draw_string(text, size * 2, top, size, 0xFF_FF_FF);
+        }
+    }
+
+    // Draw text with the built-in font, every font pixel is size x size window pixels.
+    fn draw_string(&mut self, text: &str, left: usize, top: usize, size: usize, colour: u32) {
+        overlay::draw_text(&mut self.framebuffer, self.window_size, text, left, top, size, colour);
+    }
+
+    // Fill a rectangle, clipped to the window.
+    fn fill(&mut self, left: usize, top: usize, width: usize, height: usize, colour: u32) {
+        let (window_width, window_height) = self.window_size;
+        for y in top..(top + height).min(window_height) {
+            for x in left..(left + width).min(window_width) {
+                self.framebuffer[y * window_width + x] = colour;
+            }
+        }
+    }
+
+    // Switch to the next palette preset, dropping any custom colours.
+    // Returns the name of the new palette.
+    pub fn cycle_palette(&mut self) -> &str {
+        let (name, colours) = palette::next(&self.palette);
+        self.palette = name.to_string();
+        self.colours = colours;
+        &self.palette
+    }
+
+    // The session commands whose hotkeys were pressed since the last update.
+    pub fn commands(&self) -> Vec<Command> {
+        HOTKEYS.iter()
+            .filter(|(key, _)| self.window.is_key_pressed(*key, KeyRepeat::No))
+            .map(|(_, command)| *command)
+            .collect()
+    }
+
+    // The keypad key under the mouse while the left button is held.
+    fn clicked_key(&self) -> Option<usize> {
+        let (left, top, size) = self.keypad_area()?;
+        if !self.window.get_mouse_down(MouseButton::Left) {
+            return None;
+        }
+        let (x, y) = self.window.get_unscaled_mouse_pos(MouseMode::Discard)?;
+        let (x, y) = (x as usize, y as usize);
+        if x < left || y < top || x >= left + size || y >= top + size {
+            return None;
+        }
+        let cell = (size / 4).max(1);
+        KEYPAD_LAYOUT.get(((y - top) / cell) * 4 + (x - left) / cell).copied()
+    }
+
+    pub fn set_keys(&mut self, keys: &mut [bool]) {
+        let clicked = self.clicked_key();
+        for (i, key) in self.key_map.iter().enumerate() {
+            keys[i] = self.window.is_key_down(*key) || clicked == Some(i);
+        }
+        self.pressed.copy_from_slice(keys);
+    }
 }
 
-pub const WIDTH: usize = 64;
-pub const HEIGHT: usize = 32;
-
-const KEY_MAP: [Key; 16] = [
-Key::Key1,
-Key::Key2,
-Key::Key3,
-Key::Key4,
-Key::Q,
-Key::W,
-Key::E,
-Key::R,
-Key::A,
-Key::S,
-Key::D,
-Key::F,
-Key::Y,
-Key::X,
-Key::C,
-Key::V,
-];
+// The area of the window the display is drawn to as (left, top, width, height).
+fn viewport(scaling: Scaling, window: (usize, usize), display: (usize, usize)) -> (usize, usize, usize, usize) {
+    let ((window_width, window_height), (width, height)) = (window, display);
+    let (draw_width, draw_height) = match scaling {
+        Scaling::Integer => {
+            let scale = (window_width / width).min(window_height / height).max(1);
+            (width * scale, height * scale)
+        }
+        Scaling::Aspect => {
+            let scale = (window_width as f32 / width as f32).min(window_height as f32 / height as f32);
+            ((width as f32 * scale) as usize, (height as f32 * scale) as usize)
+        }
+    };
+    let (draw_width, draw_height) = (draw_width.min(window_width), draw_height.min(window_height));
+    ((window_width - draw_width) / 2, (window_height - draw_height) / 2, draw_width, draw_height)
+}
+
+fn open_window(width: usize, height: usize, fullscreen: bool) -> Window {
+    let mut window = Window::new(
+        TITLE,
+        width,
+        height,
+        WindowOptions {
+            borderless: fullscreen,
+            title: !fullscreen,
+            resize: true,
+            scale: if fullscreen { Scale::FitScreen } else { Scale::X1 },
+            // The contents are already scaled by Io::draw
+            scale_mode: ScaleMode::UpperLeft,
+        },
+    )
+    .unwrap_or_else(|e| {
+        panic!("{}", e);
+    });
+    // Frames are paced by the run loop.
+    window.limit_update_rate(None);
+    window
+}
+
+// Blend two 0RGB colours, amount 0.0 is all background and 1.0 all foreground.
+fn mix(background: u32, foreground: u32, amount: f32) -> u32 {
+    let mut colour = 0;
+    for shift in [0, 8, 16].iter() {
+        let from = ((background >> shift) & 0xFF) as f32;
+        let to = ((foreground >> shift) & 0xFF) as f32;
+        colour |= ((from + (to - from) * amount).round() as u32) << shift;
+    }
+    colour
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn letterboxing() {
+        assert_eq!((0, 0, 640, 320), viewport(Scaling::Integer, (640, 320), (64, 32)));
+        assert_eq!((32, 6, 576, 288), viewport(Scaling::Integer, (640, 300), (64, 32)));
+        assert_eq!((20, 0, 600, 300), viewport(Scaling::Aspect, (640, 300), (64, 32)));
+        // hires displays get the same space
+        assert_eq!((0, 0, 640, 320), viewport(Scaling::Integer, (640, 320), (128, 64)));
+        // windows smaller than the display get squashed
+        assert_eq!((0, 0, 40, 20), viewport(Scaling::Integer, (40, 20), (64, 32)));
+    }
+
+    #[test]
+    fn mixing() {
+        assert_eq!(0x00_00_00, mix(0x00_00_00, 0xFF_FF_FF, 0.0));
+        assert_eq!(0x80_40_00, mix(0x00_00_00, 0xFF_80_00, 0.5));
+        assert_eq!(0xFF_80_00, mix(0x00_00_00, 0xFF_80_00, 1.0));
+    }
+}
@@ -0,0 +1,16 @@
+pub mod cdp1802;
+pub mod cheat;
+pub mod chip8;
+pub mod dap;
+pub mod database;
+pub mod diff;
+pub mod disassembler;
+pub mod gdb;
+pub mod history;
+pub mod libretro;
+pub mod machine;
+pub mod profile;
+pub mod script;
+pub mod sprites;
+pub mod trace;
+pub mod vip;
@@ -0,0 +1,513 @@
+// A libretro core wrapping the interpreter, so it can be used from RetroArch and friends.
+// Only the parts of libretro.h that are needed here are declared.
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_uint, c_void};
+use std::panic::AssertUnwindSafe;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use crate::chip8::{Chip8, Quirks, Timing, HEIGHT, RNG_STATE_SIZE, STATE_SIZE, WIDTH};
+use crate::database;
+
+const RETRO_API_VERSION: c_uint = 1;
+
+const RETRO_DEVICE_JOYPAD: c_uint = 1;
+
+const RETRO_ENVIRONMENT_SHUTDOWN: c_uint = 7;
+const RETRO_ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+const RETRO_ENVIRONMENT_GET_VARIABLE: c_uint = 15;
+const RETRO_ENVIRONMENT_SET_VARIABLES: c_uint = 16;
+const RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE: c_uint = 17;
+
+const RETRO_PIXEL_FORMAT_XRGB8888: c_uint = 1;
+const RETRO_REGION_NTSC: c_uint = 0;
+
+const FPS: f64 = 60.0;
+const SAMPLE_RATE: f64 = 44100.0;
+const SAMPLES_PER_FRAME: usize = (SAMPLE_RATE / FPS) as usize;
+const TONE_PERIOD: u32 = (SAMPLE_RATE / 440.0) as u32;
+const VOLUME: i16 = 0x1000;
+
+// RetroPad button id -> hex keypad key.
+// The d-pad maps to the 2/4/6/8 cross most games use for movement,
+// A to 5 which is the usual action key.
+const JOYPAD_MAP: [(c_uint, usize); 16] = [
+    (4, 0x2),  // Up
+    (5, 0x8),  // Down
+    (6, 0x4),  // Left
+    (7, 0x6),  // Right
+    (8, 0x5),  // A
+    (0, 0x0),  // B
+    (9, 0xA),  // X
+    (1, 0xB),  // Y
+    (10, 0x1), // L
+    (11, 0x3), // R
+    (12, 0x7), // L2
+    (13, 0x9), // R2
+    (14, 0xC), // L3
+    (15, 0xD), // R3
+    (2, 0xE),  // Select
+    (3, 0xF),  // Start
+];
+
+#[repr(C)]
+pub struct RetroSystemInfo {
+    pub library_name: *const c_char,
+    pub library_version: *const c_char,
+    pub valid_extensions: *const c_char,
+    pub need_fullpath: bool,
+    pub block_extract: bool,
+}
+
+#[repr(C)]
+pub struct RetroGameGeometry {
+    pub base_width: c_uint,
+    pub base_height: c_uint,
+    pub max_width: c_uint,
+    pub max_height: c_uint,
+    pub aspect_ratio: f32,
+}
+
+#[repr(C)]
+pub struct RetroSystemTiming {
+    pub fps: f64,
+    pub sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct RetroSystemAvInfo {
+    pub geometry: RetroGameGeometry,
+    pub timing: RetroSystemTiming,
+}
+
+#[repr(C)]
+pub struct RetroGameInfo {
+    pub path: *const c_char,
+    pub data: *const c_void,
+    pub size: usize,
+    pub meta: *const c_char,
+}
+
+#[repr(C)]
+pub struct RetroVariable {
+    pub key: *const c_char,
+    pub value: *const c_char,
+}
+
+pub type RetroEnvironment = extern "C" fn(cmd: c_uint, data: *mut c_void) -> bool;
+pub type RetroVideoRefresh = extern "C" fn(data: *const c_void, width: c_uint, height: c_uint, pitch: usize);
+pub type RetroAudioSample = extern "C" fn(left: i16, right: i16);
+pub type RetroAudioSampleBatch = extern "C" fn(data: *const i16, frames: usize) -> usize;
+pub type RetroInputPoll = extern "C" fn();
+pub type RetroInputState = extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16;
+
+#[derive(Clone, Copy)]
+struct Callbacks {
+    environment: Option<RetroEnvironment>,
+    video_refresh: Option<RetroVideoRefresh>,
+    audio_sample_batch: Option<RetroAudioSampleBatch>,
+    input_poll: Option<RetroInputPoll>,
+    input_state: Option<RetroInputState>,
+}
+
+struct Core {
+    chip8: Chip8,
+    rom: Vec<u8>,
+    timing: Timing,
+    quirks: Quirks,
+    // what the auto options stand for, from the rom database or the defaults of the desktop build
+    default_timing: Timing,
+    default_quirks: Quirks,
+    video: [u32; WIDTH * HEIGHT],
+    audio: [i16; SAMPLES_PER_FRAME * 2],
+    // position within the square wave
+    phase: u32,
+}
+
+impl Core {
+    fn new(rom: Vec<u8>) -> Core {
+        let entry = database::lookup(&rom);
+        let default_timing = entry.and_then(|entry| entry.timing()).unwrap_or(Timing::Instructions(10));
+        let default_quirks = Quirks::from_profile(entry.map_or(Quirks::DEFAULT_PROFILE, |entry| entry.quirks())).unwrap_or_default();
+        let mut core = Core {
+            chip8: Chip8::new_with_state(),
+            rom,
+            timing: default_timing,
+            quirks: default_quirks,
+            default_timing,
+            default_quirks,
+            video: [0; WIDTH * HEIGHT],
+            audio: [0; SAMPLES_PER_FRAME * 2],
+            phase: 0,
+        };
+        core.update_options();
+        core.reset();
+        core
+    }
+
+    fn reset(&mut self) {
+        self.chip8 = Chip8::new_with_state();
+        self.chip8.quirks = self.quirks;
+        self.chip8.load_bytes(&self.rom);
+    }
+
+    // Options set to auto keep the settings from the rom database.
+    fn update_options(&mut self) {
+        self.timing = match get_variable(b"crusty8_speed\0").and_then(|v| v.parse().ok()) {
+            Some(speed) => Timing::Instructions(speed),
+            None => self.default_timing,
+        };
+        if get_variable(b"crusty8_timing\0").as_deref() == Some("vip") {
+            self.timing = Timing::Vip;
+        }
+        let enabled = |key: &[u8], default: bool| match get_variable(key).as_deref() {
+            Some("enabled") => true,
+            Some("disabled") => false,
+            _ => default,
+        };
+        let defaults = self.default_quirks;
+        self.quirks = Quirks {
+            shift: enabled(b"crusty8_quirk_shift\0", defaults.shift),
+            load_store: enabled(b"crusty8_quirk_load_store\0", defaults.load_store),
+            jump: enabled(b"crusty8_quirk_jump\0", defaults.jump),
+            display_wait: enabled(b"crusty8_quirk_display_wait\0", defaults.display_wait),
+            wrap: enabled(b"crusty8_quirk_wrap\0", defaults.wrap),
+        };
+        self.chip8.quirks = self.quirks;
+    }
+
+    fn run_frame(&mut self, callbacks: Callbacks) {
+        if let Some(input_poll) = callbacks.input_poll { input_poll() };
+        if let Some(input_state) = callbacks.input_state {
+            for &(button, key) in JOYPAD_MAP.iter() {
+                self.chip8.keys[key] = input_state(0, RETRO_DEVICE_JOYPAD, 0, button) != 0;
+            }
+        }
+
+        self.chip8.run_frame(self.timing);
+
+        for (i, pixel) in self.video.iter_mut().enumerate() {
+            *pixel = if self.chip8.framebuffer[i] { 0x00_FF_FF_FF } else { 0x00_00_00_00 };
+        }
+        if let Some(video_refresh) = callbacks.video_refresh {
+            let pitch = WIDTH * std::mem::size_of::<u32>();
+            video_refresh(self.video.as_ptr() as *const c_void, WIDTH as c_uint, HEIGHT as c_uint, pitch);
+        }
+
+        // A simple square wave while the sound timer is running.
+        let sound = self.chip8.sound_active();
+        for frame in self.audio.chunks_mut(2) {
+            let sample = if !sound {
+                0
+            } else if self.phase < TONE_PERIOD / 2 {
+                VOLUME
+            } else {
+                -VOLUME
+            };
+            frame[0] = sample;
+            frame[1] = sample;
+            self.phase = (self.phase + 1) % TONE_PERIOD;
+        }
+        if let Some(audio_sample_batch) = callbacks.audio_sample_batch {
+            audio_sample_batch(self.audio.as_ptr(), SAMPLES_PER_FRAME);
+        }
+    }
+}
+
+static CALLBACKS: Mutex<Callbacks> = Mutex::new(Callbacks {
+    environment: None,
+    video_refresh: None,
+    audio_sample_batch: None,
+    input_poll: None,
+    input_state: None,
+});
+
+static CORE: Mutex<Option<Core>> = Mutex::new(None);
+
+// The core even after a panic, see guard.
+fn core() -> MutexGuard<'static, Option<Core>> {
+    CORE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Run an entry point without letting a panic unwind into the frontend, which would abort it.
+// Roms can crash the interpreter, e.g. by returning with an empty stack, and that stops the core instead.
+fn guard<T>(failed: T, entry: impl FnOnce() -> T) -> T {
+    match std::panic::catch_unwind(AssertUnwindSafe(entry)) {
+        Ok(result) => result,
+        Err(_) => {
+            *core() = None;
+            CORE.clear_poison();
+            environment(RETRO_ENVIRONMENT_SHUTDOWN, std::ptr::null_mut());
+            failed
+        }
+    }
+}
+
+fn callbacks() -> Callbacks {
+    *CALLBACKS.lock().unwrap()
+}
+
+fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+    match callbacks().environment {
+        Some(environment) => environment(cmd, data),
+        None => false,
+    }
+}
+
+// Ask the frontend for the current value of a core option.
+fn get_variable(key: &[u8]) -> Option<String> {
+    let mut variable = RetroVariable {
+        key: key.as_ptr() as *const c_char,
+        value: std::ptr::null(),
+    };
+    if !environment(RETRO_ENVIRONMENT_GET_VARIABLE, &mut variable as *mut _ as *mut c_void) || variable.value.is_null() {
+        return None;
+    }
+    let value = unsafe { CStr::from_ptr(variable.value) };
+    Some(value.to_string_lossy().into_owned())
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(callback: RetroEnvironment) {
+    CALLBACKS.lock().unwrap().environment = Some(callback);
+
+    // The first value of every option is its default, auto takes the setting from the rom database.
+    let variables = [
+        RetroVariable {
+            key: b"crusty8_speed\0".as_ptr() as *const c_char,
+            value: b"Instructions per frame (auto uses the rom database); auto|5|10|15|20|30|50|100|200\0".as_ptr() as *const c_char,
+        },
+        RetroVariable {
+            key: b"crusty8_timing\0".as_ptr() as *const c_char,
+            value: b"Timing (vip charges the COSMAC VIP cycle cost of every instruction); fixed|vip\0".as_ptr() as *const c_char,
+        },
+        RetroVariable {
+            key: b"crusty8_quirk_shift\0".as_ptr() as *const c_char,
+            value: b"Shift quirk (8XY6/8XYE shift VX); auto|disabled|enabled\0".as_ptr() as *const c_char,
+        },
+        RetroVariable {
+            key: b"crusty8_quirk_load_store\0".as_ptr() as *const c_char,
+            value: b"Load/store quirk (FX55/FX65 leave I unchanged); auto|disabled|enabled\0".as_ptr() as *const c_char,
+        },
+        RetroVariable {
+            key: b"crusty8_quirk_jump\0".as_ptr() as *const c_char,
+            value: b"Jump quirk (BXNN jumps to XNN + VX); auto|disabled|enabled\0".as_ptr() as *const c_char,
+        },
+        RetroVariable {
+            key: b"crusty8_quirk_display_wait\0".as_ptr() as *const c_char,
+            value: b"Display wait (DXYN waits for the next frame); auto|disabled|enabled\0".as_ptr() as *const c_char,
+        },
+        RetroVariable {
+            key: b"crusty8_quirk_wrap\0".as_ptr() as *const c_char,
+            value: b"Wrap quirk (sprites wrap around instead of being clipped); auto|disabled|enabled\0".as_ptr() as *const c_char,
+        },
+        RetroVariable { key: std::ptr::null(), value: std::ptr::null() },
+    ];
+    callback(RETRO_ENVIRONMENT_SET_VARIABLES, variables.as_ptr() as *mut c_void);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(callback: RetroVideoRefresh) {
+    CALLBACKS.lock().unwrap().video_refresh = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_callback: RetroAudioSample) {
+    // Audio is submitted in batches, see retro_set_audio_sample_batch.
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(callback: RetroAudioSampleBatch) {
+    CALLBACKS.lock().unwrap().audio_sample_batch = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(callback: RetroInputPoll) {
+    CALLBACKS.lock().unwrap().input_poll = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(callback: RetroInputState) {
+    CALLBACKS.lock().unwrap().input_state = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {
+    let mut format = RETRO_PIXEL_FORMAT_XRGB8888;
+    environment(RETRO_ENVIRONMENT_SET_PIXEL_FORMAT, &mut format as *mut _ as *mut c_void);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    *core() = None;
+}
+
+/// # Safety
+/// `info` must point to a writable `retro_system_info`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut RetroSystemInfo) {
+    *info = RetroSystemInfo {
+        library_name: b"Crusty-8\0".as_ptr() as *const c_char,
+        library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+        valid_extensions: b"ch8|c8|rom\0".as_ptr() as *const c_char,
+        need_fullpath: false,
+        block_extract: false,
+    };
+}
+
+/// # Safety
+/// `info` must point to a writable `retro_system_av_info`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut RetroSystemAvInfo) {
+    *info = RetroSystemAvInfo {
+        geometry: RetroGameGeometry {
+            base_width: WIDTH as c_uint,
+            base_height: HEIGHT as c_uint,
+            max_width: WIDTH as c_uint,
+            max_height: HEIGHT as c_uint,
+            aspect_ratio: WIDTH as f32 / HEIGHT as f32,
+        },
+        timing: RetroSystemTiming { fps: FPS, sample_rate: SAMPLE_RATE },
+    };
+}
+
+/// # Safety
+/// `game` must be null or point to a valid `retro_game_info` whose `data` holds `size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const RetroGameInfo) -> bool {
+    if game.is_null() || (*game).data.is_null() {
+        return false;
+    }
+    let rom = std::slice::from_raw_parts((*game).data as *const u8, (*game).size);
+    if rom.len() > 4096 - 0x200 {
+        return false;
+    }
+
+    guard(false, || {
+        *core() = Some(Core::new(rom.to_vec()));
+        true
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(_game_type: c_uint, _info: *const RetroGameInfo, _num_info: usize) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    *core() = None;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    guard((), || {
+        if let Some(core) = core().as_mut() {
+            core.reset();
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    let mut updated = false;
+    environment(RETRO_ENVIRONMENT_GET_VARIABLE_UPDATE, &mut updated as *mut _ as *mut c_void);
+
+    guard((), || {
+        if let Some(core) = core().as_mut() {
+            if updated { core.update_options() };
+            core.run_frame(callbacks());
+        }
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    RETRO_REGION_NTSC
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    // The random number generator follows the machine, so frames replayed after a restore stay the same
+    STATE_SIZE + RNG_STATE_SIZE
+}
+
+/// # Safety
+/// `data` must be writable for `size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    guard(false, || match core().as_ref() {
+        Some(core) if size >= retro_serialize_size() => {
+            let mut state = core.chip8.save_state();
+            state.extend(core.chip8.save_rng());
+            std::ptr::copy_nonoverlapping(state.as_ptr(), data as *mut u8, state.len());
+            true
+        }
+        _ => false,
+    })
+}
+
+/// # Safety
+/// `data` must be readable for `size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    guard(false, || match core().as_mut() {
+        Some(core) if size == retro_serialize_size() => {
+            let (state, rng) = std::slice::from_raw_parts(data as *const u8, size).split_at(STATE_SIZE);
+            core.chip8.load_state(state) && core.chip8.load_rng(rng)
+        }
+        _ => false,
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_set(_index: c_uint, _enabled: bool, _code: *const c_char) {}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(_id: c_uint) -> *mut c_void {
+    std::ptr::null_mut()
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(_id: c_uint) -> usize {
+    0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn settings_from_database() {
+        let core = Core::new(std::fs::read("roms/games/Blinky [Hans Christian Egeberg, 1991].ch8").unwrap());
+        assert_eq!(Quirks::from_profile("chip48").unwrap(), core.chip8.quirks);
+        assert_eq!(Timing::Instructions(15), core.timing);
+
+        let core = Core::new(vec![0x12, 0x00]);
+        assert_eq!(Quirks::from_profile(Quirks::DEFAULT_PROFILE).unwrap(), core.chip8.quirks);
+        assert_eq!(Timing::Instructions(10), core.timing);
+    }
+
+    #[test]
+    fn crash_stops_the_core() {
+        // Returns with an empty stack
+        let rom = [0x00, 0xEE];
+        let game = RetroGameInfo { path: std::ptr::null(), data: rom.as_ptr() as *const c_void, size: rom.len(), meta: std::ptr::null() };
+        assert!(unsafe { retro_load_game(&game) });
+        retro_run();
+        assert!(core().is_none());
+        // and later calls don't do anything
+        retro_run();
+        retro_reset();
+    }
+}
@@ -0,0 +1,89 @@
+use crate::chip8::{AccessCounts, Chip8, Timing};
+
+// Snapshots cover the memory from 0x200 up to here, above it the COSMAC VIP keeps the interpreter's data.
+pub const SNAPSHOT_END: usize = 0xE90;
+
+// The CHIP-8 state of a machine, independent of how it is emulated.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub pc: u16,
+    pub i: u16,
+    pub v: [u8; 16],
+    // the program area from 0x200 to SNAPSHOT_END
+    pub memory: Vec<u8>,
+    pub framebuffer: Vec<bool>,
+}
+
+// What a frontend needs from an emulated machine.
+// Both the high-level interpreter and the COSMAC VIP implement it, so they can run the same rom.
+pub trait Machine {
+    // Emulate one 60 Hz frame, see Chip8::run_frame.
+    fn run_frame(&mut self, timing: Timing) -> (u64, bool);
+    // State of the hex keypad
+    fn keys(&mut self) -> &mut [bool; 16];
+    // WIDTH * HEIGHT pixels, row by row
+    fn framebuffer(&self) -> &[bool];
+    fn sound_active(&self) -> bool;
+    // Whether the program is blocked waiting for a key, if the machine can tell
+    fn waiting_for_key(&self) -> bool;
+    // Restart the program but keep the memory
+    fn reset(&mut self);
+    fn save_state(&self) -> Vec<u8>;
+    fn load_state(&mut self, state: &[u8]) -> bool;
+    fn snapshot(&self) -> Snapshot;
+    // Run the CHIP-8 instruction the machine is in the middle of to its end, so the snapshot shows the state
+    // between two instructions. Returns false if that didn't happen, e.g. while waiting for a key.
+    fn finish_instruction(&mut self) -> bool {
+        true
+    }
+    // Per-address memory accesses, if they are counted
+    fn access_counts(&self) -> Option<&AccessCounts>;
+    // The high-level interpreter for debugging tools, None for other backends
+    fn chip8(&mut self) -> Option<&mut Chip8>;
+}
+
+impl Machine for Chip8 {
+    fn run_frame(&mut self, timing: Timing) -> (u64, bool) {
+        Chip8::run_frame(self, timing)
+    }
+
+    fn keys(&mut self) -> &mut [bool; 16] {
+        &mut self.keys
+    }
+
+    fn framebuffer(&self) -> &[bool] {
+        &self.framebuffer
+    }
+
+    fn sound_active(&self) -> bool {
+        Chip8::sound_active(self)
+    }
+
+    fn waiting_for_key(&self) -> bool {
+        self.key_wait().is_some()
+    }
+
+    fn reset(&mut self) {
+        Chip8::reset(self)
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        Chip8::save_state(self)
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> bool {
+        Chip8::load_state(self, state)
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Chip8::snapshot(self)
+    }
+
+    fn access_counts(&self) -> Option<&AccessCounts> {
+        Chip8::access_counts(self)
+    }
+
+    fn chip8(&mut self) -> Option<&mut Chip8> {
+        Some(self)
+    }
+}
@@ -1,25 +1,164 @@
-mod chip8;
+mod config;
+mod debugger;
+mod filter;
+mod heatmap;
 mod io;
+mod overlay;
+mod palette;
+mod session;
 
-use std::fs::File;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver};
+use std::time::Instant;
 use structopt::StructOpt;
-use chip8::Chip8;
+use crusty_8::cheat::{Cheats, Console};
+use crusty_8::chip8::{Chip8, WIDTH, HEIGHT};
+use crusty_8::database;
+use crusty_8::gdb::GdbServer;
+use crusty_8::history::{self, History};
+use crusty_8::machine::Machine;
+use crusty_8::profile::Profiler;
+use crusty_8::script::Script;
+use crusty_8::trace::{self, Tracer};
+use crusty_8::vip::Vip;
+use config::{Config, Profile};
+use debugger::Debugger;
+use filter::Filter;
+use heatmap::Heatmap;
 use io::Io;
+use overlay::Overlay;
+use session::{Command, Session, FRAME};
 
 #[derive(StructOpt)]
 #[structopt(about = "My supersweet Chip-8 interpreter.")]
 struct Settings {
     #[structopt(short, long, parse(from_os_str))]
     /// Specifies a path to a chip-8 rom
-    path: std::path::PathBuf,
+    path: PathBuf,
 
-    #[structopt(short, long, default_value = "1660")]
-    /// Specifies the MAXIMUM refresh rate in microseconds, see --authentic-drawing
-    update_rate: u64,
+    #[structopt(short, long)]
+    /// Specifies the time per instruction in microseconds, which sets the speed [default: 1660]
+    update_rate: Option<u64>,
+
+    #[structopt(long)]
+    /// Sets the timing model, fixed uses the update rate while vip charges every instruction its COSMAC VIP cycle cost [default: fixed]
+    timing: Option<String>,
+
+    #[structopt(long)]
+    /// Sets the speed multiplier of fast forward (F8), 0 runs as fast as possible [default: 4]
+    fast_forward: Option<u32>,
 
     /// Draws only when the actual instruction was executed
     #[structopt(short, long = "authentic")]
     authentic_drawing: bool,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Logs every executed instruction to a file
+    trace: Option<PathBuf>,
+
+    #[structopt(long, default_value = "text")]
+    /// Sets the format of the trace, either text or binary
+    trace_format: String,
+
+    #[structopt(long, default_value = "000-FFF")]
+    /// Only traces instructions in this range of addresses, given in hex
+    trace_range: String,
+
+    #[structopt(long)]
+    /// Keeps only the last N instructions and writes them when the interpreter crashes
+    trace_ring: Option<usize>,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Counts executed instructions and writes a report of the hot spots, the call tree and the opcodes at exit
+    profile: Option<PathBuf>,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Also writes the profile as folded stacks for flamegraph tools
+    profile_folded: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// Opens a debugger window with disassembly, registers, stack, keypad and memory, the program starts halted
+    debugger: bool,
+
+    #[structopt(long)]
+    /// Listens for a gdb remote protocol connection on this local port, the program halts when a debugger attaches
+    gdb: Option<u16>,
+
+    #[structopt(long)]
+    /// Opens a window showing recent memory reads, writes and instruction fetches
+    heatmap: bool,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Runs a Rhai script that can read and change the machine, press keys and draw on the display
+    script: Option<PathBuf>,
+
+    #[structopt(long)]
+    /// Applies the cheats saved for the rom and reads cheat commands like search and freeze from the terminal, F9 toggles the cheats
+    cheats: bool,
+
+    #[structopt(long, parse(from_os_str))]
+    /// Specifies a config file [default: ~/.config/crusty-8/config.toml]
+    config: Option<PathBuf>,
+
+    /// Prints the effective settings for the rom and exits
+    #[structopt(long)]
+    print_config: bool,
+
+    #[structopt(long)]
+    /// Sets the keyboard layout, either qwertz or qwerty [default: qwertz]
+    layout: Option<String>,
+
+    #[structopt(long)]
+    /// Sets the backend, either chip8 or vip, which emulates the COSMAC VIP running its original interpreter [default: chip8]
+    backend: Option<String>,
+
+    #[structopt(long)]
+    /// Specifies a dump of the original 512 byte CHIP-8 interpreter for the vip backend
+    interpreter: Option<String>,
+
+    #[structopt(long)]
+    /// Sets the quirk profile, either modern, vip or chip48, optionally followed by single quirks like vip,wrap [default: modern]
+    quirks: Option<String>,
+
+    #[structopt(long)]
+    /// Sets the initial window scale, from 1 to 64 [default: 16]
+    scale: Option<usize>,
+
+    #[structopt(long)]
+    /// Sets how the display fits into a resized window, either integer or aspect [default: integer]
+    scaling: Option<String>,
+
+    #[structopt(long)]
+    /// Draws lines between the pixels
+    grid: bool,
+
+    #[structopt(long)]
+    /// Shows frame rate, speed and other status information, F1 toggles it at runtime
+    overlay: bool,
+
+    #[structopt(long)]
+    /// Shows the keypad next to the display, its keys can be clicked with the mouse
+    keypad: bool,
+
+    #[structopt(long)]
+    /// Sets the colour palette, press F2 to cycle through them at runtime [default: classic]
+    palette: Option<String>,
+
+    #[structopt(long)]
+    /// Overrides the colour of lit pixels as #RRGGBB
+    foreground: Option<String>,
+
+    #[structopt(long)]
+    /// Overrides the colour of unlit pixels as #RRGGBB
+    background: Option<String>,
+
+    #[structopt(long)]
+    /// Reduces flicker, either none, phosphor or blend [default: none]
+    filter: Option<String>,
+
+    #[structopt(long)]
+    /// Sets how much brightness a pixel loses per frame with the phosphor filter, from 0 to 1 [default: 0.3]
+    decay: Option<f32>,
 }
 
 fn main() {
@@ -27,27 +166,367 @@ fn main() {
     run(args);
 }
 
-fn run (args: Settings) {
-    let f = File::open(args.path).unwrap();
+// Merge the rom database, the config file and the command line, which always has the last word.
+fn configure(args: &Settings, rom: &[u8]) -> Result<Config, String> {
+    let rom_hash = database::rom_hash(rom);
+
+    let mut layers: Vec<Profile> = database::lookup(rom).map(Profile::from_database).into_iter().collect();
+    if let Some(path) = args.config.clone().or_else(config::default_path) {
+        layers.extend(config::load(&path, &rom_hash)?);
+    }
+    layers.push(Profile {
+        layout: args.layout.clone(),
+        keys: None,
+        palette: args.palette.clone(),
+        foreground: args.foreground.clone(),
+        background: args.background.clone(),
+        filter: args.filter.clone(),
+        decay: args.decay,
+        update_rate: args.update_rate,
+        timing: args.timing.clone(),
+        backend: args.backend.clone(),
+        interpreter: args.interpreter.clone(),
+        fast_forward: args.fast_forward,
+        quirks: args.quirks.clone(),
+        scale: args.scale,
+        scaling: args.scaling.clone(),
+        grid: if args.grid { Some(true) } else { None },
+        overlay: if args.overlay { Some(true) } else { None },
+        keypad: if args.keypad { Some(true) } else { None },
+    });
+
+    Config::resolve(rom_hash, &layers)
+}
+
+// Parse an address range like 200-2FF.
+fn parse_range(range: &str) -> Result<(u16, u16), String> {
+    let error = || format!("Invalid address range '{}', expected something like 200-2FF", range);
+    let mut parts = range.splitn(2, '-');
+    let start = u16::from_str_radix(parts.next().unwrap().trim(), 16).map_err(|_| error())?;
+    let end = match parts.next() {
+        Some(end) => u16::from_str_radix(end.trim(), 16).map_err(|_| error())?,
+        None => start,
+    };
+    if start > end { return Err(error()) };
+    Ok((start, end))
+}
+
+fn tracer(args: &Settings) -> Result<Option<Tracer>, String> {
+    let path = match &args.trace {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let format = trace::Format::from_name(&args.trace_format)
+        .ok_or_else(|| format!("Unknown trace format '{}', expected one of {:?}", args.trace_format, trace::Format::NAMES))?;
+    let range = parse_range(&args.trace_range)?;
+    Ok(Some(Tracer::new(create(path)?, format, range, args.trace_ring)))
+}
+
+fn create(path: &std::path::Path) -> Result<Box<dyn std::io::Write + Send>, String> {
+    let file = std::fs::File::create(path).map_err(|e| format!("Could not create {}: {}", path.display(), e))?;
+    Ok(Box::new(std::io::BufWriter::new(file)))
+}
+
+fn profiler(args: &Settings) -> Result<Option<Profiler>, String> {
+    let folded = match &args.profile_folded {
+        Some(path) => Some(create(path)?),
+        None => None,
+    };
+    match &args.profile {
+        Some(path) => Ok(Some(Profiler::new(create(path)?, folded))),
+        None if folded.is_some() => Ok(Some(Profiler::new(Box::new(std::io::sink()), folded))),
+        None => Ok(None),
+    }
+}
+
+fn boot(rom: &[u8], config: &Config, args: &Settings) -> Result<Box<dyn Machine>, String> {
+    if config.backend == "vip" {
+        if args.trace.is_some() {
+            return Err(String::from("Tracing needs the chip8 backend"));
+        }
+        if args.profile.is_some() || args.profile_folded.is_some() {
+            return Err(String::from("Profiling needs the chip8 backend"));
+        }
+        if args.heatmap {
+            return Err(String::from("The heatmap needs the chip8 backend"));
+        }
+        if args.gdb.is_some() || args.debugger {
+            return Err(String::from("Debugging needs the chip8 backend"));
+        }
+        if args.script.is_some() {
+            return Err(String::from("Scripts need the chip8 backend"));
+        }
+        if args.cheats {
+            return Err(String::from("Cheats need the chip8 backend"));
+        }
+        let path = config.interpreter.as_ref()
+            .ok_or("The vip backend needs a dump of the original interpreter, see --interpreter")?;
+        let interpreter = std::fs::read(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+        return Ok(Box::new(Vip::new(&interpreter, rom)?));
+    }
 
     let mut chip8 = Chip8::new_with_state();
-    chip8.load_rom(&f);
+    chip8.quirks = config.quirks();
+    chip8.load_bytes(rom);
+    chip8.count_accesses(args.heatmap);
+    if args.gdb.is_some() || args.debugger {
+        chip8.history = Some(History::new(history::CHECKPOINTS));
+    }
+    if let Some(path) = &args.script {
+        let source = std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        let script = Script::load(&source, &mut chip8, config.timing())?;
+        chip8.hook = Some(Box::new(script));
+    }
+    Ok(Box::new(chip8))
+}
+
+// Boot the machine for the first time, which also creates the trace and the profile.
+fn start(rom: &[u8], config: &Config, args: &Settings) -> Result<Box<dyn Machine>, String> {
+    let mut machine = boot(rom, config, args)?;
+    if let Some(chip8) = machine.chip8() {
+        chip8.tracer = tracer(args)?;
+        chip8.profiler = profiler(args)?;
+    }
+    Ok(machine)
+}
+
+// Reload the rom from disk, it might have been rebuilt in the meantime.
+// The new machine takes over the tracer and the profiler, creating them again would truncate the files the old ones
+// still write to. The breakpoints and the history stay as well, the reset itself counts as a change from outside.
+fn reboot(machine: &mut Box<dyn Machine>, config: &Config, args: &Settings) -> Result<(), String> {
+    let rom = std::fs::read(&args.path).map_err(|e| e.to_string())?;
+    let mut rebooted = boot(&rom, config, args)?;
+    if let (Some(old), Some(new)) = (machine.chip8(), rebooted.chip8()) {
+        new.tracer = old.tracer.take();
+        new.profiler = old.profiler.take();
+        if let Some(profiler) = &mut new.profiler {
+            profiler.reset();
+        }
+        new.breakpoints = std::mem::take(&mut old.breakpoints);
+        new.history = old.history.take();
+        if let Some(history) = &mut new.history {
+            history.changed();
+        }
+    }
+    *machine = rebooted;
+    Ok(())
+}
+
+// The script of the machine, if it runs one.
+fn script(machine: &mut dyn Machine) -> Option<&mut Script> {
+    Script::attached(machine.chip8()?)
+}
+
+// Load the cheats saved for the rom, a rom without any starts with none.
+fn cheats(args: &Settings, rom: &[u8]) -> Result<Option<Console>, String> {
+    if !args.cheats {
+        return Ok(None);
+    }
+    let path = args.config.clone().or_else(config::default_path)
+        .map(|config| config::cheats_path(&config, &database::rom_hash(rom)));
+    let cheats = match path.as_ref().map(std::fs::read_to_string) {
+        Some(Ok(text)) => Cheats::parse(&text).map_err(|e| format!("{}: {}", path.as_ref().unwrap().display(), e))?,
+        Some(Err(ref e)) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(format!("Could not read {}: {}", path.as_ref().unwrap().display(), e));
+        }
+        _ => Cheats::default(),
+    };
+    Ok(Some(Console::new(cheats, path)))
+}
+
+// Read cheat commands from the terminal without blocking the window.
+fn read_commands() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let stdin = std::io::stdin();
+        let mut line = String::new();
+        while matches!(stdin.read_line(&mut line), Ok(n) if n > 0) {
+            if sender.send(std::mem::take(&mut line)).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
 
-    let mut io = Io::new();
-    io.setup(args.update_rate);
+fn run (args: Settings) {
+    let rom = std::fs::read(&args.path).unwrap();
+
+    let config = configure(&args, &rom).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+
+    let entry = database::lookup(&rom);
+    if args.print_config {
+        if let Some(entry) = entry {
+            println!("# {}", entry.name());
+        }
+        print!("{}", config.to_toml());
+        return;
+    }
+    if let Some(entry) = entry.filter(|entry| !entry.supported()) {
+        eprintln!("{} was made for the {} platform, which isn't emulated", entry.title, entry.platform);
+    }
+
+    // Known roms show their title instead of the file name
+    let rom_name = match entry {
+        Some(entry) => entry.name(),
+        None => args.path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+    };
+    let timing = config.timing();
+
+    let mut machine = start(&rom, &config, &args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let mut cheats = cheats(&args, &rom).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let cheat_commands = cheats.as_ref().map(|_| {
+        println!("Type cheat commands here, help lists them");
+        read_commands()
+    });
+    // A script that tests the rom might be done before the window opens.
+    if script(machine.as_mut()).is_some_and(|script| script.quit()) {
+        return;
+    }
+    let mut io = Io::new(&config, WIDTH, HEIGHT);
+    let mut filter = Filter::new(config.filter, config.decay);
+    let mut session = Session::new(config.fast_forward);
+    let mut overlay = Overlay::new(config.overlay);
+    if let Some(keys) = entry.and_then(|entry| entry.keys.as_ref()) {
+        overlay.notify(keys);
+    }
+    let mut heatmap = if args.heatmap { Some(Heatmap::new()) } else { None };
+    let mut debugger = if args.debugger { Some(Debugger::new()) } else { None };
+    let mut gdb = args.gdb.map(|port| GdbServer::bind(port).unwrap_or_else(|e| {
+        eprintln!("Could not listen on port {}: {}", port, e);
+        std::process::exit(1);
+    }));
+
+    let mut title = String::new();
+    let mut drawn = true;
+    let mut next_frame = Instant::now();
+    let mut last_present = next_frame - FRAME;
 
     while io.window.is_open() && !io.window.is_key_down(minifb::Key::Escape) {
 
-        if args.authentic_drawing {
-            // Only draw when the actual drawing instruction was executed.
-            if chip8.should_draw { io.draw(&chip8.framebuffer) } else { io.window.update() }
-        } else {
-            // Draw on every iteration.
-            io.draw(&chip8.framebuffer);
+        if io.window.is_key_pressed(minifb::Key::F1, minifb::KeyRepeat::No) {
+            overlay.visible = !overlay.visible;
+        }
+        if io.window.is_key_pressed(minifb::Key::F2, minifb::KeyRepeat::No) {
+            let palette = io.cycle_palette();
+            overlay.notify(&format!("Palette {}", palette));
+        }
+        if let Some(console) = cheats.as_mut().filter(|_| io.window.is_key_pressed(minifb::Key::F9, minifb::KeyRepeat::No)) {
+            console.cheats.active = !console.cheats.active;
+            overlay.notify(if console.cheats.active { "Cheats on" } else { "Cheats off" });
+        }
+        if io.window.is_key_pressed(minifb::Key::F11, minifb::KeyRepeat::No) {
+            io.toggle_fullscreen(WIDTH, HEIGHT);
+        }
+
+        for command in io.commands() {
+            match command {
+                Command::SoftReset => {
+                    machine.reset();
+                    overlay.notify("Soft reset");
+                }
+                Command::HardReset => match reboot(&mut machine, &config, &args) {
+                    Ok(()) => overlay.notify("Hard reset"),
+                    Err(e) => overlay.notify(&format!("Could not reload rom: {}", e)),
+                },
+                _ => session.handle(command),
+            }
         }
 
-        // Set the keys after updating the window to get the new input.
-        io.set_keys(&mut chip8.keys);
-        chip8.tick();
+        if let Some(chip8) = machine.chip8() {
+            if let Some(gdb) = &mut gdb {
+                gdb.poll(chip8);
+            }
+            if let Some(debugger) = &mut debugger {
+                debugger.update(chip8);
+            }
+            if let (Some(console), Some(commands)) = (&mut cheats, &cheat_commands) {
+                for line in commands.try_iter() {
+                    match console.execute(&line, chip8) {
+                        Ok(output) if output.is_empty() => (),
+                        Ok(output) => println!("{}", output),
+                        Err(e) => println!("{}", e),
+                    }
+                }
+            }
+        }
+
+        // A halted debugger holds the machine, like pausing it.
+        let halted = gdb.as_ref().is_some_and(GdbServer::halted) || debugger.as_ref().is_some_and(Debugger::halted);
+        if !halted && session.next_frame() {
+            io.set_keys(machine.keys());
+            if let (Some(console), Some(chip8)) = (&cheats, machine.chip8()) {
+                console.cheats.apply(chip8);
+            }
+            let (executed, changed) = machine.run_frame(timing);
+            if let Some(chip8) = machine.chip8() {
+                if let Some(gdb) = &mut gdb {
+                    gdb.after_frame(chip8);
+                }
+                if let Some(debugger) = &mut debugger {
+                    debugger.after_frame(chip8);
+                }
+            }
+            drawn |= changed;
+            overlay.count_instructions(executed);
+            if let (Some(heatmap), Some(counts)) = (&mut heatmap, machine.access_counts()) {
+                heatmap.update(counts);
+            }
+            if let Some(script) = script(machine.as_mut()) {
+                if let Some(error) = script.error() {
+                    eprintln!("{}", error);
+                    overlay.notify("The script stopped");
+                }
+                if script.quit() {
+                    break;
+                }
+            }
+        }
+
+        // Fast forward can emulate a lot more frames than the screen can show.
+        let now = Instant::now();
+        if now >= last_present + FRAME {
+            if args.authentic_drawing && !drawn {
+                // Only draw when the actual drawing instruction was executed.
+                io.window.update();
+            } else {
+                let shapes = script(machine.as_mut()).map(|script| script.shapes()).unwrap_or_default();
+                io.draw(filter.apply(machine.framebuffer()), WIDTH, HEIGHT, &overlay.lines(&session, machine.as_ref()), &shapes);
+            }
+            if let Some(heatmap) = &mut heatmap {
+                heatmap.draw();
+            }
+            if let (Some(debugger), Some(chip8)) = (&mut debugger, machine.chip8()) {
+                debugger.draw(chip8);
+            }
+            overlay.count_frame();
+            drawn = false;
+            last_present = now;
+
+            let current = session.title(&rom_name);
+            if current != title {
+                io.window.set_title(&current);
+                title = current;
+            }
+        }
+
+        match session.frame_duration() {
+            Some(duration) => {
+                next_frame += duration;
+                let now = Instant::now();
+                // Don't try to catch up after falling behind, e.g. because the window was moved.
+                if next_frame > now { std::thread::sleep(next_frame - now) } else { next_frame = now }
+            }
+            None => next_frame = Instant::now(),
+        }
     }
 }
@@ -0,0 +1,195 @@
+use std::time::{Duration, Instant};
+use crusty_8::machine::Machine;
+use crate::session::{Session, State};
+
+const NOTIFICATION_TIME: Duration = Duration::from_secs(2);
+
+// Status text drawn on top of the display by Io::draw.
+// It lives entirely in the frontend and never touches the emulated framebuffer.
+pub struct Overlay {
+    pub visible: bool,
+    notification: Option<(String, Instant)>,
+    // counts since the start of the current second
+    frames: u32,
+    instructions: u64,
+    second: Instant,
+    // values of the last complete second
+    fps: u32,
+    ips: u64,
+}
+
+impl Overlay {
+    pub fn new(visible: bool) -> Overlay {
+        Overlay {
+            visible,
+            notification: None,
+            frames: 0,
+            instructions: 0,
+            second: Instant::now(),
+            fps: 0,
+            ips: 0,
+        }
+    }
+
+    // Show a message for a few seconds, even if the overlay is hidden.
+    pub fn notify(&mut self, message: &str) {
+        self.notification = Some((message.to_string(), Instant::now()));
+    }
+
+    pub fn count_instructions(&mut self, instructions: u64) {
+        self.instructions += instructions;
+    }
+
+    // Called for every frame that is shown on screen.
+    pub fn count_frame(&mut self) {
+        self.frames += 1;
+        if self.second.elapsed() >= Duration::from_secs(1) {
+            self.fps = self.frames;
+            self.ips = self.instructions;
+            self.frames = 0;
+            self.instructions = 0;
+            self.second = Instant::now();
+        }
+    }
+
+    pub fn lines(&self, session: &Session, machine: &dyn Machine) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        if self.visible {
+            lines.push(format!("FPS {}", self.fps));
+            lines.push(format!("IPS {}", self.ips));
+            lines.push(format!("SPEED {}", session.speed_label().to_uppercase()));
+            if session.state != State::Running {
+                lines.push(String::from("PAUSED"));
+            }
+            if machine.waiting_for_key() {
+                lines.push(String::from("WAITING FOR KEY"));
+            }
+        }
+
+        if let Some((message, since)) = &self.notification {
+            if since.elapsed() < NOTIFICATION_TIME {
+                lines.push(message.to_uppercase());
+            }
+        }
+
+        lines
+    }
+}
+
+// Glyphs are 3 pixels wide and 5 high, bit 2 is the leftmost column.
+pub const GLYPH_WIDTH: usize = 3;
+pub const GLYPH_HEIGHT: usize = 5;
+
+// Draw text with the built-in font into a 0RGB buffer of the given size, clipped to it.
+// Every font pixel is scale x scale buffer pixels.
+pub fn draw_text(buffer: &mut [u32], size: (usize, usize), text: &str, left: usize, top: usize, scale: usize, colour: u32) {
+    let (width, height) = size;
+    for (column, c) in text.chars().enumerate() {
+        let left = left + column * (GLYPH_WIDTH + 1) * scale;
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for bit in 0..GLYPH_WIDTH {
+                if bits >> (GLYPH_WIDTH - 1 - bit) & 1 == 0 {
+                    continue;
+                }
+                let (x, y) = (left + bit * scale, top + row * scale);
+                for y in y..(y + scale).min(height) {
+                    for x in x..(x + scale).min(width) {
+                        buffer[y * width + x] = colour;
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c.to_ascii_uppercase() {
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b111, 0b001, 0b111, 0b100, 0b111],
+        '3' => [0b111, 0b001, 0b111, 0b001, 0b111],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b111, 0b001, 0b111],
+        '6' => [0b111, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b111],
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        ' ' => [0b000, 0b000, 0b000, 0b000, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ',' => [0b000, 0b000, 0b000, 0b010, 0b100],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '=' => [0b000, 0b111, 0b000, 0b111, 0b000],
+        '[' => [0b110, 0b100, 0b100, 0b100, 0b110],
+        ']' => [0b011, 0b001, 0b001, 0b001, 0b011],
+        '(' => [0b010, 0b100, 0b100, 0b100, 0b010],
+        ')' => [0b010, 0b001, 0b001, 0b001, 0b010],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        '#' => [0b101, 0b111, 0b101, 0b111, 0b101],
+        '>' => [0b100, 0b010, 0b001, 0b010, 0b100],
+        '<' => [0b001, 0b010, 0b100, 0b010, 0b001],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        _ => [0b111, 0b001, 0b010, 0b000, 0b010],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crusty_8::chip8::Chip8;
+
+    #[test]
+    fn lines() {
+        let mut session = Session::new(4);
+        let mut chip8 = Chip8::new_with_state();
+        let mut overlay = Overlay::new(false);
+        assert!(overlay.lines(&session, &chip8).is_empty());
+
+        overlay.notify("Palette amber");
+        assert_eq!(vec!["PALETTE AMBER"], overlay.lines(&session, &chip8));
+
+        overlay.visible = true;
+        session.handle(crate::session::Command::TogglePause);
+        assert_eq!(vec!["FPS 0", "IPS 0", "SPEED 1X", "PAUSED", "PALETTE AMBER"], overlay.lines(&session, &chip8));
+
+        // FX0A
+        chip8.load_bytes(&[0xF0, 0x0A]);
+        chip8.tick();
+        assert_eq!("WAITING FOR KEY", overlay.lines(&session, &chip8)[4]);
+    }
+
+    #[test]
+    fn unknown_characters() {
+        assert_eq!(glyph('?'), glyph('~'));
+        assert_eq!(glyph('a'), glyph('A'));
+    }
+}
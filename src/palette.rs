@@ -0,0 +1,44 @@
+// Colours for the background, the first plane, the second plane and both planes overlapping.
+// Only the first two are used as long as the interpreter has a single plane.
+pub type Palette = [u32; 4];
+
+pub const PRESETS: [(&str, Palette); 9] = [
+    ("classic", [0x00_00_00, 0xFF_FF_FF, 0xAA_AA_AA, 0x55_55_55]),
+    ("green", [0x00_12_00, 0x33_FF_66, 0x1A_99_33, 0x99_FF_BB]),
+    ("amber", [0x1A_0F_00, 0xFF_B0_00, 0x99_66_00, 0xFF_DD_88]),
+    ("lcd", [0xF9_FF_B3, 0x3D_80_26, 0xAB_CC_47, 0x00_13_1A]),
+    // The remaining ones are Octo's palettes
+    ("octo", [0x99_66_00, 0xFF_CC_00, 0xFF_66_00, 0x66_22_00]),
+    ("hotdog", [0x00_00_00, 0xFF_00_00, 0xFF_FF_00, 0xFF_FF_FF]),
+    ("gray", [0xAA_AA_AA, 0x00_00_00, 0xFF_FF_FF, 0x66_66_66]),
+    ("cga0", [0x00_00_00, 0x00_FF_00, 0xFF_00_00, 0xFF_FF_00]),
+    ("cga1", [0x00_00_00, 0xFF_00_FF, 0x00_FF_FF, 0xFF_FF_FF]),
+];
+
+pub fn find(name: &str) -> Option<Palette> {
+    PRESETS.iter().find(|(preset, _)| *preset == name).map(|(_, palette)| *palette)
+}
+
+pub fn names() -> Vec<&'static str> {
+    PRESETS.iter().map(|(name, _)| *name).collect()
+}
+
+// The preset after the given one, wrapping around at the end.
+// Unknown names start over at the first preset.
+pub fn next(name: &str) -> (&'static str, Palette) {
+    let index = PRESETS.iter().position(|(preset, _)| *preset == name).map_or(0, |i| i + 1);
+    PRESETS[index % PRESETS.len()]
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cycle() {
+        assert_eq!("green", next("classic").0);
+        assert_eq!("classic", next("cga1").0);
+        assert_eq!("classic", next("custom").0);
+        assert_eq!(Some(PRESETS[3].1), find("lcd"));
+    }
+}
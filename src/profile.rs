@@ -0,0 +1,217 @@
+// Where a rom spends its time, see Chip8::profiler.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use crate::disassembler;
+
+// Addresses listed in the report
+const HOT_SPOTS: usize = 20;
+
+pub struct Profiler {
+    report: Box<dyn Write + Send>,
+    // flamegraph input, one line per call stack
+    folded: Option<Box<dyn Write + Send>>,
+    // executions and the last opcode per address
+    addresses: Vec<(u64, u16)>,
+    // executions per opcode pattern, see disassembler::pattern
+    patterns: HashMap<&'static str, u64>,
+    // executions per call stack, given as the addresses of the subroutines from the outermost one
+    stacks: HashMap<Vec<u16>, u64>,
+    // reused to look up the current call stack without allocating
+    current: Vec<u16>,
+    count: u64,
+    // instructions executed before every hard reset, the profile covers all runs
+    resets: Vec<u64>,
+}
+
+impl Profiler {
+    pub fn new(report: Box<dyn Write + Send>, folded: Option<Box<dyn Write + Send>>) -> Profiler {
+        Profiler {
+            report,
+            folded,
+            addresses: vec![(0, 0); 4096],
+            patterns: HashMap::new(),
+            stacks: HashMap::new(),
+            current: Vec::new(),
+            count: 0,
+            resets: Vec::new(),
+        }
+    }
+
+    // The stack only holds return addresses, the called subroutine is found in the 2NNN right before each.
+    pub fn record(&mut self, pc: u16, opcode: u16, stack: &[u16], memory: &[u8]) {
+        let entry = &mut self.addresses[pc as usize & 0xFFF];
+        entry.0 += 1;
+        entry.1 = opcode;
+        *self.patterns.entry(disassembler::pattern(opcode)).or_insert(0) += 1;
+
+        self.current.clear();
+        self.current.extend(stack.iter().map(|&address| {
+            let call = (address.wrapping_sub(2) & 0xFFF) as usize;
+            (memory[call] as u16 & 0x0F) << 8 | memory[(call + 1) & 0xFFF] as u16
+        }));
+        match self.stacks.get_mut(&self.current) {
+            Some(count) => *count += 1,
+            None => { self.stacks.insert(self.current.clone(), 1); }
+        }
+        self.count += 1;
+    }
+
+    // The program was restarted from scratch, the report says where since the rom might have changed.
+    pub fn reset(&mut self) {
+        self.resets.push(self.count);
+    }
+
+    fn percent(&self, count: u64) -> f64 {
+        count as f64 * 100.0 / self.count.max(1) as f64
+    }
+
+    fn frame_name(address: Option<&u16>) -> String {
+        match address {
+            Some(address) => format!("sub_{:03X}", address),
+            None => String::from("main"),
+        }
+    }
+
+    // Hot spots, the call tree with the time spent in and below every subroutine, and the opcode histogram.
+    pub fn write_report(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(writer, "{} instructions executed", self.count)?;
+        for &count in self.resets.iter() {
+            writeln!(writer, "Hard reset after {} instructions, the profile covers all runs", count)?;
+        }
+
+        writeln!(writer, "\nHot spots")?;
+        let mut addresses: Vec<usize> = (0..self.addresses.len()).filter(|&a| self.addresses[a].0 > 0).collect();
+        addresses.sort_by_key(|&a| std::cmp::Reverse(self.addresses[a].0));
+        for &address in addresses.iter().take(HOT_SPOTS) {
+            let (count, opcode) = self.addresses[address];
+            writeln!(
+                writer, "{:>12} {:>6.2}%  {:03X} {:04X} {}",
+                count, self.percent(count), address, opcode, disassembler::mnemonic(opcode),
+            )?;
+        }
+
+        // Every prefix of a call stack is a node of the tree, with the total and the self time.
+        let mut nodes: BTreeMap<&[u16], (u64, u64)> = BTreeMap::new();
+        for (stack, &count) in self.stacks.iter() {
+            for depth in 0..=stack.len() {
+                nodes.entry(&stack[..depth]).or_insert((0, 0)).0 += count;
+            }
+            nodes.entry(&stack[..]).or_insert((0, 0)).1 += count;
+        }
+        writeln!(writer, "\n{:<12}{:>8}{:>8}", "Call tree", "total", "self")?;
+        let mut pending = vec![&[][..]];
+        while let Some(path) = pending.pop() {
+            if let Some(&(total, own)) = nodes.get(path) {
+                writeln!(
+                    writer, "{:indent$}{:<width$}{:>7.2}%{:>7.2}%",
+                    "", Profiler::frame_name(path.last()), self.percent(total), self.percent(own),
+                    indent = path.len() * 2, width = 12usize.saturating_sub(path.len() * 2),
+                )?;
+            }
+            let mut children: Vec<&[u16]> = nodes.keys()
+                .filter(|child| child.len() == path.len() + 1 && child.starts_with(path))
+                .cloned()
+                .collect();
+            // The busiest child ends up on top of the stack
+            children.sort_by_key(|child| nodes[child].0);
+            pending.extend(children);
+        }
+
+        writeln!(writer, "\nOpcodes")?;
+        let mut patterns: Vec<(&str, u64)> = self.patterns.iter().map(|(&pattern, &count)| (pattern, count)).collect();
+        patterns.sort_by_key(|&(pattern, count)| (std::cmp::Reverse(count), pattern));
+        for (pattern, count) in patterns {
+            writeln!(writer, "{:<8}{:>12} {:>6.2}%", pattern, count, self.percent(count))?;
+        }
+        Ok(())
+    }
+
+    // The folded stack format of flamegraph.pl and inferno: "main;sub_2A4;sub_300 1234"
+    pub fn write_folded(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+        let mut stacks: Vec<(&Vec<u16>, &u64)> = self.stacks.iter().collect();
+        stacks.sort();
+        for (stack, count) in stacks {
+            let mut line = Profiler::frame_name(None);
+            for address in stack.iter() {
+                line.push(';');
+                line.push_str(&Profiler::frame_name(Some(address)));
+            }
+            writeln!(writer, "{} {}", line, count)?;
+        }
+        Ok(())
+    }
+}
+
+// Like the trace, the report is written when the interpreter goes away, which includes a panic.
+impl Drop for Profiler {
+    fn drop(&mut self) {
+        let mut report = std::mem::replace(&mut self.report, Box::new(std::io::sink()));
+        let _ = self.write_report(&mut report).and_then(|_| report.flush());
+        if let Some(mut folded) = self.folded.take() {
+            let _ = self.write_folded(&mut folded).and_then(|_| folded.flush());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::chip8::Chip8;
+
+    // Calls 0x208 from the main loop, which calls 0x20C
+    const ROM: [u8; 16] = [0x22, 0x08, 0x12, 0x00, 0, 0, 0, 0, 0x22, 0x0C, 0x00, 0xEE, 0x60, 0x01, 0x00, 0xEE];
+
+    fn profile(steps: usize) -> Profiler {
+        let mut c = Chip8::new_with_state();
+        c.load_bytes(&ROM);
+        c.profiler = Some(Profiler::new(Box::new(std::io::sink()), None));
+        for _ in 0..steps {
+            c.tick();
+        }
+        c.profiler.take().unwrap()
+    }
+
+    #[test]
+    fn counts() {
+        // CALL, CALL, LD, RET, RET, JP and the first CALL again
+        let profiler = profile(7);
+        assert_eq!(7, profiler.count);
+        assert_eq!(2, profiler.addresses[0x200].0);
+        assert_eq!((1, 0x6001), profiler.addresses[0x20C]);
+        assert_eq!(Some(&3), profiler.patterns.get("2NNN"));
+        assert_eq!(Some(&3), profiler.stacks.get(&vec![]));
+        assert_eq!(Some(&2), profiler.stacks.get(&vec![0x208]));
+        assert_eq!(Some(&2), profiler.stacks.get(&vec![0x208, 0x20C]));
+    }
+
+    #[test]
+    fn reset() {
+        let mut profiler = profile(7);
+        profiler.reset();
+        profiler.record(0x200, 0x2208, &[], &[0; 4096]);
+        let mut report = Vec::new();
+        profiler.write_report(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.starts_with("8 instructions executed\nHard reset after 7 instructions, the profile covers all runs\n"));
+        assert_eq!(3, profiler.addresses[0x200].0);
+    }
+
+    #[test]
+    fn report() {
+        let profiler = profile(7);
+        let mut report = Vec::new();
+        profiler.write_report(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.starts_with("7 instructions executed\n\nHot spots\n"));
+        assert!(report.contains("           2  28.57%  200 2208 CALL 0x208\n"));
+        assert!(report.contains(
+            "main         100.00%  42.86%\n  sub_208     57.14%  28.57%\n    sub_20C   28.57%  28.57%\n"
+        ));
+        assert!(report.contains("2NNN               3  42.86%\n"));
+
+        let mut folded = Vec::new();
+        profiler.write_folded(&mut folded).unwrap();
+        assert_eq!("main 3\nmain;sub_208 2\nmain;sub_208;sub_20C 2\n", String::from_utf8(folded).unwrap());
+    }
+}
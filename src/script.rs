@@ -0,0 +1,348 @@
+// Rhai scripts that automate the interpreter, run as its Hook. See the README for what they can call.
+// Bots press keys, tests run some frames and check the registers, HUDs draw on top of the display.
+//
+// The functions a script calls can't borrow the Chip8, so it is swapped into the shared state
+// while the script runs and swapped back afterwards, see Script::run.
+
+use std::any::Any;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex, MutexGuard};
+use rhai::{Dynamic, Engine, EvalAltResult, FnPtr, AST, INT};
+use crate::chip8::{Chip8, Hook, Registers, Timing, PC_LIMIT};
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+// Something a script drew on top of the display, in display pixels. Colours are 0RGB.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Shape {
+    Text { x: i64, y: i64, text: String, colour: u32 },
+    Rect { x: i64, y: i64, width: i64, height: i64, colour: u32 },
+}
+
+struct Shared {
+    // the interpreter while the script runs, a spare one otherwise
+    chip8: Chip8,
+    timing: Timing,
+    frame_callbacks: Vec<FnPtr>,
+    pc_callbacks: BTreeMap<u16, Vec<FnPtr>>,
+    // keys the script holds down, in addition to the ones pressed on the keyboard
+    held: [bool; 16],
+    // drawn since the start of the frame
+    shapes: Vec<Shape>,
+    frames: u64,
+    quit: bool,
+}
+
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    shared: Arc<Mutex<Shared>>,
+    // addresses with callbacks, checked before every instruction
+    watched: BTreeSet<u16>,
+    // the first runtime error, after which no more callbacks run
+    error: Option<String>,
+}
+
+impl Script {
+    // Compile a script and run its top level, which registers callbacks or runs a test right away.
+    pub fn load(source: &str, chip8: &mut Chip8, timing: Timing) -> Result<Script, String> {
+        let shared = Arc::new(Mutex::new(Shared {
+            chip8: Chip8::new_with_state(),
+            timing,
+            frame_callbacks: Vec::new(),
+            pc_callbacks: BTreeMap::new(),
+            held: [false; 16],
+            shapes: Vec::new(),
+            frames: 0,
+            quit: false,
+        }));
+        let mut engine = Engine::new();
+        bind(&mut engine, &shared);
+        let ast = engine.compile(source).map_err(|e| format!("Could not compile the script: {}", e))?;
+
+        let mut script = Script { engine, ast, shared, watched: BTreeSet::new(), error: None };
+        std::mem::swap(chip8, &mut lock(&script.shared).chip8);
+        let result = script.engine.run_ast(&script.ast);
+        std::mem::swap(chip8, &mut lock(&script.shared).chip8);
+        result.map_err(|e| format!("The script failed: {}", e))?;
+        script.watch();
+        Ok(script)
+    }
+
+    // The script the machine runs as its hook, if any.
+    pub fn attached(chip8: &mut Chip8) -> Option<&mut Script> {
+        let hook: &mut dyn Any = chip8.hook.as_mut()?.as_mut();
+        hook.downcast_mut()
+    }
+
+    pub fn shapes(&self) -> Vec<Shape> {
+        lock(&self.shared).shapes.clone()
+    }
+
+    // Whether the script asked to end the program.
+    pub fn quit(&self) -> bool {
+        lock(&self.shared).quit
+    }
+
+    // The error that stopped the script, returned only once.
+    pub fn error(&mut self) -> Option<String> {
+        self.error.take()
+    }
+
+    fn run(&mut self, chip8: &mut Chip8, callbacks: impl FnOnce(&Shared) -> Vec<FnPtr>) {
+        let callbacks = callbacks(&lock(&self.shared));
+        std::mem::swap(chip8, &mut lock(&self.shared).chip8);
+        for callback in callbacks {
+            if let Err(e) = callback.call::<Dynamic>(&self.engine, &self.ast, ()) {
+                self.error = Some(format!("The script failed: {}", e));
+                break;
+            }
+        }
+        std::mem::swap(chip8, &mut lock(&self.shared).chip8);
+        // A broken script stops, the program keeps running
+        if self.error.is_some() {
+            let mut shared = lock(&self.shared);
+            shared.frame_callbacks.clear();
+            shared.pc_callbacks.clear();
+        }
+        self.watch();
+    }
+
+    fn watch(&mut self) {
+        self.watched = lock(&self.shared).pc_callbacks.keys().copied().collect();
+    }
+}
+
+impl Hook for Script {
+    // Start a frame: the shapes of the last one are gone and the script's keys are held again.
+    fn begin_frame(&mut self, chip8: &mut Chip8) {
+        let mut shared = lock(&self.shared);
+        shared.shapes.clear();
+        for (key, &held) in chip8.keys.iter_mut().zip(shared.held.iter()) {
+            *key |= held;
+        }
+    }
+
+    fn end_frame(&mut self, chip8: &mut Chip8) {
+        lock(&self.shared).frames += 1;
+        self.run(chip8, |shared| shared.frame_callbacks.clone());
+    }
+
+    fn watches(&self, pc: u16) -> bool {
+        self.watched.contains(&pc)
+    }
+
+    // Run the callbacks for the address about to be executed.
+    fn reached(&mut self, chip8: &mut Chip8) {
+        let pc = chip8.registers().pc;
+        self.run(chip8, |shared| shared.pc_callbacks.get(&pc).cloned().unwrap_or_default());
+    }
+}
+
+// A panicking callback doesn't leave anything half done that would matter.
+fn lock(shared: &Mutex<Shared>) -> MutexGuard<'_, Shared> {
+    shared.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn index(value: INT, limit: usize, what: &str) -> ScriptResult<usize> {
+    if value >= 0 && (value as usize) < limit {
+        Ok(value as usize)
+    } else {
+        Err(format!("There is no {} {}", what, value).into())
+    }
+}
+
+fn update(shared: &Mutex<Shared>, change: impl FnOnce(&mut Registers)) {
+    let chip8 = &mut lock(shared).chip8;
+    let mut registers = chip8.registers();
+    change(&mut registers);
+    chip8.set_registers(registers);
+}
+
+// Values written to registers and memory are cut to their size, except for the PC which has to leave room
+// for an instruction.
+fn bind(engine: &mut Engine, shared: &Arc<Mutex<Shared>>) {
+    let s = shared.clone();
+    engine.register_fn("v", move |x: INT| -> ScriptResult<INT> {
+        Ok(lock(&s).chip8.registers().v[index(x, 16, "register")?] as INT)
+    });
+    let s = shared.clone();
+    engine.register_fn("set_v", move |x: INT, value: INT| -> ScriptResult<()> {
+        let x = index(x, 16, "register")?;
+        update(&s, |registers| registers.v[x] = value as u8);
+        Ok(())
+    });
+    let s = shared.clone();
+    engine.register_fn("i", move || lock(&s).chip8.registers().i as INT);
+    let s = shared.clone();
+    engine.register_fn("set_i", move |value: INT| update(&s, |registers| registers.i = value as u16));
+    let s = shared.clone();
+    engine.register_fn("pc", move || lock(&s).chip8.registers().pc as INT);
+    let s = shared.clone();
+    engine.register_fn("set_pc", move |value: INT| -> ScriptResult<()> {
+        let pc = index(value, PC_LIMIT as usize, "address for the PC")? as u16;
+        update(&s, |registers| registers.pc = pc);
+        Ok(())
+    });
+    let s = shared.clone();
+    engine.register_fn("sp", move || lock(&s).chip8.registers().sp as INT);
+    let s = shared.clone();
+    engine.register_fn("dt", move || lock(&s).chip8.registers().dt as INT);
+    let s = shared.clone();
+    engine.register_fn("set_dt", move |value: INT| update(&s, |registers| registers.dt = value as u8));
+    let s = shared.clone();
+    engine.register_fn("st", move || lock(&s).chip8.registers().st as INT);
+    let s = shared.clone();
+    engine.register_fn("set_st", move |value: INT| update(&s, |registers| registers.st = value as u8));
+
+    let s = shared.clone();
+    engine.register_fn("peek", move |address: INT| -> ScriptResult<INT> {
+        Ok(lock(&s).chip8.memory()[index(address, 4096, "address")?] as INT)
+    });
+    let s = shared.clone();
+    engine.register_fn("poke", move |address: INT, value: INT| -> ScriptResult<()> {
+        lock(&s).chip8.memory_mut()[index(address, 4096, "address")?] = value as u8;
+        Ok(())
+    });
+
+    // Pressed keys stay down until released, on top of the keyboard
+    let s = shared.clone();
+    engine.register_fn("press", move |key: INT| -> ScriptResult<()> {
+        let key = index(key, 16, "key")?;
+        let shared = &mut *lock(&s);
+        shared.held[key] = true;
+        shared.chip8.keys[key] = true;
+        Ok(())
+    });
+    let s = shared.clone();
+    engine.register_fn("release", move |key: INT| -> ScriptResult<()> {
+        let key = index(key, 16, "key")?;
+        let shared = &mut *lock(&s);
+        shared.held[key] = false;
+        shared.chip8.keys[key] = false;
+        Ok(())
+    });
+    let s = shared.clone();
+    engine.register_fn("key_down", move |key: INT| -> ScriptResult<bool> {
+        Ok(lock(&s).chip8.keys[index(key, 16, "key")?])
+    });
+
+    let s = shared.clone();
+    engine.register_fn("step", move || lock(&s).chip8.tick());
+    let s = shared.clone();
+    engine.register_fn("run_frame", move || {
+        let shared = &mut *lock(&s);
+        shared.chip8.run_frame(shared.timing);
+        shared.frames += 1;
+    });
+    let s = shared.clone();
+    engine.register_fn("frame", move || lock(&s).frames as INT);
+    let s = shared.clone();
+    engine.register_fn("quit", move || lock(&s).quit = true);
+
+    let s = shared.clone();
+    engine.register_fn("on_frame", move |callback: FnPtr| lock(&s).frame_callbacks.push(callback));
+    let s = shared.clone();
+    engine.register_fn("on_pc", move |address: INT, callback: FnPtr| -> ScriptResult<()> {
+        let address = index(address, 4096, "address")? as u16;
+        lock(&s).pc_callbacks.entry(address).or_default().push(callback);
+        Ok(())
+    });
+
+    let s = shared.clone();
+    engine.register_fn("text", move |x: INT, y: INT, text: &str, colour: INT| {
+        lock(&s).shapes.push(Shape::Text { x, y, text: text.to_string(), colour: colour as u32 & 0xFF_FF_FF });
+    });
+    let s = shared.clone();
+    engine.register_fn("text", move |x: INT, y: INT, text: &str| {
+        lock(&s).shapes.push(Shape::Text { x, y, text: text.to_string(), colour: 0xFF_FF_FF });
+    });
+    let s = shared.clone();
+    engine.register_fn("rect", move |x: INT, y: INT, width: INT, height: INT, colour: INT| {
+        lock(&s).shapes.push(Shape::Rect { x, y, width, height, colour: colour as u32 & 0xFF_FF_FF });
+    });
+    let s = shared.clone();
+    engine.register_fn("rect", move |x: INT, y: INT, width: INT, height: INT| {
+        lock(&s).shapes.push(Shape::Rect { x, y, width, height, colour: 0xFF_FF_FF });
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // ADD V0, 1; JP 0x200
+    const ROM: [u8; 4] = [0x70, 0x01, 0x12, 0x00];
+
+    fn load(source: &str) -> (Chip8, Result<Script, String>) {
+        let mut chip8 = Chip8::new_with_state();
+        chip8.load_bytes(&ROM);
+        let script = Script::load(source, &mut chip8, Timing::Instructions(10));
+        (chip8, script)
+    }
+
+    #[test]
+    fn top_level() {
+        let (chip8, script) = load(r#"
+            set_v(3, 0x1FF);
+            poke(0x300, peek(0x200));
+            run_frame();
+            if v(0) != 5 { throw "V0 is " + v(0); }
+            quit();
+        "#);
+        let script = script.unwrap();
+        assert!(script.quit());
+        assert_eq!(0xFF, chip8.registers().v[3]);
+        assert_eq!(0x70, chip8.memory()[0x300]);
+        assert_eq!(0x200, chip8.registers().pc);
+
+        let (_, script) = load("peek(4096);");
+        assert!(script.err().unwrap().contains("There is no address 4096"));
+        assert!(load("let x = ;").1.err().unwrap().starts_with("Could not compile the script"));
+        let (chip8, script) = load("set_pc(0xFFE);");
+        assert!(script.err().unwrap().contains("There is no address for the PC 4094"));
+        assert_eq!(0x200, chip8.registers().pc);
+    }
+
+    #[test]
+    fn callbacks() {
+        let (mut chip8, script) = load(r#"
+            let jumps = 0;
+            on_pc(0x202, || jumps += 1);
+            on_frame(|| {
+                press(5);
+                text(1, 2, "JUMPS " + jumps, 0xFF0000);
+            });
+        "#);
+        chip8.hook = Some(Box::new(script.unwrap()));
+
+        chip8.run_frame(Timing::Instructions(10));
+        let shapes = Script::attached(&mut chip8).unwrap().shapes();
+        assert_eq!(vec![Shape::Text { x: 1, y: 2, text: String::from("JUMPS 5"), colour: 0xFF0000 }], shapes);
+        assert!(chip8.keys[5]);
+
+        // Keys pressed by the script stay down, even if the keyboard releases them
+        chip8.keys = [false; 16];
+        chip8.run_frame(Timing::Instructions(10));
+        assert!(chip8.keys[5]);
+        assert_eq!("JUMPS 10", match &Script::attached(&mut chip8).unwrap().shapes()[0] {
+            Shape::Text { text, .. } => text.clone(),
+            other => panic!("{:?}", other),
+        });
+    }
+
+    #[test]
+    fn errors() {
+        let (mut chip8, script) = load("on_pc(0x202, || set_v(16, 0));");
+        chip8.hook = Some(Box::new(script.unwrap()));
+
+        chip8.run_frame(Timing::Instructions(10));
+        let script = Script::attached(&mut chip8).unwrap();
+        assert!(script.error().unwrap().contains("There is no register 16"));
+        assert_eq!(None, script.error());
+        // The program keeps running without the script
+        assert!(!script.watches(0x202));
+        chip8.run_frame(Timing::Instructions(10));
+        assert_eq!(10, chip8.registers().v[0]);
+    }
+}
@@ -0,0 +1,157 @@
+use std::time::Duration;
+
+pub const FRAME: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+// Slow motion runs at a quarter of the normal speed.
+const SLOW_MOTION: u32 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    Running,
+    Paused,
+    // Run a single frame, then pause again
+    Advancing,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Speed {
+    Normal,
+    // Runs frames this many times faster, zero means as fast as possible
+    FastForward(u32),
+    SlowMotion,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    TogglePause,
+    Advance,
+    ToggleFastForward,
+    ToggleSlowMotion,
+    SoftReset,
+    HardReset,
+}
+
+// Keeps track of what the run loop should be doing.
+pub struct Session {
+    pub state: State,
+    pub speed: Speed,
+    // multiplier used by fast forward, zero means uncapped
+    fast_forward: u32,
+}
+
+impl Session {
+    pub fn new(fast_forward: u32) -> Session {
+        Session {
+            state: State::Running,
+            speed: Speed::Normal,
+            fast_forward,
+        }
+    }
+
+    // Resets are up to the caller, they don't change the state.
+    pub fn handle(&mut self, command: Command) {
+        match command {
+            Command::TogglePause => {
+                self.state = if self.state == State::Running { State::Paused } else { State::Running };
+            }
+            Command::Advance => self.state = State::Advancing,
+            Command::ToggleFastForward => {
+                self.speed = match self.speed {
+                    Speed::FastForward(_) => Speed::Normal,
+                    _ => Speed::FastForward(self.fast_forward),
+                };
+            }
+            Command::ToggleSlowMotion => {
+                self.speed = if self.speed == Speed::SlowMotion { Speed::Normal } else { Speed::SlowMotion };
+            }
+            Command::SoftReset | Command::HardReset => (),
+        }
+    }
+
+    // Whether the next frame should be emulated.
+    // Advancing only lets a single frame through.
+    pub fn next_frame(&mut self) -> bool {
+        match self.state {
+            State::Running => true,
+            State::Paused => false,
+            State::Advancing => {
+                self.state = State::Paused;
+                true
+            }
+        }
+    }
+
+    // How long an emulated frame should take, None if there is no limit.
+    pub fn frame_duration(&self) -> Option<Duration> {
+        match self.speed {
+            Speed::Normal => Some(FRAME),
+            Speed::FastForward(0) => None,
+            Speed::FastForward(multiplier) => Some(FRAME / multiplier),
+            Speed::SlowMotion => Some(FRAME * SLOW_MOTION),
+        }
+    }
+
+    pub fn speed_label(&self) -> String {
+        match self.speed {
+            Speed::Normal => String::from("1x"),
+            Speed::FastForward(0) => String::from("uncapped"),
+            Speed::FastForward(multiplier) => format!("{}x", multiplier),
+            Speed::SlowMotion => format!("1/{}x", SLOW_MOTION),
+        }
+    }
+
+    pub fn title(&self, rom: &str) -> String {
+        let mut title = format!("Crusty-8 - {}", rom);
+        if self.speed != Speed::Normal {
+            title.push_str(&format!(" [{}]", self.speed_label()));
+        }
+        if self.state != State::Running {
+            title.push_str(" [paused]");
+        }
+        title
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pause_and_advance() {
+        let mut s = Session::new(4);
+        assert!(s.next_frame());
+
+        s.handle(Command::TogglePause);
+        assert!(!s.next_frame());
+        assert_eq!("Crusty-8 - pong [paused]", s.title("pong"));
+
+        s.handle(Command::Advance);
+        assert!(s.next_frame());
+        assert!(!s.next_frame());
+
+        s.handle(Command::TogglePause);
+        assert!(s.next_frame());
+        assert!(s.next_frame());
+    }
+
+    #[test]
+    fn speed() {
+        let mut s = Session::new(4);
+        assert_eq!(Some(FRAME), s.frame_duration());
+
+        s.handle(Command::ToggleFastForward);
+        assert_eq!(Some(FRAME / 4), s.frame_duration());
+        assert_eq!("Crusty-8 - pong [4x]", s.title("pong"));
+
+        // Slow motion replaces fast forward
+        s.handle(Command::ToggleSlowMotion);
+        assert_eq!(Some(FRAME * 4), s.frame_duration());
+        s.handle(Command::ToggleSlowMotion);
+        assert_eq!(Some(FRAME), s.frame_duration());
+
+        let mut s = Session::new(0);
+        s.handle(Command::ToggleFastForward);
+        assert_eq!(None, s.frame_duration());
+        assert_eq!("Crusty-8 - pong [uncapped]", s.title("pong"));
+    }
+}
@@ -0,0 +1,153 @@
+// Finding the sprites of a rom and laying them out on a sheet, see the crusty-8-sprites tool.
+
+use std::collections::BTreeMap;
+
+// Instructions after an ANNN that are searched for the DXYN drawing from there
+const LOOKAHEAD: usize = 8;
+// Empty pixels between the sprites of a sheet
+const SPACING: usize = 1;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sprite {
+    pub address: u16,
+    // one byte per row, the most significant bit is the leftmost pixel
+    pub rows: Vec<u8>,
+}
+
+fn opcode(rom: &[u8], offset: usize) -> Option<u16> {
+    let bytes = rom.get(offset..offset + 2)?;
+    Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+// Sprites that are drawn by a DXYN shortly after an ANNN pointing into the rom, which is loaded at 0x200.
+// Code and data aren't told apart, so every offset is tried. Different heights at the same address
+// are merged into the tallest one.
+pub fn referenced(rom: &[u8]) -> Vec<Sprite> {
+    let mut heights = BTreeMap::new();
+
+    for start in 0..rom.len() {
+        let address = match opcode(rom, start) {
+            Some(opcode) if opcode >> 12 == 0xA => (opcode & 0x0FFF) as usize,
+            _ => continue,
+        };
+        if address < 0x200 || address >= 0x200 + rom.len() {
+            continue;
+        }
+
+        for step in 1..=LOOKAHEAD {
+            let opcode = match opcode(rom, start + step * 2) {
+                Some(opcode) => opcode,
+                None => break,
+            };
+            match opcode >> 12 {
+                0xD if opcode & 0x000F != 0 => {
+                    let height = heights.entry(address).or_insert(0);
+                    *height = (opcode & 0x000F).max(*height);
+                    break;
+                }
+                // I changes or the code goes elsewhere
+                0xA | 0x1 | 0xB => break,
+                0x0 if opcode == 0x00EE => break,
+                _ => (),
+            }
+        }
+    }
+
+    heights.into_iter()
+        .map(|(address, height)| {
+            let offset = address - 0x200;
+            let end = (offset + height as usize).min(rom.len());
+            Sprite { address: address as u16, rows: rom[offset..end].to_vec() }
+        })
+        .collect()
+}
+
+// The whole rom cut into sprites of the same height, for graphics no instruction points at directly.
+pub fn tiles(rom: &[u8], height: usize) -> Vec<Sprite> {
+    rom.chunks(height.max(1))
+        .enumerate()
+        .map(|(index, rows)| Sprite { address: (0x200 + index * height.max(1)) as u16, rows: rows.to_vec() })
+        .collect()
+}
+
+// Sprites arranged in a grid, every cell as high as the tallest sprite.
+pub struct Sheet {
+    pub width: usize,
+    pub height: usize,
+    // None between the sprites
+    pub pixels: Vec<Option<bool>>,
+    // cell width and height including the spacing
+    pub cell: (usize, usize),
+    pub columns: usize,
+}
+
+impl Sheet {
+    pub fn new(sprites: &[Sprite], columns: usize) -> Sheet {
+        let columns = columns.max(1).min(sprites.len().max(1));
+        let rows = sprites.len().div_ceil(columns);
+        let tallest = sprites.iter().map(|sprite| sprite.rows.len()).max().unwrap_or(0);
+        let cell = (8 + SPACING, tallest + SPACING);
+        let (width, height) = (columns * cell.0 + SPACING, rows * cell.1 + SPACING);
+
+        let mut pixels = vec![None; width * height];
+        for (index, sprite) in sprites.iter().enumerate() {
+            let left = SPACING + (index % columns) * cell.0;
+            let top = SPACING + (index / columns) * cell.1;
+            for y in 0..tallest {
+                for x in 0..8 {
+                    let row = sprite.rows.get(y).copied().unwrap_or(0);
+                    pixels[(top + y) * width + left + x] = Some(row >> (7 - x) & 1 != 0);
+                }
+            }
+        }
+        Sheet { width, height, pixels, cell, columns }
+    }
+
+    // The sprite under a pixel of the sheet, by its index.
+    pub fn sprite_at(&self, x: usize, y: usize) -> Option<usize> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        Some((y / self.cell.1) * self.columns + x / self.cell.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // LD I, 0x20A; DRW V0, V1, 3; LD I, 0x20A; LD V0, 1; DRW V0, V1, 4; the sprite; a pointer without a draw
+    const ROM: [u8; 18] = [
+        0xA2, 0x0A, 0xD0, 0x13, 0xA2, 0x0A, 0x60, 0x01, 0xD0, 0x14,
+        0xF0, 0x90, 0xF0, 0x90, 0xA2, 0x00, 0x12, 0x00,
+    ];
+
+    #[test]
+    fn references() {
+        let sprites = referenced(&ROM);
+        assert_eq!(vec![Sprite { address: 0x20A, rows: vec![0xF0, 0x90, 0xF0, 0x90] }], sprites);
+        // Pointing past the end of the rom
+        assert!(referenced(&[0xA3, 0x00, 0xD0, 0x15]).is_empty());
+    }
+
+    #[test]
+    fn tiling() {
+        let sprites = tiles(&ROM, 8);
+        assert_eq!(3, sprites.len());
+        assert_eq!(0x210, sprites[2].address);
+        assert_eq!(vec![0x12, 0x00], sprites[2].rows);
+    }
+
+    #[test]
+    fn sheet() {
+        let sprites = tiles(&[0x80, 0x01, 0xFF], 2);
+        let sheet = Sheet::new(&sprites, 4);
+        assert_eq!((19, 4), (sheet.width, sheet.height));
+        assert_eq!(None, sheet.pixels[0]);
+        assert_eq!(Some(true), sheet.pixels[sheet.width + 1]);
+        assert_eq!(Some(true), sheet.pixels[2 * sheet.width + 8]);
+        // The second sprite has a single row
+        assert_eq!(Some(false), sheet.pixels[2 * sheet.width + 10]);
+        assert_eq!(Some(1), sheet.sprite_at(12, 2));
+    }
+}
@@ -0,0 +1,234 @@
+// A log of every executed instruction, see Chip8::tracer.
+
+use std::collections::VecDeque;
+use std::io::Write;
+use crate::chip8::Registers;
+use crate::disassembler;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    // One line per instruction
+    Text,
+    // Fixed header per instruction followed by the changed registers, see Tracer::encode
+    Binary,
+}
+
+impl Format {
+    pub const NAMES: [&'static str; 2] = ["text", "binary"];
+
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "text" => Some(Format::Text),
+            "binary" => Some(Format::Binary),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Text => "text",
+            Format::Binary => "binary",
+        }
+    }
+}
+
+pub struct Tracer {
+    writer: Box<dyn Write + Send>,
+    format: Format,
+    // only instructions at these addresses are logged
+    range: (u16, u16),
+    // keep only the last entries in memory until the interpreter panics
+    ring: Option<(usize, VecDeque<Vec<u8>>)>,
+    // executed instructions, including the ones that were filtered out
+    count: u64,
+    // machine cycles they took with Timing::Vip, which are logged instead of the count then
+    cycles: Option<u64>,
+    // the instruction being executed
+    pending: Option<(Registers, u16)>,
+}
+
+impl Tracer {
+    pub fn new(writer: Box<dyn Write + Send>, format: Format, range: (u16, u16), ring: Option<usize>) -> Tracer {
+        Tracer {
+            writer,
+            format,
+            range,
+            ring: ring.map(|size| (size, VecDeque::with_capacity(size))),
+            count: 0,
+            cycles: None,
+            pending: None,
+        }
+    }
+
+    // The last instruction took this many COSMAC VIP machine cycles.
+    pub fn charge(&mut self, cycles: u64) {
+        self.cycles = Some(self.cycles.unwrap_or(0) + cycles);
+    }
+
+    pub fn begin(&mut self, before: Registers, opcode: u16) {
+        self.pending = Some((before, opcode));
+    }
+
+    pub fn end(&mut self, after: Registers) {
+        if let Some((before, opcode)) = self.pending.take() {
+            if (self.range.0..=self.range.1).contains(&before.pc) {
+                let entry = self.encode(&before, opcode, Some(&after));
+                match &mut self.ring {
+                    Some((size, entries)) => {
+                        if entries.len() == *size {
+                            entries.pop_front();
+                        }
+                        entries.push_back(entry);
+                    }
+                    // Tracing is a debugging aid, a full disk shouldn't stop the game.
+                    None => { let _ = self.writer.write_all(&entry); }
+                }
+            }
+            self.count += 1;
+        }
+    }
+
+    // Text: cycle, PC, opcode, mnemonic, changed registers, I and the timers.
+    // Binary: cycle as u64, PC, opcode and I as u16, DT, ST, a u16 with a bit for every changed register
+    // and their new values, all big endian. Without the state after, the instruction didn't finish.
+    // The cycle is when the instruction started, in machine cycles with Timing::Vip and in instructions otherwise.
+    fn encode(&self, before: &Registers, opcode: u16, after: Option<&Registers>) -> Vec<u8> {
+        let state = after.unwrap_or(before);
+        let cycle = self.cycles.unwrap_or(self.count);
+        let changed: Vec<usize> = match after {
+            Some(after) => (0..16).filter(|&x| before.v[x] != after.v[x]).collect(),
+            None => Vec::new(),
+        };
+
+        match self.format {
+            Format::Text => {
+                let mut line = format!(
+                    "{:>8} {:03X} {:04X} {:<16}",
+                    cycle, before.pc, opcode, disassembler::mnemonic(opcode),
+                );
+                for &x in changed.iter() {
+                    line.push_str(&format!(" V{:X}={:02X}", x, state.v[x]));
+                }
+                line.push_str(&format!(" I={:03X} DT={:02X} ST={:02X}", state.i, state.dt, state.st));
+                if after.is_none() {
+                    line.push_str(" <- panicked");
+                }
+                line.push('\n');
+                line.into_bytes()
+            }
+            Format::Binary => {
+                let mut entry = Vec::with_capacity(18 + changed.len());
+                entry.extend_from_slice(&cycle.to_be_bytes());
+                entry.extend_from_slice(&before.pc.to_be_bytes());
+                entry.extend_from_slice(&opcode.to_be_bytes());
+                entry.extend_from_slice(&state.i.to_be_bytes());
+                entry.push(state.dt);
+                entry.push(state.st);
+                let mask = changed.iter().fold(0u16, |mask, &x| mask | 1 << x);
+                entry.extend_from_slice(&mask.to_be_bytes());
+                entry.extend(changed.iter().map(|&x| state.v[x]));
+                entry
+            }
+        }
+    }
+}
+
+// The ring buffer is only written out if the interpreter panicked, together with the instruction that did it.
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            if let Some((_, entries)) = self.ring.take() {
+                for entry in entries.iter() {
+                    let _ = self.writer.write_all(entry);
+                }
+            }
+            if let Some((before, opcode)) = self.pending {
+                let entry = self.encode(&before, opcode, None);
+                let _ = self.writer.write_all(&entry);
+            }
+        }
+        let _ = self.writer.flush();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::chip8::{Chip8, Timing};
+
+    // Writes into a buffer that outlives the tracer.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run(rom: &[u8], format: Format, range: (u16, u16), ring: Option<usize>, steps: usize) -> Vec<u8> {
+        let buffer = Buffer::default();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let mut c = Chip8::new_with_state();
+            c.load_bytes(rom);
+            c.tracer = Some(Tracer::new(Box::new(buffer.clone()), format, range, ring));
+            for _ in 0..steps {
+                c.tick();
+            }
+        }));
+        assert_eq!(ring.is_some(), result.is_err());
+        let output = buffer.0.lock().unwrap().clone();
+        output
+    }
+
+    #[test]
+    fn text() {
+        let output = run(&[0x63, 0x12, 0xA2, 0xA0, 0x12, 0x00], Format::Text, (0x200, 0xFFF), None, 3);
+        assert_eq!(
+            "       0 200 6312 LD V3, 0x12      V3=12 I=000 DT=00 ST=00\n\
+             \x20      1 202 A2A0 LD I, 0x2A0      I=2A0 DT=00 ST=00\n\
+             \x20      2 204 1200 JP 0x200         I=2A0 DT=00 ST=00\n",
+            String::from_utf8(output).unwrap(),
+        );
+    }
+
+    #[test]
+    fn vip_cycles() {
+        let buffer = Buffer::default();
+        let mut c = Chip8::new_with_state();
+        c.load_bytes(&[0x63, 0x12, 0xA2, 0xA0, 0x12, 0x00]);
+        c.tracer = Some(Tracer::new(Box::new(buffer.clone()), Format::Text, (0x200, 0xFFF), None));
+        c.run_frame(Timing::Vip);
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let cycles: Vec<&str> = output.lines().take(4).map(|line| line.split_whitespace().next().unwrap()).collect();
+        // 6XNN takes 46 machine cycles, ANNN and 1NNN 52
+        assert_eq!(vec!["0", "46", "98", "150"], cycles);
+    }
+
+    #[test]
+    fn binary_and_range() {
+        let output = run(&[0x63, 0x12, 0xA2, 0xA0, 0x12, 0x00], Format::Binary, (0x200, 0x200), None, 4);
+        // The first and the fourth instruction, which doesn't change V3 anymore
+        assert_eq!(19 + 18, output.len());
+        assert_eq!(&[0, 0, 0, 0, 0, 0, 0, 0, 0x02, 0x00, 0x63, 0x12, 0, 0, 0, 0, 0x00, 0x08, 0x12], &output[..19]);
+        assert_eq!(3, output[19 + 7]);
+    }
+
+    #[test]
+    fn ring_after_panic() {
+        // Returns from a subroutine that was never called
+        let output = run(&[0x60, 0x01, 0x70, 0x01, 0x70, 0x01, 0x00, 0xEE], Format::Text, (0x200, 0xFFF), Some(2), 4);
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(3, lines.len());
+        assert!(lines[0].starts_with("       1 202 7001"));
+        assert!(lines[2].starts_with("       3 206 00EE RET"));
+        assert!(lines[2].ends_with("<- panicked"));
+    }
+}
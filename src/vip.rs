@@ -0,0 +1,370 @@
+// The COSMAC VIP running the original CHIP-8 interpreter on an emulated CDP1802.
+// The interpreter itself isn't included, it has to be loaded from a 512 byte dump.
+
+use crate::cdp1802::{self, Bus, Cdp1802};
+use crate::chip8::{AccessCounts, Chip8, Timing, HEIGHT, WIDTH};
+use crate::machine::{Machine, Snapshot, SNAPSHOT_END};
+
+pub const INTERPRETER_SIZE: usize = 0x200;
+// The interpreter keeps its stack, variables and the display in the last pages of the 4K of RAM.
+pub const MAX_ROM_SIZE: usize = SNAPSHOT_END - 0x200;
+
+// Size of a serialized machine state, see Vip::save_state.
+pub const STATE_SIZE: usize = cdp1802::STATE_SIZE + 4096 + 1 + 1 + 4 + WIDTH * HEIGHT;
+
+// Timing of the CDP1861 video chip, a frame has 262 lines of 14 machine cycles each.
+const CYCLES_PER_LINE: i32 = 14;
+const LINES: usize = 262;
+// The 128 visible lines, every CHIP-8 row is repeated on four of them
+const DISPLAY_START: usize = 80;
+const DISPLAY_END: usize = DISPLAY_START + 128;
+// The interrupt arrives two lines before the first DMA, EF1 is asserted four lines before the start and the end.
+const INTERRUPT_LINE: usize = DISPLAY_START - 2;
+const EF1_LINES: usize = 4;
+
+// The interpreter finds the top of the RAM in R1.1, which the monitor leaves there for it.
+const TOP_PAGE: u16 = 0x0F00;
+// Where the interpreter keeps the CHIP-8 state: V0-VF in memory, PC in R5 and I in RA
+const V_ADDRESS: usize = 0xEF0;
+const PC_REGISTER: usize = 0x5;
+const I_REGISTER: usize = 0xA;
+// The fetch loop runs with R4 as the program counter and calls the routine of every instruction,
+// which returns to it. Between two CHIP-8 instructions the processor is at its start.
+const FETCH_REGISTER: u8 = 0x4;
+const FETCH_LOOP: u16 = 0x001B;
+
+struct VipBus {
+    memory: [u8; 4096],
+    keys: [bool; 16],
+    // key selected with OUT 2, EF3 tells whether it is pressed
+    latch: u8,
+    // the 1861 is switched on with INP 1 and off with OUT 1
+    display: bool,
+    ef1: bool,
+}
+
+impl Bus for VipBus {
+    // The 4K of RAM are mirrored up to the monitor rom at 0x8000, which isn't emulated.
+    fn read(&mut self, address: u16) -> u8 {
+        if address < 0x8000 { self.memory[address as usize & 0xFFF] } else { 0 }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        if address < 0x8000 { self.memory[address as usize & 0xFFF] = value }
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        match port {
+            1 => self.display = false,
+            2 => self.latch = value & 0x0F,
+            _ => (),
+        }
+    }
+
+    fn input(&mut self, port: u8) -> u8 {
+        if port == 1 { self.display = true };
+        0
+    }
+
+    fn flag(&self, flag: u8) -> bool {
+        match flag {
+            1 => self.ef1,
+            3 => self.keys[self.latch as usize],
+            _ => false,
+        }
+    }
+}
+
+pub struct Vip {
+    cpu: Cdp1802,
+    bus: VipBus,
+    // machine cycles left in the current line, negative if the last instruction ran over
+    cycles: i32,
+    // the bytes the 1861 fetched for every visible line of the last frame
+    lines: [u8; 128 * 8],
+    framebuffer: [bool; WIDTH * HEIGHT],
+}
+
+impl Vip {
+    // Load the interpreter at 0x000 and the rom at 0x200 like the real machine.
+    pub fn new(interpreter: &[u8], rom: &[u8]) -> Result<Vip, String> {
+        if interpreter.len() > INTERPRETER_SIZE {
+            return Err(format!("The interpreter is {} bytes, expected at most {}", interpreter.len(), INTERPRETER_SIZE));
+        }
+        if rom.len() > MAX_ROM_SIZE {
+            return Err(format!("The rom is {} bytes, the COSMAC VIP fits at most {}", rom.len(), MAX_ROM_SIZE));
+        }
+
+        let mut memory = [0; 4096];
+        memory[..interpreter.len()].copy_from_slice(interpreter);
+        memory[0x200..0x200 + rom.len()].copy_from_slice(rom);
+
+        let mut vip = Vip {
+            cpu: Cdp1802::new(),
+            bus: VipBus { memory, keys: [false; 16], latch: 0, display: false, ef1: false },
+            cycles: 0,
+            lines: [0; 128 * 8],
+            framebuffer: [false; WIDTH * HEIGHT],
+        };
+        vip.reset();
+        Ok(vip)
+    }
+
+    fn between_instructions(&self) -> bool {
+        self.cpu.p == FETCH_REGISTER && self.cpu.r[FETCH_REGISTER as usize] == FETCH_LOOP
+    }
+
+    // Show the first of the four lines of every row.
+    fn update_framebuffer(&mut self) -> bool {
+        let mut changed = false;
+        for (index, pixel) in self.framebuffer.iter_mut().enumerate() {
+            let (x, y) = (index % WIDTH, index / WIDTH);
+            let byte = self.lines[y * 4 * 8 + x / 8];
+            let on = self.bus.display && byte >> (7 - x % 8) & 0x01 != 0;
+            changed |= *pixel != on;
+            *pixel = on;
+        }
+        changed
+    }
+}
+
+impl Machine for Vip {
+    // The timing is given by the hardware, the returned count is in 1802 instructions.
+    fn run_frame(&mut self, _timing: Timing) -> (u64, bool) {
+        let mut executed = 0;
+
+        for line in 0..LINES {
+            self.bus.ef1 = self.bus.display
+                && ((DISPLAY_START - EF1_LINES..DISPLAY_START).contains(&line)
+                    || (DISPLAY_END - EF1_LINES..DISPLAY_END).contains(&line));
+            if line == INTERRUPT_LINE && self.bus.display {
+                self.cpu.interrupt();
+            }
+
+            self.cycles += CYCLES_PER_LINE;
+            if self.bus.display && (DISPLAY_START..DISPLAY_END).contains(&line) {
+                let start = (line - DISPLAY_START) * 8;
+                for byte in self.lines[start..start + 8].iter_mut() {
+                    *byte = self.cpu.dma_out(&mut self.bus);
+                }
+                self.cycles -= 8;
+            }
+
+            while self.cycles > 0 {
+                if !self.cpu.idle { executed += 1 };
+                self.cycles -= self.cpu.step(&mut self.bus) as i32;
+            }
+        }
+
+        (executed, self.update_framebuffer())
+    }
+
+    fn keys(&mut self) -> &mut [bool; 16] {
+        &mut self.bus.keys
+    }
+
+    fn framebuffer(&self) -> &[bool] {
+        &self.framebuffer
+    }
+
+    fn sound_active(&self) -> bool {
+        self.cpu.q
+    }
+
+    // Only the interpreter knows.
+    fn waiting_for_key(&self) -> bool {
+        false
+    }
+
+    fn reset(&mut self) {
+        self.cpu.reset();
+        self.cpu.r[1] = TOP_PAGE;
+        self.bus.display = false;
+        self.bus.ef1 = false;
+        self.cycles = 0;
+    }
+
+    // The processor, the RAM and the hardware around it.
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = self.cpu.save_state();
+        state.extend_from_slice(&self.bus.memory);
+        state.push(self.bus.latch);
+        state.push(self.bus.display as u8);
+        state.extend_from_slice(&self.cycles.to_be_bytes());
+        state.extend(self.framebuffer.iter().map(|&pixel| pixel as u8));
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> bool {
+        if state.len() != STATE_SIZE {
+            return false;
+        }
+
+        let (cpu, rest) = state.split_at(cdp1802::STATE_SIZE);
+        let (memory, rest) = rest.split_at(4096);
+        self.cpu.load_state(cpu);
+        self.bus.memory.copy_from_slice(memory);
+        self.bus.latch = rest[0] & 0x0F;
+        self.bus.display = rest[1] != 0;
+        self.cycles = i32::from_be_bytes([rest[2], rest[3], rest[4], rest[5]]);
+        for (pixel, &value) in self.framebuffer.iter_mut().zip(&rest[6..]) {
+            *pixel = value != 0;
+        }
+        true
+    }
+
+    // Only meaningful between CHIP-8 instructions, see Vip::finish_instruction.
+    fn snapshot(&self) -> Snapshot {
+        let mut v = [0; 16];
+        v.copy_from_slice(&self.bus.memory[V_ADDRESS..V_ADDRESS + 16]);
+        Snapshot {
+            pc: self.cpu.r[PC_REGISTER],
+            i: self.cpu.r[I_REGISTER],
+            v,
+            memory: self.bus.memory[0x200..SNAPSHOT_END].to_vec(),
+            framebuffer: self.framebuffer.to_vec(),
+        }
+    }
+
+    // Frames end wherever the cycles run out, usually halfway through the routine of an instruction.
+    // The cycles spent finishing it are taken from the next frame. Instructions that wait, like FX0A,
+    // are given up on after a frame.
+    fn finish_instruction(&mut self) -> bool {
+        let mut cycles = 0;
+        while !self.between_instructions() && cycles < LINES as i32 * CYCLES_PER_LINE && !self.cpu.idle {
+            let spent = self.cpu.step(&mut self.bus) as i32;
+            self.cycles -= spent;
+            cycles += spent;
+        }
+        self.between_instructions()
+    }
+
+    // The 1802 accesses memory for the interpreter too, which isn't what CHIP-8 programs care about.
+    fn access_counts(&self) -> Option<&AccessCounts> {
+        None
+    }
+
+    fn chip8(&mut self) -> Option<&mut Chip8> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::diff::{self, Input};
+
+    // A stand-in for the interpreter, which turns on the display and sets Q while key A is pressed.
+    // Its interrupt routine points R0 at the top page, so every line shows the next 8 bytes of it.
+    const INTERPRETER: [u8; 0x3B] = [
+        // R3 becomes the program counter, R0 is needed for the DMA
+        0xF8, 0x00, 0xB3, 0xF8, 0x07, 0xA3, 0xD3,
+        // R1 = 0x0031, R2 = 0x0EFF, X = 2, display on
+        0xF8, 0x00, 0xB1, 0xF8, 0x31, 0xA1, 0xF8, 0x0E, 0xB2, 0xF8, 0xFF, 0xA2, 0xE2, 0x69,
+        // latch key A
+        0xF8, 0x0A, 0x52, 0x62, 0x22,
+        // 0x1A: Q off until the key is pressed, then on until it is released
+        0x7A, 0x3E, 0x1A, 0x7B, 0x36, 0x1D, 0x30, 0x1A,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        // 0x30: RET, 0x31: interrupt routine
+        0x70, 0x22, 0x78, 0xF8, 0x0F, 0xB0, 0xF8, 0x00, 0xA0, 0x30, 0x30,
+    ];
+
+    // A stand-in for the interpreter that only knows 1NNN, 6XNN and 7XNN, and skips everything else.
+    // Like the original it dispatches from a fetch loop at FETCH_LOOP to routines that return to it.
+    fn fetch_loop_interpreter() -> Vec<u8> {
+        let mut interpreter = vec![0; 0x90];
+        let mut put = |address: usize, code: &[u8]| interpreter[address..address + code.len()].copy_from_slice(code);
+        // R4 = FETCH_LOOP, R5 = 0x200, R6 points at the V registers, R3 and R7 in page 0
+        put(0x00, &[0xF8, 0x00, 0xB4, 0xF8, 0x1B, 0xA4, 0xF8, 0x02, 0xB5, 0xF8, 0x00, 0xA5]);
+        put(0x0C, &[0xF8, 0x0E, 0xB6, 0xF8, 0x00, 0xB3, 0xB7, 0xD4]);
+        // The fetch loop: R6 = VX, RF.0 = the first byte, the routine of the opcode from the table at 0x80
+        put(0x1B, &[0x45, 0xAF, 0xFA, 0x0F, 0xF9, 0xF0, 0xA6, 0x8F, 0xF6, 0xF6, 0xF6, 0xF6]);
+        put(0x27, &[0xFC, 0x80, 0xA7, 0x07, 0xA3, 0xD3, 0x30, 0x1B]);
+        // 6XNN, 7XNN, 1NNN and the rest
+        put(0x40, &[0x45, 0x56, 0xD4]);
+        put(0x48, &[0xE6, 0x45, 0xF4, 0x56, 0xD4]);
+        put(0x50, &[0x05, 0xA5, 0x8F, 0xFA, 0x0F, 0xB5, 0xD4]);
+        put(0x60, &[0x15, 0xD4]);
+        put(0x80, &[0x60; 16]);
+        put(0x81, &[0x50]);
+        put(0x86, &[0x40, 0x48]);
+        interpreter
+    }
+
+    #[test]
+    fn display_and_keypad() {
+        let mut vip = Vip::new(&INTERPRETER, &[]).unwrap();
+        vip.bus.memory[0xF00] = 0xFF;
+        vip.bus.memory[0xF20] = 0x80;
+
+        let (executed, drawn) = vip.run_frame(Timing::Vip);
+        assert!(executed > 0);
+        assert!(drawn);
+        // The first eight rows come from the top page, the rest from the mirrored interpreter
+        assert_eq!(8 + 1, vip.framebuffer()[..8 * WIDTH].iter().filter(|&&pixel| pixel).count());
+        assert!(vip.framebuffer()[7]);
+        assert!(vip.framebuffer()[WIDTH]);
+        assert!(!vip.sound_active());
+
+        vip.keys()[0xA] = true;
+        vip.run_frame(Timing::Vip);
+        assert!(vip.sound_active());
+        vip.keys()[0xA] = false;
+        vip.run_frame(Timing::Vip);
+        assert!(!vip.sound_active());
+
+        // Nothing changed on screen
+        assert!(!vip.run_frame(Timing::Vip).1);
+    }
+
+    #[test]
+    fn state() {
+        let mut vip = Vip::new(&INTERPRETER, &[0x12, 0x00]).unwrap();
+        vip.run_frame(Timing::Vip);
+        let state = vip.save_state();
+        assert_eq!(STATE_SIZE, state.len());
+
+        let mut restored = Vip::new(&INTERPRETER, &[]).unwrap();
+        assert!(restored.load_state(&state));
+        assert_eq!(state, restored.save_state());
+        assert_eq!(0x12, restored.bus.memory[0x200]);
+    }
+
+    #[test]
+    fn compare_with_chip8() {
+        // LD V0, 5; ADD V1, 3; ADD V1, 3; JP 0x206
+        let rom = [0x60, 0x05, 0x71, 0x03, 0x71, 0x03, 0x12, 0x06];
+        let mut vip = Vip::new(&fetch_loop_interpreter(), &rom).unwrap();
+        let mut interrupted = 0;
+        for _ in 0..10 {
+            vip.run_frame(Timing::Vip);
+            interrupted += !vip.between_instructions() as usize;
+            assert!(vip.finish_instruction());
+            let snapshot = vip.snapshot();
+            assert_eq!((0x206, 5, 6), (snapshot.pc, snapshot.v[0], snapshot.v[1]));
+        }
+        assert!(interrupted > 0);
+
+        let mut chip8 = Chip8::new_with_state();
+        chip8.load_bytes(&rom);
+        let mut vip = Vip::new(&fetch_loop_interpreter(), &rom).unwrap();
+        assert!(diff::run_frames(&mut chip8, &mut vip, &Input::none(), 60, Timing::Vip, 4).is_none());
+
+        // The stand-in skips CXNN, which can't be told apart from different random numbers
+        let rom = [0xC0, 0xFF, 0x12, 0x02];
+        let mut chip8 = Chip8::new_with_state();
+        chip8.seed(1);
+        chip8.load_bytes(&rom);
+        let mut vip = Vip::new(&fetch_loop_interpreter(), &rom).unwrap();
+        let divergence = diff::run_frames(&mut chip8, &mut vip, &Input::none(), 60, Timing::Vip, 4).unwrap();
+        assert_eq!(0, divergence.frame);
+        assert!(divergence.random);
+    }
+
+    #[test]
+    fn sizes() {
+        assert!(Vip::new(&[0; INTERPRETER_SIZE + 1], &[]).is_err());
+        assert!(Vip::new(&[], &[0; MAX_ROM_SIZE + 1]).is_err());
+    }
+}
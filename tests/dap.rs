@@ -0,0 +1,80 @@
+// Runs the debug adapter like an editor would and talks to it over stdio.
+
+use std::io::BufReader;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use serde_json::{json, Value};
+use crusty_8::dap;
+
+// LD V0, 5; ADD V0, 1; JP 0x202
+const ROM: [u8; 6] = [0x60, 0x05, 0x70, 0x01, 0x12, 0x02];
+
+struct Editor {
+    adapter: Child,
+    output: BufReader<ChildStdout>,
+    seq: u64,
+}
+
+impl Editor {
+    fn request(&mut self, command: &str, arguments: Value) -> Value {
+        self.seq += 1;
+        let request = json!({ "seq": self.seq, "type": "request", "command": command, "arguments": arguments });
+        dap::write_message(self.adapter.stdin.as_mut().unwrap(), &request).unwrap();
+        let seq = self.seq;
+        self.wait_for(|message| message["type"] == "response" && message["request_seq"] == seq)
+    }
+
+    // Skip messages until the expected one, the adapter may send events at any time.
+    fn wait_for(&mut self, expected: impl Fn(&Value) -> bool) -> Value {
+        loop {
+            let message = dap::read_message(&mut self.output).unwrap().expect("the adapter quit");
+            if expected(&message) {
+                return message;
+            }
+        }
+    }
+
+    fn stopped(&mut self) -> Value {
+        self.wait_for(|message| message["event"] == "stopped")
+    }
+}
+
+#[test]
+fn debug_session() {
+    let rom = std::env::temp_dir().join("crusty-8-dap-session.ch8");
+    std::fs::write(&rom, ROM).unwrap();
+
+    let mut adapter = Command::new(env!("CARGO_BIN_EXE_crusty-8-dap"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let output = BufReader::new(adapter.stdout.take().unwrap());
+    let mut editor = Editor { adapter, output, seq: 0 };
+
+    let response = editor.request("initialize", json!({ "adapterID": "crusty-8" }));
+    assert_eq!(json!(true), response["body"]["supportsInstructionBreakpoints"]);
+    editor.wait_for(|message| message["event"] == "initialized");
+
+    let response = editor.request("launch", json!({ "program": rom.to_str().unwrap(), "stopOnEntry": true, "display": false }));
+    assert_eq!(json!(true), response["success"]);
+    editor.request("setInstructionBreakpoints", json!({ "breakpoints": [{ "instructionReference": "0x204" }] }));
+    editor.request("configurationDone", json!({}));
+    assert_eq!(json!("entry"), editor.stopped()["body"]["reason"]);
+
+    editor.request("continue", json!({ "threadId": 1 }));
+    assert_eq!(json!("breakpoint"), editor.stopped()["body"]["reason"]);
+
+    let response = editor.request("stackTrace", json!({ "threadId": 1 }));
+    assert_eq!(json!("0x204"), response["body"]["stackFrames"][0]["instructionPointerReference"]);
+    let response = editor.request("variables", json!({ "variablesReference": 1 }));
+    assert_eq!(json!("0x06"), response["body"]["variables"][0]["value"]);
+
+    // The breakpoint is hit once per loop
+    editor.request("continue", json!({ "threadId": 1 }));
+    editor.stopped();
+    let response = editor.request("variables", json!({ "variablesReference": 1 }));
+    assert_eq!(json!("0x07"), response["body"]["variables"][0]["value"]);
+
+    editor.request("disconnect", json!({}));
+    assert!(editor.adapter.wait().unwrap().success());
+}
@@ -0,0 +1,182 @@
+// Loads the libretro core like a frontend would and drives a few frames.
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_uint, c_void};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use libloading::{Library, Symbol};
+
+#[repr(C)]
+struct RetroGameInfo {
+    path: *const c_char,
+    data: *const c_void,
+    size: usize,
+    meta: *const c_char,
+}
+
+#[repr(C)]
+struct RetroVariable {
+    key: *const c_char,
+    value: *const c_char,
+}
+
+static FRAMES: AtomicUsize = AtomicUsize::new(0);
+static LIT_PIXELS: AtomicUsize = AtomicUsize::new(0);
+static AUDIO_FRAMES: AtomicUsize = AtomicUsize::new(0);
+static SOUND: AtomicBool = AtomicBool::new(false);
+static OPTIONS_SET: AtomicBool = AtomicBool::new(false);
+
+// Draws a "0" at (8, 4), starts the sound timer and waits for a key which is stored in V3.
+const ROM: [u8; 16] = [
+    0xA0, 0x00, 0x60, 0x08, 0x61, 0x04, 0xD0, 0x15,
+    0x62, 0x20, 0xF2, 0x18, 0xF3, 0x0A, 0x12, 0x0E,
+];
+
+// Keeps filling V0 and V1 with random numbers.
+const RANDOM: [u8; 6] = [0xC0, 0xFF, 0xC1, 0xFF, 0x12, 0x00];
+
+extern "C" fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+    match cmd {
+        // SET_PIXEL_FORMAT
+        10 => true,
+        // GET_VARIABLE
+        15 => {
+            let variable = unsafe { &mut *(data as *mut RetroVariable) };
+            let key = unsafe { CStr::from_ptr(variable.key) };
+            if key.to_bytes() == b"crusty8_speed" {
+                variable.value = b"20\0".as_ptr() as *const c_char;
+                true
+            } else {
+                false
+            }
+        }
+        // SET_VARIABLES
+        16 => {
+            OPTIONS_SET.store(true, Ordering::SeqCst);
+            true
+        }
+        _ => false,
+    }
+}
+
+extern "C" fn video_refresh(data: *const c_void, width: c_uint, height: c_uint, pitch: usize) {
+    assert_eq!((64, 32, 64 * 4), (width, height, pitch));
+    let pixels = unsafe { std::slice::from_raw_parts(data as *const u32, 64 * 32) };
+    LIT_PIXELS.store(pixels.iter().filter(|&&p| p != 0).count(), Ordering::SeqCst);
+    FRAMES.fetch_add(1, Ordering::SeqCst);
+}
+
+extern "C" fn audio_sample_batch(data: *const i16, frames: usize) -> usize {
+    let samples = unsafe { std::slice::from_raw_parts(data, frames * 2) };
+    if samples.iter().any(|&s| s != 0) {
+        SOUND.store(true, Ordering::SeqCst);
+    }
+    AUDIO_FRAMES.fetch_add(frames, Ordering::SeqCst);
+    frames
+}
+
+extern "C" fn input_poll() {}
+
+// Presses A, which is mapped to key 5, during the first frame and releases it afterwards.
+extern "C" fn input_state(_port: c_uint, _device: c_uint, _index: c_uint, id: c_uint) -> i16 {
+    if id == 8 && FRAMES.load(Ordering::SeqCst) == 0 { 1 } else { 0 }
+}
+
+fn core_path() -> std::path::PathBuf {
+    let exe = std::env::current_exe().unwrap();
+    let deps = exe.parent().unwrap();
+    let name = libloading::library_filename("crusty_8");
+    [deps, deps.parent().unwrap()]
+        .iter()
+        .map(|dir| dir.join(&name))
+        .find(|path| path.exists())
+        .expect("libretro core has not been built")
+}
+
+#[test]
+fn drive_core() {
+    unsafe {
+        let core = Library::new(core_path()).unwrap();
+
+        let api_version: Symbol<extern "C" fn() -> c_uint> = core.get(b"retro_api_version").unwrap();
+        assert_eq!(1, api_version());
+
+        let set_environment: Symbol<extern "C" fn(extern "C" fn(c_uint, *mut c_void) -> bool)> =
+            core.get(b"retro_set_environment").unwrap();
+        let set_video_refresh: Symbol<extern "C" fn(extern "C" fn(*const c_void, c_uint, c_uint, usize))> =
+            core.get(b"retro_set_video_refresh").unwrap();
+        let set_audio_sample_batch: Symbol<extern "C" fn(extern "C" fn(*const i16, usize) -> usize)> =
+            core.get(b"retro_set_audio_sample_batch").unwrap();
+        let set_input_poll: Symbol<extern "C" fn(extern "C" fn())> = core.get(b"retro_set_input_poll").unwrap();
+        let set_input_state: Symbol<extern "C" fn(extern "C" fn(c_uint, c_uint, c_uint, c_uint) -> i16)> =
+            core.get(b"retro_set_input_state").unwrap();
+        let init: Symbol<extern "C" fn()> = core.get(b"retro_init").unwrap();
+        let load_game: Symbol<unsafe extern "C" fn(*const RetroGameInfo) -> bool> = core.get(b"retro_load_game").unwrap();
+        let run: Symbol<extern "C" fn()> = core.get(b"retro_run").unwrap();
+        let reset: Symbol<extern "C" fn()> = core.get(b"retro_reset").unwrap();
+        let serialize_size: Symbol<extern "C" fn() -> usize> = core.get(b"retro_serialize_size").unwrap();
+        let serialize: Symbol<unsafe extern "C" fn(*mut c_void, usize) -> bool> = core.get(b"retro_serialize").unwrap();
+        let unserialize: Symbol<unsafe extern "C" fn(*const c_void, usize) -> bool> = core.get(b"retro_unserialize").unwrap();
+        let unload_game: Symbol<extern "C" fn()> = core.get(b"retro_unload_game").unwrap();
+        let deinit: Symbol<extern "C" fn()> = core.get(b"retro_deinit").unwrap();
+
+        set_environment(environment);
+        assert!(OPTIONS_SET.load(Ordering::SeqCst));
+        set_video_refresh(video_refresh);
+        set_audio_sample_batch(audio_sample_batch);
+        set_input_poll(input_poll);
+        set_input_state(input_state);
+        init();
+
+        let game = RetroGameInfo {
+            path: std::ptr::null(),
+            data: ROM.as_ptr() as *const c_void,
+            size: ROM.len(),
+            meta: std::ptr::null(),
+        };
+        assert!(load_game(&game));
+
+        for _ in 0..3 {
+            run();
+        }
+        assert_eq!(3, FRAMES.load(Ordering::SeqCst));
+        assert_eq!(14, LIT_PIXELS.load(Ordering::SeqCst));
+        assert_eq!(3 * 735, AUDIO_FRAMES.load(Ordering::SeqCst));
+        assert!(SOUND.load(Ordering::SeqCst));
+
+        let size = serialize_size();
+        let mut state = vec![0u8; size];
+        assert!(serialize(state.as_mut_ptr() as *mut c_void, size));
+        // V3 holds the key read by FX0A, the registers follow PC and I.
+        assert_eq!(0x5, state[4 + 3]);
+
+        reset();
+        run();
+        assert!(unserialize(state.as_ptr() as *const c_void, size));
+        let mut restored = vec![0u8; size];
+        assert!(serialize(restored.as_mut_ptr() as *mut c_void, size));
+        assert_eq!(state, restored);
+        unload_game();
+
+        // Frames replayed after a restore, like rewind or run-ahead do, draw the same random numbers
+        let game = RetroGameInfo { data: RANDOM.as_ptr() as *const c_void, size: RANDOM.len(), ..game };
+        assert!(load_game(&game));
+        run();
+        assert!(serialize(state.as_mut_ptr() as *mut c_void, size));
+        let run_frames = || {
+            for _ in 0..5 {
+                run();
+            }
+            let mut after = vec![0u8; size];
+            assert!(serialize(after.as_mut_ptr() as *mut c_void, size));
+            after
+        };
+        let first = run_frames();
+        assert_ne!(state, first);
+        assert!(unserialize(state.as_ptr() as *const c_void, size));
+        assert_eq!(first, run_frames());
+        assert!(!unserialize(state.as_ptr() as *const c_void, size - 1));
+
+        unload_game();
+        deinit();
+    }
+}